@@ -1,15 +1,20 @@
-use criterion::{
-    black_box, criterion_group, criterion_main, Bencher, BenchmarkId, Criterion, Throughput,
-};
-use twitterperf::data::START_TIME;
+use std::thread;
+use std::time::Instant;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use twitterperf::compress::CompressedFollows;
+use twitterperf::data::{Datastore, Timestamp, Tweet, START_TIME};
+use twitterperf::import::GraphBuilder;
 // use twitterperf::data::Datastore;
-use twitterperf::generate::{LoadGraph, TweetGenerator, TweetGeneratorConfig};
-use twitterperf::timeline::{Timeline, TimelineFetcher};
+use twitterperf::generate::{LoadGraph, TweetGenerator, TweetGeneratorConfig, ViewGenerator};
+use twitterperf::perf::Counters;
+use twitterperf::rank::{EngagementScorer, RankedFetcher};
+use twitterperf::timeline::{ProfileStart, TimelineFetcher};
 
 // fn bench_merge<'a>(b: &mut Bencher, input: &'a mut (&'a mut TweetGenerator, &'a mut Datastore<'a>)) {
 //     let (gen, data) = input;
 //     b.iter(|| {
-//         let user_idx = black_box(gen.gen_view());
+//         let user_idx = black_box(view_gen.gen_view());
 //         Timeline::for_user(&data, user_idx, 200)
 //     });
 // }
@@ -19,10 +24,26 @@ fn criterion_benchmark(c: &mut Criterion) {
     let graph = loader.graph();
 
     let n_tweets = 4_000_000;
-    let mut config = TweetGeneratorConfig::default();
-    let (mut gen, mut data) = TweetGenerator::new(config, graph);
+    let config = TweetGeneratorConfig::default();
+    let (mut gen, viewing_users, mut data) = TweetGenerator::new(config, graph);
+
+    let counters = Counters::from_env();
+    counters.iter().for_each(Counters::start);
+    gen.add_tweets(&mut data, n_tweets).unwrap();
+    if let Some(counters) = &counters {
+        eprintln!("add {}", counters.stop().per(n_tweets as u64, "tweet"));
+    }
+    let mut view_gen = ViewGenerator::new(gen.fork_seed(), &viewing_users);
 
-    gen.add_tweets(&mut data, n_tweets);
+    if let Some(counters) = &counters {
+        let n_views = 100_000;
+        let mut fetcher = TimelineFetcher::default();
+        counters.start();
+        for _ in 0..n_views {
+            fetcher.for_user(&data, view_gen.gen_view(), 200, START_TIME);
+        }
+        eprintln!("merge {}", counters.stop().per(n_views, "timeline"));
+    }
 
     // c.bench_with_input(BenchmarkId::new("timeline_merge", "default"), &mut (&mut gen, &mut data), bench_merge);
     let mut group = c.benchmark_group("timeline");
@@ -30,12 +51,123 @@ fn criterion_benchmark(c: &mut Criterion) {
     group.bench_function("merge", |b| {
         let mut fetcher = TimelineFetcher::default();
         b.iter(|| {
-            let user_idx = black_box(gen.gen_view());
+            let user_idx = black_box(view_gen.gen_view());
             fetcher.for_user(&data, user_idx, 200, START_TIME);
         })
     });
+    group.bench_function("ranked", |b| {
+        let mut fetcher = RankedFetcher::default();
+        let scorer = EngagementScorer::default();
+        b.iter(|| {
+            let user_idx = black_box(view_gen.gen_view());
+            fetcher.for_user(&data, &scorer, user_idx, 800, 200, START_TIME);
+        })
+    });
+    // viewing users mostly read rather than tweet, so page through the
+    // authors of a sample of tweets, weighted by how much they tweet
+    let authors: Vec<_> = (data.tweets.start()..data.tweets.len())
+        .step_by(997)
+        .map(|i| data.tweets[i].author)
+        .collect();
+    group.bench_function("profile", |b| {
+        let mut fetcher = TimelineFetcher::default();
+        let mut authors = authors.iter().copied().cycle();
+        b.iter(|| {
+            let user_idx = black_box(view_gen.gen_view());
+            let author = black_box(authors.next().unwrap());
+            let page = fetcher.for_profile(
+                &data,
+                user_idx,
+                author,
+                200,
+                START_TIME,
+                ProfileStart::Newest,
+            );
+            page.tweets.len()
+        })
+    });
+    group.finish();
+
+    let compressed = CompressedFollows::encode(&data.graph);
+    let raw_bytes = std::mem::size_of_val(data.graph.follows);
+    let compressed_bytes = compressed.size_bytes();
+    eprintln!(
+        "follows: {raw_bytes} bytes raw, {compressed_bytes} bytes compressed ({:.2}x)",
+        raw_bytes as f64 / compressed_bytes as f64
+    );
+
+    let mut group = c.benchmark_group("follows");
+    group.bench_function("scan_raw", |b| {
+        b.iter(|| {
+            let user = &data.graph.users[view_gen.gen_view() as usize];
+            data.graph
+                .user_follows(user)
+                .iter()
+                .map(|f| *f as u64)
+                .sum::<u64>()
+        })
+    });
+    group.bench_function("scan_compressed", |b| {
+        b.iter(|| {
+            compressed
+                .user_follows(view_gen.gen_view())
+                .map(|f| f as u64)
+                .sum::<u64>()
+        })
+    });
+    group.throughput(Throughput::Elements(69));
+    group.bench_function("merge_compressed", |b| {
+        let mut fetcher = TimelineFetcher::default();
+        b.iter(|| {
+            let user_idx = black_box(view_gen.gen_view());
+            fetcher.for_user_compressed(&data, &compressed, user_idx, 200, START_TIME);
+        })
+    });
     group.finish()
 }
 
-criterion_group!(benches, criterion_benchmark);
+/// Fetches from several threads at once, so shared cache lines on the read
+/// path show up. Uses a synthetic graph, so it runs without `users.bin`.
+fn parallel_fetch_benchmark(c: &mut Criterion) {
+    let n_users = 20_000u32;
+    let mut builder = GraphBuilder::new(n_users as usize, true);
+    for user in 0..n_users {
+        for i in 1..=100 {
+            builder.add_edge(user, (user + i * 197) % n_users);
+        }
+    }
+    let baked = builder.finish();
+    let data = Datastore::new(baked.graph()).unwrap();
+    for t in 1..=1_000_000u32 {
+        let tweet = Tweet::dummy(Timestamp::new(t).unwrap());
+        data.add_tweet(tweet, t.wrapping_mul(2_654_435_761) % n_users)
+            .unwrap();
+    }
+
+    let mut group = c.benchmark_group("parallel_fetch");
+    for threads in [1u32, 4, 16] {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_function(format!("{threads}_threads"), |b| {
+            b.iter_custom(|iters| {
+                let data = &data;
+                let start = Instant::now();
+                thread::scope(|s| {
+                    for t in 0..threads {
+                        s.spawn(move || {
+                            let mut fetcher = TimelineFetcher::default();
+                            for i in 0..iters as u32 {
+                                let user = i.wrapping_mul(7919).wrapping_add(t) % n_users;
+                                fetcher.for_user(data, user, 50, START_TIME);
+                            }
+                        });
+                    }
+                });
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parallel_fetch_benchmark, criterion_benchmark);
 criterion_main!(benches);
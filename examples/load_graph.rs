@@ -1,69 +1,77 @@
 // process the graph from https://snap.stanford.edu/data/twitter-2010.html
 // time cat /Users/tristan/Downloads/twitter-2010.txt.gz | gunzip | cargo run --release --example load_graph
+//
+// other formats: cargo run --release --example load_graph -- --format metis|mtx|bvgraph [--transpose] [path]
+// text formats read stdin when no path is given, bvgraph takes the basename of the .graph/.properties files.
+// Edges (a, b) mean "b follows a" like twitter-2010, pass --transpose for graphs where a follows b.
+// --reorder degree|rcm relabels users for feed locality and also writes data/permutation.bin,
+// point TWITTERPERF_DATA at the output directory to benchmark it.
 
-use bytemuck::cast_slice;
 use std::{
     fs::File,
-    io::{self, BufRead, Write},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
 };
 
-use twitterperf::data::*;
+use twitterperf::import::*;
+use twitterperf::reorder::{Permutation, Reordering};
 
 const TEST: bool = true;
 
 fn main() {
-    let num_users = 41652230;
-    let mut graph: Vec<Vec<UserIdx>> = (0..num_users).map(|_| vec![]).collect();
-
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
-
-    let mut total_follows = 0;
-    while handle.read_line(&mut buffer).unwrap() != 0 {
-        let mut split = buffer.split(" ");
-        // An edge from i to j indicates that j is a follower of i
-        let i: u32 = split.next().unwrap().trim().parse().unwrap();
-        let j: u32 = split.next().unwrap().trim().parse().unwrap();
-        graph[j as usize].push(i);
-        buffer.clear();
-        total_follows += 1;
+    let mut format = Format::Snap;
+    let mut transpose = false;
+    let mut reordering: Option<Reordering> = None;
+    let mut path: Option<PathBuf> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--format" => format = args.next().expect("missing format").parse().unwrap(),
+            "--transpose" => transpose = true,
+            "--reorder" => {
+                reordering = Some(args.next().expect("missing reordering").parse().unwrap())
+            }
+            _ => path = Some(arg.into()),
+        }
     }
 
-    eprintln!("Done phase 1");
-
-    let mut users: Vec<User> = Vec::with_capacity(num_users);
-    let mut follows: Vec<UserIdx> = Vec::with_capacity(total_follows);
-
-    for ls in &graph {
-        let user = User {
-            follows_idx: follows.len(),
-            num_follows: ls.len() as u32,
-            num_followers: 0,
-        };
-        users.push(user);
-        for x in ls {
-            follows.push(*x);
+    let baked = match (format, &path) {
+        (Format::BvGraph, Some(path)) => import_bvgraph(path, transpose),
+        (Format::BvGraph, None) => panic!("bvgraph needs a basename"),
+        (_, Some(path)) => {
+            import_text(format, BufReader::new(File::open(path).unwrap()), transpose)
         }
+        (_, None) => import_text(format, io::stdin().lock(), transpose),
     }
+    .unwrap();
 
-    eprintln!("Done phase 2");
+    eprintln!(
+        "Loaded {} users with {} follows",
+        baked.users.len(),
+        baked.follows.len()
+    );
 
-    for f in &follows {
-        users[*f as usize].num_followers += 1;
-    }
-
-    eprintln!("Done phase 3");
+    let (baked, permutation) = match reordering {
+        Some(reordering) => {
+            let permutation = Permutation::compute(&baked.graph(), reordering);
+            eprintln!("Relabelled users by {reordering:?}");
+            (permutation.apply(&baked.graph()), Some(permutation))
+        }
+        None => (baked, None),
+    };
 
     if TEST {
         return;
     }
 
-    let mut users_f = File::create("data/users.bin").unwrap();
-    let user_bytes = cast_slice(&users[..]);
-    users_f.write_all(user_bytes).unwrap();
-
-    let mut follows_f = File::create("data/follows.bin").unwrap();
-    let follows_bytes = cast_slice(&follows[..]);
-    follows_f.write_all(follows_bytes).unwrap();
+    let dir = Path::new("data");
+    baked.save(dir).unwrap();
+    match permutation {
+        Some(permutation) => permutation.save(dir, &baked.graph()).unwrap(),
+        // LoadGraph would ignore it anyway, but don't leave it lying around
+        None => match std::fs::remove_file(dir.join("permutation.bin")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => panic!("{e}"),
+            _ => {}
+        },
+    }
 }
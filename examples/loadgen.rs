@@ -0,0 +1,99 @@
+// Drives examples/rpc_server.rs the way simulate drives an in-process Datastore
+// cargo run --release --example loadgen -- 127.0.0.1:9090
+// cargo run --release --example loadgen -- unix:/tmp/twitterperf.sock
+
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Instant;
+
+use twitterperf::data::START_TIME;
+use twitterperf::generate::{LoadGraph, TweetGenerator, TweetGeneratorConfig, ViewGenerator};
+use twitterperf::rpc::{Client, Request, Response};
+
+/// Requests kept in flight on each connection
+const PIPELINE_DEPTH: usize = 32;
+
+fn connect(addr: &str) -> Client<Box<dyn Read + Send>, Box<dyn Write + Send>> {
+    match addr.strip_prefix("unix:") {
+        Some(path) => {
+            let s = std::os::unix::net::UnixStream::connect(path).unwrap();
+            Client::new(Box::new(s.try_clone().unwrap()), Box::new(s))
+        }
+        None => {
+            let s = std::net::TcpStream::connect(addr).unwrap();
+            s.set_nodelay(true).unwrap();
+            Client::new(Box::new(s.try_clone().unwrap()), Box::new(s))
+        }
+    }
+}
+
+fn main() {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9090".to_string());
+
+    let loader = LoadGraph::new().unwrap();
+    let graph = loader.graph();
+    let config = TweetGeneratorConfig::default();
+    let (mut gen, viewing_users) = TweetGenerator::from_graph(config, &graph);
+
+    let n_test_add = 1_000_000;
+    let mut client = connect(&addr);
+    let add_start = Instant::now();
+    for _ in 0..n_test_add {
+        let (user, _) = gen.gen_tweet();
+        client
+            .send(&Request::AddTweet {
+                user,
+                content: String::new(),
+            })
+            .unwrap();
+        if client.in_flight() >= PIPELINE_DEPTH {
+            assert!(matches!(client.recv().unwrap(), Response::Added { .. }));
+        }
+    }
+    while client.in_flight() > 0 {
+        client.recv().unwrap();
+    }
+    let add_dur = Instant::now() - add_start;
+    let add_rate = n_test_add as f64 / add_dur.as_secs_f64();
+    eprintln!("Added {n_test_add} tweets in {add_dur:?}: {add_rate:.3} tweets/s.");
+
+    let n_views = 100_000;
+    let n_threads = 8;
+    eprintln!("Starting fetches from {n_threads} connections, {PIPELINE_DEPTH} deep");
+    let viewing_users = &viewing_users[..];
+    let addr = &addr[..];
+    thread::scope(|s| {
+        for _ in 0..n_threads {
+            let seed: u64 = gen.fork_seed();
+            s.spawn(move || {
+                let mut view_gen = ViewGenerator::new(seed, viewing_users);
+                let mut client = connect(addr);
+                let mut total_viewed = 0usize;
+                let start = Instant::now();
+                for _ in 0..n_views {
+                    let user = view_gen.gen_view();
+                    client
+                        .send(&Request::ForUser {
+                            user,
+                            max_len: 256,
+                            after: START_TIME,
+                        })
+                        .unwrap();
+                    if client.in_flight() >= PIPELINE_DEPTH {
+                        total_viewed += client.recv_timeline_len().unwrap().unwrap();
+                    }
+                }
+                while client.in_flight() > 0 {
+                    total_viewed += client.recv_timeline_len().unwrap().unwrap();
+                }
+                let dur = Instant::now() - start;
+                let rate = total_viewed as f64 / dur.as_secs_f64();
+                let req_rate = n_views as f64 / dur.as_secs_f64();
+                let avg_timeline_size = total_viewed as f64 / n_views as f64;
+                eprintln!("Done {total_viewed} in {dur:?} at {rate:.3} tweets/s, {req_rate:.0} timelines/s. Avg timeline size {avg_timeline_size:.2}");
+            });
+        }
+    });
+}
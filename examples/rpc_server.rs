@@ -0,0 +1,44 @@
+// Serves the binary protocol in twitterperf::rpc for examples/loadgen.rs
+// cargo run --release --example rpc_server -- 127.0.0.1:9090
+// cargo run --release --example rpc_server -- unix:/tmp/twitterperf.sock
+
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::time::Instant;
+
+use twitterperf::generate::{LoadGraph, TweetGenerator, TweetGeneratorConfig};
+use twitterperf::rpc::RpcServer;
+
+/// Connections beyond this wait in the listen backlog
+const MAX_CONNECTIONS: usize = 256;
+
+fn main() {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9090".to_string());
+
+    let loader = LoadGraph::new().unwrap();
+    let graph = loader.graph();
+
+    let n_tweets = 15_000_000;
+    let config = TweetGeneratorConfig::default();
+    let (mut gen, _viewing_users, mut data) = TweetGenerator::new(config, graph);
+
+    let add_start = Instant::now();
+    gen.add_tweets(&mut data, n_tweets).unwrap();
+    eprintln!(
+        "Added {n_tweets} tweets in {:?}",
+        Instant::now() - add_start
+    );
+
+    let server = RpcServer::new(&data, gen.next_ts());
+    eprintln!("Listening on {addr}");
+    match addr.strip_prefix("unix:") {
+        Some(path) => {
+            let _ = std::fs::remove_file(path);
+            server.serve_unix(UnixListener::bind(path).unwrap(), MAX_CONNECTIONS)
+        }
+        None => server.serve_tcp(TcpListener::bind(&addr).unwrap(), MAX_CONNECTIONS),
+    }
+    .unwrap();
+}
@@ -0,0 +1,36 @@
+// HTTP front end for load testing on localhost, e.g.
+// cargo run --release --example server -- 127.0.0.1:8080
+// curl -d 'user=12&content=hello' localhost:8080/tweets
+// wrk -c 8 -t 8 'http://localhost:8080/users/12/timeline?max=200'
+
+use std::net::TcpListener;
+use std::time::Instant;
+
+use twitterperf::generate::{LoadGraph, TweetGenerator, TweetGeneratorConfig};
+use twitterperf::http::Server;
+
+fn main() {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+
+    let loader = LoadGraph::new().unwrap();
+    let graph = loader.graph();
+
+    let n_tweets = 15_000_000;
+    let config = TweetGeneratorConfig::default();
+    let (mut gen, _viewing_users, mut data) = TweetGenerator::new(config, graph);
+
+    let add_start = Instant::now();
+    gen.add_tweets(&mut data, n_tweets).unwrap();
+    eprintln!(
+        "Added {n_tweets} tweets in {:?}",
+        Instant::now() - add_start
+    );
+
+    let server = Server::new(&data, gen.next_ts());
+    let listener = TcpListener::bind(&addr).unwrap();
+    let workers = std::thread::available_parallelism().map_or(8, |n| n.get());
+    eprintln!("Listening on http://{addr} with {workers} workers");
+    server.serve(listener, workers).unwrap();
+}
@@ -1,61 +1,282 @@
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use twitterperf::data::START_TIME;
+use twitterperf::data::{Graph, UserIdx, START_TIME};
 use twitterperf::generate::{LoadGraph, TweetGenerator, TweetGeneratorConfig, ViewGenerator};
+use twitterperf::histogram::Histogram;
+use twitterperf::numa::Topology;
+use twitterperf::pages::HugePageUsage;
+use twitterperf::perf::Counters;
+use twitterperf::reorder::{feed_locality, Permutation};
+use twitterperf::shard::{ShardedDatastore, ShardedFetcher};
 use twitterperf::timeline::TimelineFetcher;
-
-use signpost::{trace_function, AutoTrace};
+use twitterperf::trace;
 
 fn main() {
+    trace::init_from_env();
     let loader = LoadGraph::new().unwrap();
     let graph = loader.graph();
 
     let n_test_add = 15_000_000;
     let n_tweets = 30_000_000 - n_test_add;
     let config = TweetGeneratorConfig::default();
-    let (mut gen, viewing_users, mut data) = TweetGenerator::new(config, graph);
+    let (mut gen, viewing_users, mut store) = TweetGenerator::new(config, graph);
+    report_locality(&loader, &viewing_users);
 
     let add_start = Instant::now();
-    trace_function(1, &[0; 4], || gen.add_tweets(&mut data, n_tweets));
+    gen.add_tweets(&mut store, n_tweets).unwrap();
     let add_dur = Instant::now() - add_start;
     let add_rate = n_tweets as f64 / add_dur.as_secs_f64();
     eprintln!("Initially added {n_tweets} tweets in {add_dur:?}: {add_rate:.3} tweets/s.");
 
+    let counters = Counters::from_env();
     let add_start = Instant::now();
-    trace_function(1, &[0; 4], || gen.add_tweets(&mut data, n_tweets));
+    counters.iter().for_each(Counters::start);
+    gen.add_tweets(&mut store, n_tweets).unwrap();
     let add_dur = Instant::now() - add_start;
     let add_rate = n_test_add as f64 / add_dur.as_secs_f64();
     eprintln!("Benchmarked adding {n_test_add} tweets in {add_dur:?}: {add_rate:.3} tweets/s.");
+    if let Some(counters) = &counters {
+        eprintln!("Adding {}", counters.stop().per(n_test_add as u64, "tweet"));
+    }
 
-    let _x = AutoTrace::new(2, &[0usize; 4]);
+    let fetch_span = trace::span("fetch");
     let n_views = 100_000;
     // let mut total_likes = 0u32;
     let n_threads = 8;
     eprintln!("Starting fetches from {n_threads} threads");
     let viewing_users = &viewing_users[..];
-    let data = &data;
-    thread::scope(|s| {
-        for _ in 0..n_threads {
+    eprintln!(
+        "Pages: graph {:?}, tweets {:?}, huge page usage {:?}",
+        loader.page_policy(),
+        store.tweets.page_policy(),
+        HugePageUsage::current(),
+    );
+    counters.iter().for_each(Counters::start);
+    let topology = &Topology::from_env();
+    eprintln!(
+        "Pinning readers round-robin over {} NUMA nodes",
+        topology.nodes.len()
+    );
+    let data = &store;
+    let latencies = thread::scope(|s| {
+        let mut handles = Vec::new();
+        for thread_idx in 0..n_threads {
             let seed: u64 = gen.fork_seed();
-            s.spawn(move || {
+            handles.push(s.spawn(move || {
+            pin(topology, thread_idx);
             let mut view_gen = ViewGenerator::new(seed, viewing_users);
             let mut total_viewed = 0usize;
+            let mut latencies = Latencies::default();
             let start = Instant::now();
             let mut fetcher = TimelineFetcher::default();
             for _ in 0..n_views {
                 let user_idx = view_gen.gen_view();
+                let fetch_start = Instant::now();
                 let timeline = fetcher.for_user(data, user_idx, 256, START_TIME);
                 total_viewed += timeline.tweets.len();
                 // total_likes += timeline.tweets.iter().map(|t| t.likes).sum::<u32>();
+                let follows = data.graph.users[user_idx as usize].num_follows;
+                latencies.record(follows, fetch_start.elapsed());
             }
             let dur = Instant::now() - start;
             let rate = total_viewed as f64 / dur.as_secs_f64();
             let avg_timeline_size = total_viewed as f64 / n_views as f64;
             let expansion = (avg_timeline_size * view_gen.viewing_users.len() as f64) / n_tweets as f64;
             eprintln!("Done {total_viewed} in {dur:?} at {rate:.3} tweets/s. Avg timeline size {avg_timeline_size:.2} -> expansion {expansion:.2}");
-            });
+            latencies
+            }));
+        }
+        let mut all = Latencies::default();
+        for handle in handles {
+            all.merge(&handle.join().unwrap());
         }
+        all
     });
+    drop(fetch_span);
+    latencies.report();
     // eprintln!("{total_likes}");
+    if let Some(counters) = &counters {
+        let n_timelines = (n_views * n_threads) as u64;
+        eprintln!("Fetching {}", counters.stop().per(n_timelines, "timeline"));
+    }
+
+    // the optional runs build datastores of their own
+    drop(store);
+    if std::env::var_os("TWITTERPERF_SHARDED").is_some() {
+        for n_shards in SHARD_COUNTS {
+            simulate_sharded(&graph, n_shards, viewing_users);
+        }
+    }
+    if std::env::var_os("TWITTERPERF_HASHTAGS").is_some() {
+        for hashtag_rate in [0.0, 0.3] {
+            simulate_hashtags(graph, hashtag_rate);
+        }
+    }
+
+    match trace::finish() {
+        Ok(0) => {}
+        Ok(n) => eprintln!("Wrote {n} trace events"),
+        Err(e) => eprintln!("Couldn't write trace: {e}"),
+    }
+}
+
+/// Feed heads touched seeding the heap, and with a relabelled graph the same
+/// for the original order. Run again with `TWITTERPERF_DATA` pointing at the
+/// original graph to compare merge throughput.
+fn report_locality(loader: &LoadGraph, viewing_users: &[UserIdx]) {
+    let graph = loader.graph();
+    let sample = &viewing_users[..viewing_users.len().min(100_000)];
+    let here = feed_locality(&graph, sample, |f| f);
+    match loader.permutation() {
+        Some(new_of_old) => {
+            let old_of_new = Permutation {
+                new_of_old: new_of_old.to_vec(),
+            }
+            .old_of_new();
+            let original = feed_locality(&graph, sample, |f| old_of_new[f as usize]);
+            eprintln!(
+                "Using relabelled graph. Feed heads per fetch: {:.1} cache lines and {:.1} pages, {:.1} and {:.1} in the original order",
+                here.lines, here.pages, original.lines, original.pages
+            );
+        }
+        None => eprintln!(
+            "Feed heads per fetch: {:.1} cache lines and {:.1} pages",
+            here.lines, here.pages
+        ),
+    }
+}
+
+const SHARD_COUNTS: [usize; 4] = [1, 2, 4, 8];
+
+/// Upper bounds of the viewer follow counts latencies are broken down by,
+/// since the merge does work per followed account
+const FOLLOW_BUCKETS: [u32; 4] = [100, 1_000, 10_000, u32::MAX];
+
+#[derive(Default)]
+struct Latencies {
+    all: Histogram,
+    by_follows: [Histogram; FOLLOW_BUCKETS.len()],
+}
+
+impl Latencies {
+    fn record(&mut self, follows: u32, latency: Duration) {
+        let nanos = latency.as_nanos() as u64;
+        let bucket = FOLLOW_BUCKETS
+            .iter()
+            .position(|b| follows < *b)
+            .unwrap_or(FOLLOW_BUCKETS.len() - 1);
+        self.all.record(nanos);
+        self.by_follows[bucket].record(nanos);
+    }
+
+    fn merge(&mut self, other: &Latencies) {
+        self.all.merge(&other.all);
+        for (mine, theirs) in self.by_follows.iter_mut().zip(&other.by_follows) {
+            mine.merge(theirs);
+        }
+    }
+
+    fn report(&self) {
+        eprintln!("Timeline latency in us by viewer follows:");
+        eprintln!(
+            "{:>12} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "follows", "count", "p50", "p99", "p99.9", "max"
+        );
+        let mut lower = 0;
+        for (upper, hist) in FOLLOW_BUCKETS.iter().zip(&self.by_follows) {
+            let label = match *upper {
+                u32::MAX => format!("{lower}+"),
+                _ => format!("{lower}-{}", upper - 1),
+            };
+            Self::report_line(&label, hist);
+            lower = *upper;
+        }
+        Self::report_line("all", &self.all);
+    }
+
+    fn report_line(label: &str, hist: &Histogram) {
+        if hist.is_empty() {
+            return;
+        }
+        let us = |nanos: u64| nanos as f64 / 1000.0;
+        eprintln!(
+            "{label:>12} {:>9} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+            hist.len(),
+            us(hist.quantile(0.5)),
+            us(hist.quantile(0.99)),
+            us(hist.quantile(0.999)),
+            us(hist.max()),
+        );
+    }
+}
+
+/// Reader threads go round-robin over the nodes so each node's memory
+/// controller sees an equal share of the fetches
+fn pin(topology: &Topology, thread_idx: usize) {
+    if let Err(e) = topology.pin_current_thread(thread_idx) {
+        eprintln!("Couldn't pin reader {thread_idx}: {e}");
+    }
+}
+
+/// Smaller run of the same workload against a `ShardedDatastore`, with
+/// `TWITTERPERF_SHARDED` set
+fn simulate_sharded(graph: &Graph, n_shards: usize, viewing_users: &[UserIdx]) {
+    let n_tweets = 5_000_000;
+    let n_views = 20_000;
+    let n_threads = 8;
+    let data = ShardedDatastore::new(*graph, n_shards).unwrap();
+    let (mut gen, _) = TweetGenerator::from_graph(TweetGeneratorConfig::default(), graph);
+    for _ in 0..n_tweets {
+        let (user_id, tweet) = gen.gen_tweet();
+        data.add_tweet(tweet, user_id).unwrap();
+    }
+
+    let data = &data;
+    let topology = &Topology::from_env();
+    let start = Instant::now();
+    let total_viewed: usize = thread::scope(|s| {
+        let handles: Vec<_> = (0..n_threads)
+            .map(|thread_idx| {
+                let seed: u64 = gen.fork_seed();
+                s.spawn(move || {
+                    pin(topology, thread_idx);
+                    let mut view_gen = ViewGenerator::new(seed, viewing_users);
+                    let mut fetcher = ShardedFetcher::default();
+                    let mut total_viewed = 0usize;
+                    for _ in 0..n_views {
+                        let user_idx = view_gen.gen_view();
+                        total_viewed += fetcher
+                            .for_user(data, user_idx, 256, START_TIME)
+                            .tweets
+                            .len();
+                    }
+                    total_viewed
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).sum()
+    });
+    let dur = Instant::now() - start;
+    let rate = total_viewed as f64 / dur.as_secs_f64();
+    let timeline_rate = (n_views * n_threads) as f64 / dur.as_secs_f64();
+    eprintln!("{n_shards} shards: {total_viewed} in {dur:?} at {rate:.3} tweets/s, {timeline_rate:.0} timelines/s across {n_threads} threads");
+}
+
+/// `add_tweet` throughput with hashtag-bearing content feeding search and
+/// trends, with `TWITTERPERF_HASHTAGS` set
+fn simulate_hashtags(graph: Graph, hashtag_rate: f64) {
+    let n_tweets = 5_000_000;
+    let config = TweetGeneratorConfig {
+        hashtag_rate,
+        ..Default::default()
+    };
+    let (mut gen, _, mut data) = TweetGenerator::new(config, graph);
+    let start = Instant::now();
+    gen.add_tweets(&mut data, n_tweets).unwrap();
+    let dur = Instant::now() - start;
+    let rate = n_tweets as f64 / dur.as_secs_f64();
+    let trending = data.trends.trending(gen.next_ts(), 5);
+    let tags: Vec<&str> = trending.iter().map(|t| &t.tag[..]).collect();
+    eprintln!("hashtag rate {hashtag_rate}: added {n_tweets} tweets in {dur:?} at {rate:.3} tweets/s, trending {tags:?}");
 }
@@ -0,0 +1,126 @@
+//! Follow lists stored as sorted deltas in LEB128 varints.
+//!
+//! Most users follow accounts with nearby ids, so gaps are small and the
+//! 5.9GB of raw `u32`s shrinks a lot. The merge only ever walks a follow list
+//! front to back, so an iterator is all the access it needs.
+
+use crate::data::*;
+
+pub struct CompressedFollows {
+    /// byte range of user `i` is `offsets[i]..offsets[i + 1]`
+    offsets: Vec<usize>,
+    bytes: Vec<u8>,
+}
+
+impl CompressedFollows {
+    pub fn encode(graph: &Graph) -> Self {
+        let mut offsets = Vec::with_capacity(graph.users.len() + 1);
+        let mut bytes = Vec::with_capacity(graph.follows.len() * 2);
+        let mut sorted = vec![];
+        offsets.push(0);
+        for user in graph.users {
+            sorted.clear();
+            sorted.extend_from_slice(graph.user_follows(user));
+            sorted.sort_unstable();
+            let mut prev = 0;
+            for f in &sorted {
+                write_varint(&mut bytes, f - prev);
+                prev = *f;
+            }
+            offsets.push(bytes.len());
+        }
+        Self { offsets, bytes }
+    }
+
+    #[inline]
+    pub fn user_follows(&self, user_idx: UserIdx) -> FollowsIter<'_> {
+        let i = user_idx as usize;
+        FollowsIter {
+            bytes: &self.bytes[self.offsets[i]..self.offsets[i + 1]],
+            prev: 0,
+        }
+    }
+
+    /// Total size of the encoded lists plus the offset table
+    pub fn size_bytes(&self) -> usize {
+        self.bytes.len() + self.offsets.len() * std::mem::size_of::<usize>()
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u32) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+/// Yields a user's follows in increasing id order
+pub struct FollowsIter<'a> {
+    bytes: &'a [u8],
+    prev: UserIdx,
+}
+
+impl<'a> Iterator for FollowsIter<'a> {
+    type Item = UserIdx;
+
+    #[inline]
+    fn next(&mut self) -> Option<UserIdx> {
+        let mut delta = 0u32;
+        let mut shift = 0;
+        loop {
+            let (byte, rest) = self.bytes.split_first()?;
+            self.bytes = rest;
+            delta |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        self.prev += delta;
+        Some(self.prev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+    use crate::timeline::TimelineFetcher;
+
+    #[test]
+    fn round_trip() {
+        let mut builder = GraphBuilder::new(4, true);
+        for (a, b) in [(0, 3), (0, 1), (0, 200_000), (0, 1), (2, 0), (3, 70_000)] {
+            builder.add_edge(a, b);
+        }
+        let baked = builder.finish();
+        let graph = baked.graph();
+        let compressed = CompressedFollows::encode(&graph);
+
+        let follows: Vec<Vec<UserIdx>> = (0..graph.users.len() as UserIdx)
+            .map(|u| compressed.user_follows(u).collect())
+            .collect();
+        assert_eq!(follows[0], vec![1, 1, 3, 200_000]);
+        assert_eq!(follows[1], vec![]);
+        assert_eq!(follows[2], vec![0]);
+        assert_eq!(follows[3], vec![70_000]);
+
+        let data = Datastore::new(graph).unwrap();
+        for (i, user) in [3, 1, 200_000, 0, 3].into_iter().enumerate() {
+            let ts = Timestamp::new(i as u32 + 1).unwrap();
+            data.add_tweet(Tweet::dummy(ts), user).unwrap();
+        }
+        let mut fetcher = TimelineFetcher::default();
+        let expected: Vec<Timestamp> = fetcher
+            .for_user(&data, 0, 3, START_TIME)
+            .tweets
+            .iter()
+            .map(|t| t.ts)
+            .collect();
+        let timeline = fetcher.for_user_compressed(&data, &compressed, 0, 3, START_TIME);
+        let actual: Vec<Timestamp> = timeline.tweets.iter().map(|t| t.ts).collect();
+        assert_eq!(actual, expected);
+        assert_eq!(actual.len(), 3);
+    }
+}
@@ -1,9 +1,21 @@
-use std::{num::NonZeroU32, sync::atomic::AtomicU64};
+use std::collections::{HashSet, VecDeque};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bytemuck::{NoUninit, Pod, Zeroable};
 use static_assertions::assert_eq_size;
 
-use crate::pool::SharedPool;
+use crate::epoch::{EpochGuard, Epochs};
+use crate::lists::Lists;
+use crate::notify::{NotificationKind, Notifications};
+use crate::numa::{self, NumaPolicy};
+use crate::pool::{PoolConfig, PoolView, SharedPool};
+use crate::relations::Relations;
+use crate::search::SearchIndex;
+use crate::shm::{PerUser, ReaderGate, SharedFiles, SharedSlice};
+use crate::trends::Trends;
+use crate::visibility::Visibility;
 
 /// Leave room for a full 280 character plus some accents or emoji.
 /// A real implementation would have an escape hatch for longer tweets.
@@ -12,7 +24,7 @@ pub const TWEET_BYTES: usize = 286;
 // non-zero so options including a timestamp don't take any more space
 // u32 since that's 100+ years of second-level precision and it lets us pack atomics
 pub type Timestamp = NonZeroU32;
-pub const START_TIME: Timestamp = unsafe { NonZeroU32::new_unchecked(1) };
+pub const START_TIME: Timestamp = NonZeroU32::new(1).unwrap();
 
 #[derive(Clone)]
 pub struct Tweet {
@@ -34,16 +46,79 @@ impl Tweet {
             retweets: 0,
         }
     }
+
+    /// Truncates `text` to fit, backing off to a char boundary
+    pub fn new(ts: Timestamp, text: &str) -> Self {
+        let mut len = text.len().min(TWEET_BYTES);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut tweet = Self::dummy(ts);
+        tweet.content[..len].copy_from_slice(&text.as_bytes()[..len]);
+        tweet
+    }
+
+    /// Content up to the first NUL padding byte
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        content_text(&self.content)
+    }
+}
+
+fn content_text(content: &[u8; TWEET_BYTES]) -> std::borrow::Cow<'_, str> {
+    let len = content.iter().position(|b| *b == 0).unwrap_or(TWEET_BYTES);
+    String::from_utf8_lossy(&content[..len])
 }
 
 // assert_eq_size!([u8; 304], Tweet);
 
+/// A `Tweet` as stored in `ChainedTweet`, with the engagement counters that
+/// change after posting bumped in place. Readers get a `Tweet` through `load`.
+pub struct StoredTweet {
+    pub content: [u8; TWEET_BYTES],
+    pub ts: Timestamp,
+
+    pub likes: AtomicU32,
+    pub quotes: u32,
+    pub retweets: AtomicU32,
+}
+
+impl StoredTweet {
+    pub fn load(&self) -> Tweet {
+        Tweet {
+            content: self.content,
+            ts: self.ts,
+            likes: self.likes.load(Ordering::SeqCst),
+            quotes: self.quotes,
+            retweets: self.retweets.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        content_text(&self.content)
+    }
+}
+
+impl From<Tweet> for StoredTweet {
+    fn from(tweet: Tweet) -> Self {
+        Self {
+            content: tweet.content,
+            ts: tweet.ts,
+            likes: AtomicU32::new(tweet.likes),
+            quotes: tweet.quotes,
+            retweets: AtomicU32::new(tweet.retweets),
+        }
+    }
+}
+
+/// A tweet's index in `Datastore::tweets` narrowed to `u32`, so ids are reused
+/// every 2^32 tweets. Look them up through `Pinned`, and compare them with
+/// `crate::pool::id_cmp`.
 pub type TweetIdx = u32;
 
 /// linked list of tweets to make appending fast and avoid space overhead
 /// a linked list of chunks of tweets would probably be faster because of
 /// cache locality of fetches, but I haven't implemented that
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, NoUninit)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, NoUninit)]
 #[repr(C)]
 pub struct NextLink {
     pub ts: Timestamp,
@@ -69,30 +144,92 @@ impl AtomicChain {
 
     pub fn set(&self, next: NextLink) {
         let as_u64: u64 = bytemuck::cast(next);
-        self.0.store(as_u64, std::sync::atomic::Ordering::SeqCst);
+        self.0.store(as_u64, Ordering::SeqCst);
     }
 
     pub fn fetch(&self) -> Option<NextLink> {
-        let as_u64 = self.0.load(std::sync::atomic::Ordering::SeqCst);
+        let as_u64 = self.0.load(Ordering::SeqCst);
         // we hope LLVM optimizes this into a no-op
         let pod: PodNextLink = bytemuck::cast(as_u64);
-        match Timestamp::new(pod.0) {
-            Some(ts) => Some(NextLink {
-                ts,
-                tweet_idx: pod.1,
-            }),
-            None => None,
-        }
+        Timestamp::new(pod.0).map(|ts| NextLink {
+            ts,
+            tweet_idx: pod.1,
+        })
     }
 }
 
 #[repr(align(64))]
 pub struct ChainedTweet {
-    pub tweet: Tweet,
+    pub tweet: StoredTweet,
     pub prev_tweet: FeedChain,
+    /// fits in the padding, lets the pool double as a log of feed updates
+    pub author: UserIdx,
+    /// Which content readers should show: `ORIGINAL`, an `EditIdx + 1`, or `DELETED`.
+    /// Deleted tweets stay in their chain so older tweets are still reachable.
+    /// One word for both so an edit can't resurrect a racing delete.
+    pub version: AtomicU32,
 }
 assert_eq_size!([u8; 320], ChainedTweet);
 
+const ENGAGED_SHARDS: usize = 64;
+
+/// How long the visibility and relation logs were by the time the tweet pool
+/// was `tweets` long
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct LogMark {
+    tweets: usize,
+    visibility: usize,
+    relations: usize,
+}
+
+pub const ORIGINAL: u32 = 0;
+pub const DELETED: u32 = u32::MAX;
+
+impl ChainedTweet {
+    pub fn new(tweet: Tweet, prev_tweet: FeedChain, author: UserIdx) -> Self {
+        ChainedTweet {
+            tweet: tweet.into(),
+            prev_tweet,
+            author,
+            version: AtomicU32::new(ORIGINAL),
+        }
+    }
+
+    #[inline]
+    pub fn is_deleted(&self) -> bool {
+        self.version.load(Ordering::SeqCst) == DELETED
+    }
+}
+
+/// Narrowed like `TweetIdx`. Edits are never pushed where the version
+/// pointing at them would be `ORIGINAL` or `DELETED`.
+pub type EditIdx = u32;
+
+/// A change to some tweet's `ChainedTweet::version`, logged in
+/// `Datastore::version_log` so replicas can apply it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionUpdate {
+    pub tweet_idx: TweetIdx,
+    pub version: u32,
+}
+
+/// Edits are appended and never modified, so a reader that follows
+/// `ChainedTweet::version` to one can't see it half written
+pub struct TweetEdit {
+    /// The edited tweet, edits go once it and every tweet edited before it expired
+    pub tweet_idx: TweetIdx,
+    pub content: [u8; TWEET_BYTES],
+    pub edited_at: Timestamp,
+    /// `version` of the tweet before this edit
+    pub prev_version: u32,
+}
+
+impl TweetEdit {
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        content_text(&self.content)
+    }
+}
+
 pub type UserIdx = u32;
 
 #[derive(Copy, Clone, Pod, Zeroable)]
@@ -105,7 +242,9 @@ pub struct User {
 }
 
 /// We store the Graph in a format we can mmap from a pre-baked file
-/// so that our tests can load a real graph faster
+/// so that our tests can load a real graph faster.
+/// Each user's follows are sorted so membership is a binary search.
+#[derive(Clone, Copy)]
 pub struct Graph<'a> {
     pub users: &'a [User],
     pub follows: &'a [UserIdx],
@@ -121,22 +260,605 @@ impl<'a> Graph<'a> {
 pub struct Datastore<'a> {
     pub graph: Graph<'a>,
     pub tweets: SharedPool<ChainedTweet>,
-    pub feeds: Vec<AtomicChain>,
+    pub feeds: PerUser<AtomicChain>,
+    /// Undeleted tweets per user
+    pub tweet_counts: PerUser<AtomicU32>,
+    pub edits: SharedPool<TweetEdit>,
+    /// Every delete and edit in order, for `crate::replicate`, from the first
+    /// of a live tweet
+    pub version_log: SharedPool<VersionUpdate>,
+    edit_lock: Mutex<()>,
+    pub relations: Relations,
+    pub visibility: Visibility<'a>,
+    pub lists: Lists,
+    pub search: SearchIndex,
+    pub trends: Trends,
+    pub notifications: Notifications,
+    /// (kind, user, tweet) of every like and retweet of a live tweet, sharded
+    /// by tweet so each user counts once
+    engaged: Vec<Mutex<HashSet<(NotificationKind, UserIdx, TweetIdx)>>>,
+    /// Readers pin this while they hold references into `tweets`
+    pub epochs: Epochs,
+    /// Serializes expiry, with a mark from each run to truncate the logs by
+    retention_lock: Mutex<VecDeque<LogMark>>,
+    /// For shared datastores, see `crate::shm`
+    gate: Option<Arc<ReaderGate>>,
+}
+
+/// What a reader pinned with `Datastore::pin`. Tweets and edits live at that
+/// point stay readable until it's dropped, however much expires meanwhile.
+pub struct Pinned<'a> {
+    pub tweets: PoolView<'a, ChainedTweet>,
+    pub edits: PoolView<'a, TweetEdit>,
+    _guard: EpochGuard<'a>,
+}
+
+impl Pinned<'_> {
+    /// `None` if it expired before pinning, or doesn't exist
+    #[inline]
+    pub fn tweet(&self, tweet_idx: TweetIdx) -> Option<&ChainedTweet> {
+        self.tweets.get_narrow(tweet_idx)
+    }
+
+    /// The tweet a feed or chain link points at, `None` once it's expired.
+    /// Expired tweets are all older than live ones, so checking the timestamp
+    /// catches links old enough that their id was reused.
+    #[inline]
+    pub fn follow(&self, link: NextLink) -> Option<&ChainedTweet> {
+        self.tweet(link.tweet_idx)
+            .filter(|chained| chained.tweet.ts == link.ts)
+    }
+
+    /// The edit a live tweet's `version` points at
+    #[inline]
+    pub fn edit(&self, version: u32) -> &TweetEdit {
+        self.edits
+            .get_narrow(version.wrapping_sub(1))
+            .expect("edits of live tweets are kept")
+    }
+
+    /// The tweet as readers should see it, with the latest edit's content.
+    /// `None` if deleted.
+    #[inline]
+    pub fn current_tweet(&self, chained: &ChainedTweet) -> Option<Tweet> {
+        match chained.version.load(Ordering::SeqCst) {
+            ORIGINAL => Some(chained.tweet.load()),
+            DELETED => None,
+            version => {
+                let mut tweet = chained.tweet.load();
+                tweet.content = self.edit(version).content;
+                Some(tweet)
+            }
+        }
+    }
 }
 
 impl<'a> Datastore<'a> {
+    pub fn new(graph: Graph<'a>) -> std::io::Result<Self> {
+        let feeds: Vec<AtomicChain> = (0..graph.users.len())
+            .map(|_| AtomicChain::none())
+            .collect();
+        // readers hit every feed head, so spread them like the tweets
+        let numa = NumaPolicy::from_env();
+        if numa != NumaPolicy::Local {
+            let _ = numa::place(&feeds, numa);
+        }
+        let tweet_counts = (0..graph.users.len()).map(|_| AtomicU32::new(0)).collect();
+        Self::from_parts(
+            graph,
+            SharedPool::new()?,
+            SharedPool::new()?,
+            PerUser::Owned(feeds),
+            PerUser::Owned(tweet_counts),
+            None,
+        )
+    }
+
+    /// A datastore whose tweets and feeds other processes can read through
+    /// `open_shared`, see `crate::shm`. Replaces what's in the files.
+    pub fn create_shared(graph: Graph<'a>, files: &SharedFiles) -> std::io::Result<Self> {
+        let n = graph.users.len();
+        files.users.set_len(0)?;
+        files.users.set_len(Self::users_file_len(n) as u64)?;
+        let config = PoolConfig::default();
+        // tweets and edits are plain data, and the zeroed file is empty feeds
+        unsafe {
+            Self::from_parts(
+                graph,
+                SharedPool::create_shared(&files.tweets, config)?,
+                SharedPool::create_shared(&files.edits, config)?,
+                PerUser::Shared(SharedSlice::map(&files.users, Self::FEEDS_OFFSET, n, true)?),
+                PerUser::Shared(SharedSlice::map(
+                    &files.users,
+                    Self::counts_offset(n),
+                    n,
+                    true,
+                )?),
+                Some(ReaderGate::for_writer(&files.users)?),
+            )
+        }
+    }
+
+    /// Attaches read-only to a datastore another process made with
+    /// `create_shared` over the same graph. Mutators fail on it without
+    /// touching the mappings. Fails if the writer restricted anyone, and the
+    /// writer can't while this is attached, see `crate::shm`.
+    pub fn open_shared(graph: Graph<'a>, files: &SharedFiles) -> std::io::Result<Self> {
+        let n = graph.users.len();
+        if files.users.metadata()?.len() != Self::users_file_len(n) as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "shared datastore was made for another graph",
+            ));
+        }
+        let gate = ReaderGate::attach(&files.users)?;
+        unsafe {
+            Self::from_parts(
+                graph,
+                SharedPool::open_shared(&files.tweets)?,
+                SharedPool::open_shared(&files.edits)?,
+                PerUser::Shared(SharedSlice::map(
+                    &files.users,
+                    Self::FEEDS_OFFSET,
+                    n,
+                    false,
+                )?),
+                PerUser::Shared(SharedSlice::map(
+                    &files.users,
+                    Self::counts_offset(n),
+                    n,
+                    false,
+                )?),
+                Some(gate),
+            )
+        }
+    }
+
+    /// Feed heads follow the gate's header page
+    const FEEDS_OFFSET: usize = ReaderGate::HEADER_SIZE;
+
+    /// Tweet counts start on their own page after the feed heads
+    fn counts_offset(num_users: usize) -> usize {
+        Self::FEEDS_OFFSET + (num_users * std::mem::size_of::<AtomicChain>()).next_multiple_of(4096)
+    }
+
+    fn users_file_len(num_users: usize) -> usize {
+        Self::counts_offset(num_users) + num_users * std::mem::size_of::<AtomicU32>()
+    }
+
+    fn from_parts(
+        graph: Graph<'a>,
+        tweets: SharedPool<ChainedTweet>,
+        edits: SharedPool<TweetEdit>,
+        feeds: PerUser<AtomicChain>,
+        tweet_counts: PerUser<AtomicU32>,
+        gate: Option<ReaderGate>,
+    ) -> std::io::Result<Self> {
+        let gate = gate.map(Arc::new);
+        let mut relations = Relations::new(graph.users.len())?;
+        let mut visibility = Visibility::new(graph)?;
+        // a reader's own restrictions only affect what it serves
+        if let Some(gate) = gate.as_ref().filter(|_| tweets.check_writable().is_ok()) {
+            relations = relations.gated(gate.clone());
+            visibility = visibility.gated(gate.clone());
+        }
+        Ok(Datastore {
+            graph,
+            tweets,
+            feeds,
+            tweet_counts,
+            edits,
+            version_log: SharedPool::new()?,
+            edit_lock: Mutex::new(()),
+            relations,
+            visibility,
+            lists: Lists::new(graph.users.len()),
+            search: SearchIndex::default(),
+            trends: Trends::default(),
+            notifications: Notifications::new(graph.users.len())?,
+            engaged: (0..ENGAGED_SHARDS).map(|_| Mutex::default()).collect(),
+            epochs: Epochs::default(),
+            retention_lock: Mutex::default(),
+            gate,
+        })
+    }
+
+    /// Pins the epoch for reading tweets and edits, see `Pinned`
+    #[inline]
+    pub fn pin(&self) -> Pinned<'_> {
+        let guard = self.epochs.pin();
+        // pinned before reading where they start, so nothing from there is released
+        unsafe {
+            Pinned {
+                tweets: self.tweets.view(),
+                edits: self.edits.view(),
+                _guard: guard,
+            }
+        }
+    }
+
+    /// A `None` viewer is logged out and only sees public accounts
+    fn can_view(&self, viewer: Option<UserIdx>, author: UserIdx) -> bool {
+        match viewer {
+            Some(viewer) => self.visibility.can_see(viewer, author),
+            None => !self.visibility.is_protected(author),
+        }
+    }
+
     /// This will clobber writes (in a safe way) if called concurrently
     /// from multiple threads. Ideally we'd have a separate &mut handle for this
-    pub fn add_tweet(&self, tweet: Tweet, user_id: UserIdx) {
+    ///
+    /// Fails once the tweet pool holds as many live tweets as it can, or when
+    /// the notification pool can't take its mentions, which deletes the tweet.
+    pub fn add_tweet(&self, tweet: Tweet, user_id: UserIdx) -> std::io::Result<TweetIdx> {
+        self.tweets.check_writable()?;
         let prev_tweet = self.feeds[user_id as usize].fetch();
         let ts = tweet.ts;
-        let chained = ChainedTweet { tweet, prev_tweet };
-        let tweet_idx = self.tweets.push(chained) as TweetIdx;
+        let chained = ChainedTweet::new(tweet, prev_tweet, user_id);
+        let tweet_idx = self.tweets.push(chained)? as TweetIdx;
         self.feeds[user_id as usize].set(NextLink { ts, tweet_idx });
+        self.tweet_counts[user_id as usize].fetch_add(1, Ordering::SeqCst);
+        let pinned = self.pin();
+        let Some(chained) = pinned.tweet(tweet_idx) else {
+            // already expired
+            return Ok(tweet_idx);
+        };
+        let text = chained.tweet.text();
+        if let Err(e) = self
+            .notifications
+            .record_mentions(user_id, tweet_idx, ts, &text)
+        {
+            self.delete_tweet(tweet_idx);
+            return Err(e);
+        }
+        self.search.index(tweet_idx, &text);
+        self.trends.record(ts, &text);
+        Ok(tweet_idx)
+    }
+
+    /// Counts the like and notifies the author. Returns false if the tweet
+    /// doesn't exist, was deleted, `user` can't see it or already liked it,
+    /// and fails without counting it if the notification pool is full.
+    pub fn like_tweet(
+        &self,
+        user: UserIdx,
+        tweet_idx: TweetIdx,
+        ts: Timestamp,
+    ) -> std::io::Result<bool> {
+        self.engage(NotificationKind::Like, user, tweet_idx, ts)
+    }
+
+    /// Like `like_tweet`, it doesn't put the tweet in anyone's feed
+    pub fn retweet(
+        &self,
+        user: UserIdx,
+        tweet_idx: TweetIdx,
+        ts: Timestamp,
+    ) -> std::io::Result<bool> {
+        self.engage(NotificationKind::Retweet, user, tweet_idx, ts)
+    }
+
+    fn engage(
+        &self,
+        kind: NotificationKind,
+        user: UserIdx,
+        tweet_idx: TweetIdx,
+        ts: Timestamp,
+    ) -> std::io::Result<bool> {
+        self.tweets.check_writable()?;
+        let pinned = self.pin();
+        let Some(chained) = pinned.tweet(tweet_idx) else {
+            return Ok(false);
+        };
+        if chained.is_deleted() || !self.can_view(Some(user), chained.author) {
+            return Ok(false);
+        }
+        let shard = &self.engaged[tweet_idx as usize % ENGAGED_SHARDS];
+        let key = (kind, user, tweet_idx);
+        if !shard.lock().unwrap().insert(key) {
+            return Ok(false);
+        }
+        let author = chained.author;
+        if author != user {
+            if let Err(e) = self.notifications.push(author, kind, user, tweet_idx, ts) {
+                shard.lock().unwrap().remove(&key);
+                return Err(e);
+            }
+        }
+        let counter = match kind {
+            NotificationKind::Like => &chained.tweet.likes,
+            NotificationKind::Retweet => &chained.tweet.retweets,
+            NotificationKind::Mention => unreachable!("mentions aren't engagement"),
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Hides a tweet from all future reads. The content is left in place since
+    /// concurrent readers may be cloning it. Returns false if there's no such
+    /// tweet, it was already deleted, the version log is full or the datastore
+    /// is read-only.
+    pub fn delete_tweet(&self, tweet_idx: TweetIdx) -> bool {
+        if self.tweets.check_writable().is_err() {
+            return false;
+        }
+        let pinned = self.pin();
+        let Some(chained) = pinned.tweet(tweet_idx) else {
+            return false;
+        };
+        if chained.is_deleted() {
+            return false;
+        }
+        // logged first so a full log fails cleanly, replaying a delete twice is harmless
+        let update = VersionUpdate {
+            tweet_idx,
+            version: DELETED,
+        };
+        if self.version_log.push(update).is_err() {
+            return false;
+        }
+        if chained.version.swap(DELETED, Ordering::SeqCst) == DELETED {
+            return false;
+        }
+        self.tweet_counts[chained.author as usize].fetch_sub(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Replaces the content shown for a tweet, which keeps its original timestamp
+    /// and position in timelines. Returns false if there's no such tweet, it was
+    /// deleted, the edit pool or version log is full or the datastore is read-only.
+    pub fn edit_tweet(&self, tweet_idx: TweetIdx, text: &str, edited_at: Timestamp) -> bool {
+        if self.tweets.check_writable().is_err() {
+            return false;
+        }
+        let pinned = self.pin();
+        let Some(chained) = pinned.tweet(tweet_idx) else {
+            return false;
+        };
+        // edits to one tweet must chain in order, deletes can still race us
+        let _guard = self.edit_lock.lock().unwrap();
+        let prev_version = chained.version.load(Ordering::SeqCst);
+        if prev_version == DELETED {
+            return false;
+        }
+        let edit = TweetEdit {
+            tweet_idx,
+            content: Tweet::new(edited_at, text).content,
+            edited_at,
+            prev_version,
+        };
+        let Ok(edit_idx) = self.push_edit(edit) else {
+            return false;
+        };
+        let version = edit_idx.wrapping_add(1);
+        // a racing delete that makes the swap below fail is logged too, and wins on replicas
+        let update = VersionUpdate { tweet_idx, version };
+        if self.version_log.push(update).is_err() {
+            return false;
+        }
+        self.search.index(tweet_idx, text);
+        chained
+            .version
+            .compare_exchange(prev_version, version, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Pushes copies of `edit` past the indices whose version would be
+    /// `ORIGINAL` or `DELETED`, which replicas then copy like any other edit
+    pub fn push_edit(&self, edit: TweetEdit) -> std::io::Result<EditIdx> {
+        loop {
+            let copy = TweetEdit { ..edit };
+            let edit_idx = self.edits.push(copy)? as EditIdx;
+            if !matches!(edit_idx.wrapping_add(1), ORIGINAL | DELETED) {
+                return Ok(edit_idx);
+            }
+        }
+    }
+
+    /// Points a tweet at an edit already in `edits`, the way a replica replays
+    /// the leader's `version_log`. Deletes win. Returns false if there's no such
+    /// tweet, it was deleted, the version log is full or the datastore is read-only.
+    pub fn apply_edit(&self, tweet_idx: TweetIdx, edit_idx: EditIdx) -> bool {
+        if self.tweets.check_writable().is_err() {
+            return false;
+        }
+        let pinned = self.pin();
+        let (Some(chained), Some(edit)) =
+            (pinned.tweet(tweet_idx), pinned.edits.get_narrow(edit_idx))
+        else {
+            return false;
+        };
+        let version = edit_idx.wrapping_add(1);
+        let _guard = self.edit_lock.lock().unwrap();
+        if chained.is_deleted() {
+            return false;
+        }
+        if self
+            .version_log
+            .push(VersionUpdate { tweet_idx, version })
+            .is_err()
+        {
+            return false;
+        }
+        self.search.index(tweet_idx, &edit.text());
+        chained
+            .version
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| {
+                (v != DELETED).then_some(version)
+            })
+            .is_ok()
+    }
+
+    /// Every version of a tweet, newest first, each with `ts` set to when it was
+    /// written. Empty if deleted.
+    pub fn tweet_versions(&self, tweet_idx: TweetIdx) -> Vec<Tweet> {
+        let pinned = self.pin();
+        let Some(chained) = pinned.tweet(tweet_idx) else {
+            return vec![];
+        };
+        let mut version = chained.version.load(Ordering::SeqCst);
+        if version == DELETED {
+            return vec![];
+        }
+        let mut versions = vec![];
+        while version != ORIGINAL {
+            let edit = pinned.edit(version);
+            let mut tweet = chained.tweet.load();
+            tweet.content = edit.content;
+            tweet.ts = edit.edited_at;
+            versions.push(tweet);
+            version = edit.prev_version;
+        }
+        versions.push(chained.tweet.load());
+        versions
     }
 
-    pub fn prefetch_tweet(&self, tweet_idx: TweetIdx) {
-        let tweet_ptr = &self.tweets[tweet_idx as usize] as *const ChainedTweet;
+    /// Looks up the current version of a tweet, `None` if it doesn't exist or was deleted
+    pub fn get_tweet(&self, tweet_idx: TweetIdx) -> Option<Tweet> {
+        let pinned = self.pin();
+        pinned
+            .tweet(tweet_idx)
+            .and_then(|chained| pinned.current_tweet(chained))
+    }
+
+    /// Like `get_tweet` but also `None` if `viewer` isn't allowed to see it.
+    /// A `None` viewer is logged out and only sees public accounts.
+    pub fn get_tweet_as(&self, viewer: Option<UserIdx>, tweet_idx: TweetIdx) -> Option<Tweet> {
+        let pinned = self.pin();
+        let chained = pinned.tweet(tweet_idx)?;
+        if !self.can_view(viewer, chained.author) {
+            return None;
+        }
+        pinned.current_tweet(chained)
+    }
+
+    /// Releases every tweet older than `cutoff`, which also cuts off the tail of
+    /// every feed chain. Assumes tweets were added in timestamp order, as every
+    /// writer here does, so the expired tweets are a prefix of the pool.
+    /// Edits, version updates and notifications go in order as well, up to the
+    /// first edit or update of a live tweet and the first notification at or
+    /// after `cutoff`. Visibility and relation changes go once they were logged
+    /// before the oldest live tweet was added, as of an earlier expiry.
+    /// Returns how many tweets were released, none on a read-only datastore or
+    /// while shared readers are attached.
+    pub fn expire_before(&self, cutoff: Timestamp) -> usize {
+        if self.tweets.check_writable().is_err() {
+            return 0;
+        }
+        match &self.gate {
+            Some(gate) => gate.exclusive(|| self.expire(cutoff)).unwrap_or(0),
+            None => self.expire(cutoff),
+        }
+    }
+
+    fn expire(&self, cutoff: Timestamp) -> usize {
+        let mut marks = self.retention_lock.lock().unwrap();
+        // logs first, so everything they count was logged before `tweets`
+        let mark = LogMark {
+            visibility: self.visibility.log.len(),
+            relations: self.relations.log.len(),
+            tweets: self.tweets.len(),
+        };
+        if marks.back() != Some(&mark) {
+            marks.push_back(mark);
+        }
+        let start = self.tweets.start();
+        let len = self.tweets.len();
+        // we're the only one releasing, so indexing is safe without a pin
+        let (mut lo, mut hi) = (start, len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.tweets[mid].tweet.ts < cutoff {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        // an edit's tweet was pushed before it, even if that was after `len`
+        let live = |idx: TweetIdx| (idx.wrapping_sub(lo as u32) as usize) < self.tweets.len() - lo;
+        let mut edit_lo = self.edits.start();
+        while edit_lo < self.edits.len() && !live(self.edits[edit_lo].tweet_idx) {
+            edit_lo += 1;
+        }
+        let updates = &self.version_log;
+        let mut update_lo = updates.start();
+        while update_lo < updates.len() && !live(updates[update_lo].tweet_idx) {
+            update_lo += 1;
+        }
+        let events = &self.notifications.events;
+        let mut event_lo = events.start();
+        while event_lo < events.len() && events[event_lo].ts < cutoff {
+            event_lo += 1;
+        }
+        let (visibility, relations) = (&self.visibility.log, &self.relations.log);
+        let mut logged = LogMark {
+            tweets: 0,
+            visibility: visibility.start(),
+            relations: relations.start(),
+        };
+        while marks.front().is_some_and(|m| m.tweets <= lo) {
+            logged = marks.pop_front().unwrap();
+        }
+        if lo == start
+            && edit_lo == self.edits.start()
+            && update_lo == updates.start()
+            && event_lo == events.start()
+            && logged.visibility == visibility.start()
+            && logged.relations == relations.start()
+        {
+            return 0;
+        }
+
+        // unreachable first, so new readers and deletes stop at `lo`
+        let old_start = self.tweets.truncate_front(lo);
+        let old_edit_start = self.edits.truncate_front(edit_lo);
+        let old_update_start = updates.truncate_front(update_lo);
+        let old_event_start = events.truncate_front(event_lo);
+        let old_visibility_start = visibility.truncate_front(logged.visibility);
+        let old_relation_start = relations.truncate_front(logged.relations);
+        self.search.prune(lo as TweetIdx);
+        self.epochs.synchronize();
+        // after synchronizing, so no `engage` still adds an expired tweet.
+        // We're the only one releasing, so the view is safe without a pin.
+        let live = unsafe { self.tweets.view() };
+        for shard in &self.engaged {
+            shard
+                .lock()
+                .unwrap()
+                .retain(|(_, _, idx)| live.position(*idx).is_some());
+        }
+        for i in old_start..lo {
+            // truncated but not released yet
+            let chained = unsafe { self.tweets.get_unchecked(i) };
+            if !chained.is_deleted() {
+                self.tweet_counts[chained.author as usize].fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        unsafe {
+            self.tweets.release(old_start, lo);
+            self.edits.release(old_edit_start, edit_lo);
+            updates.release(old_update_start, update_lo);
+            events.release(old_event_start, event_lo);
+            visibility.release(old_visibility_start, logged.visibility);
+            relations.release(old_relation_start, logged.relations);
+        }
+        lo - old_start
+    }
+
+    /// Protecting keeps everyone following `author` in the graph approved.
+    /// Returns false if `author` already was or wasn't protected.
+    pub fn set_protected(&self, author: UserIdx, protected: bool) -> bool {
+        match protected {
+            true => self.visibility.protect(author),
+            false => self.visibility.unprotect(author),
+        }
+    }
+
+    pub fn prefetch_tweet(&self, pinned: &Pinned, tweet_idx: TweetIdx) {
+        let Some(chained) = pinned.tweet(tweet_idx) else {
+            return;
+        };
+        let tweet_ptr = chained as *const ChainedTweet;
         unsafe {
             for cache_line in 0..3 {
                 let line_ptr = (tweet_ptr as *const i8).offset(64 * cache_line);
@@ -145,3 +867,31 @@ impl<'a> Datastore<'a> {
         }
     }
 }
+
+/// Lets many threads post tweets, which `add_tweet` alone doesn't allow,
+/// stamping each with a strictly increasing timestamp
+pub struct Publisher<'a, 'g> {
+    pub data: &'a Datastore<'g>,
+    /// Last timestamp handed out
+    clock: AtomicU32,
+    lock: Mutex<()>,
+}
+
+impl<'a, 'g> Publisher<'a, 'g> {
+    /// `now` should be at least the newest timestamp already in `data`
+    pub fn new(data: &'a Datastore<'g>, now: Timestamp) -> Self {
+        Self {
+            data,
+            clock: AtomicU32::new(now.get()),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn publish(&self, user_id: UserIdx, text: &str) -> std::io::Result<(TweetIdx, Timestamp)> {
+        let _guard = self.lock.lock().unwrap();
+        let ts = Timestamp::new(self.clock.load(Ordering::SeqCst) + 1).unwrap();
+        let tweet_idx = self.data.add_tweet(Tweet::new(ts, text), user_id)?;
+        self.clock.store(ts.get(), Ordering::SeqCst);
+        Ok((tweet_idx, ts))
+    }
+}
@@ -0,0 +1,158 @@
+//! Epoch-based reclamation for memory readers reach without locks.
+//!
+//! Readers pin the current epoch for the duration of a fetch. A writer that
+//! wants to free memory first makes it unreachable, e.g. by advancing
+//! `SharedPool::start`, then calls `synchronize`, after which no reader can
+//! still be looking at it.
+//!
+//! Only two epochs are ever live, so this is two counters of pinned readers
+//! and a flip, like userspace RCU. A reader that pinned the old epoch bumped
+//! its counter before the flip, and one that raced the flip sees the new epoch
+//! when it re-checks and moves over.
+//!
+//! Every fetch pins, so the counters are spread over cache-line sized slots
+//! and each thread sticks to one, like crossbeam-epoch's local records.
+//! `synchronize` waits for each slot in turn. A slot's count never goes
+//! negative, since a guard unpins the slot it pinned.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Pinned readers per epoch parity, for the threads sharing this slot
+#[repr(align(128))]
+#[derive(Default)]
+struct Slot {
+    active: [AtomicUsize; 2],
+}
+
+pub struct Epochs {
+    epoch: AtomicUsize,
+    /// Power of two, at least as many as there are CPUs
+    slots: Box<[Slot]>,
+    /// `synchronize` calls must not overlap or a flip could skip readers
+    lock: Mutex<()>,
+}
+
+/// Handed out round robin as threads first pin, so threads land on different slots
+fn thread_slot() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SLOT: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    SLOT.with(|slot| *slot)
+}
+
+/// Keeps the epoch pinned until dropped
+pub struct EpochGuard<'a> {
+    active: &'a AtomicUsize,
+}
+
+impl Drop for EpochGuard<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for Epochs {
+    fn default() -> Self {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        Self {
+            epoch: AtomicUsize::new(0),
+            slots: (0..cpus.next_power_of_two())
+                .map(|_| Slot::default())
+                .collect(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Epochs {
+    /// Never call `synchronize` while holding the guard, it would wait on itself
+    #[inline]
+    pub fn pin(&self) -> EpochGuard<'_> {
+        let slot = &self.slots[thread_slot() & (self.slots.len() - 1)];
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let active = &slot.active[epoch & 1];
+            active.fetch_add(1, Ordering::SeqCst);
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                return EpochGuard { active };
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Waits until every reader that pinned before this call has unpinned
+    pub fn synchronize(&self) {
+        let _guard = self.lock.lock().unwrap();
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+        for slot in &self.slots[..] {
+            while slot.active[epoch & 1].load(Ordering::SeqCst) != 0 {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    #[test]
+    fn waits_for_readers() {
+        let epochs = Epochs::default();
+        let released = AtomicBool::new(false);
+        thread::scope(|s| {
+            let guard = epochs.pin();
+            s.spawn(|| {
+                epochs.synchronize();
+                released.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!released.load(Ordering::SeqCst));
+            // readers pinning after the flip don't hold it up
+            let _late = epochs.pin();
+            drop(guard);
+            while !released.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+        });
+        assert!(released.load(Ordering::SeqCst));
+        epochs.synchronize();
+    }
+
+    #[test]
+    fn waits_for_every_slot() {
+        let epochs = Epochs::default();
+        let pinned = AtomicUsize::new(0);
+        let release = AtomicBool::new(false);
+        let released = AtomicBool::new(false);
+        thread::scope(|s| {
+            // more readers than slots, so some share
+            for _ in 0..2 * epochs.slots.len() + 1 {
+                s.spawn(|| {
+                    let _guard = epochs.pin();
+                    pinned.fetch_add(1, Ordering::SeqCst);
+                    while !release.load(Ordering::SeqCst) {
+                        thread::yield_now();
+                    }
+                });
+            }
+            while pinned.load(Ordering::SeqCst) < 2 * epochs.slots.len() + 1 {
+                thread::yield_now();
+            }
+            s.spawn(|| {
+                epochs.synchronize();
+                released.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!released.load(Ordering::SeqCst));
+            release.store(true, Ordering::SeqCst);
+        });
+        assert!(released.load(Ordering::SeqCst));
+    }
+}
@@ -1,5 +1,8 @@
 use crate::data::*;
-use crate::pool::SharedPool;
+use crate::numa::NumaPolicy;
+use crate::pages::{AnonMap, PagePolicy};
+use crate::reorder;
+use crate::trace;
 
 use bytemuck::cast_slice;
 use memmap2::Mmap;
@@ -7,12 +10,17 @@ use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
 use rand_wyrand::WyRand;
 use std::fs::File;
+use std::io::{ErrorKind, Write};
 use std::ops::Deref;
+use std::path::Path;
 
 pub struct TweetGeneratorConfig {
     pub seed: u64,
     pub tweeter_follower_thresh: u32,
     pub viewer_follow_thresh: u32,
+    /// Fraction of tweets that carry a hashtag, drawn with a skew towards low numbers
+    pub hashtag_rate: f64,
+    pub num_hashtags: u32,
 }
 
 impl Default for TweetGeneratorConfig {
@@ -21,6 +29,8 @@ impl Default for TweetGeneratorConfig {
             seed: 123,
             tweeter_follower_thresh: 20,
             viewer_follow_thresh: 20,
+            hashtag_rate: 0.0,
+            num_hashtags: 1000,
         }
     }
 }
@@ -30,6 +40,8 @@ pub struct TweetGenerator {
     tweeting_users: Vec<UserIdx>,
     rng: WyRand,
     ts: Timestamp,
+    hashtag_rate: f64,
+    num_hashtags: u32,
 }
 
 pub type ViewingUsers = Vec<UserIdx>;
@@ -39,11 +51,14 @@ impl TweetGenerator {
         config: TweetGeneratorConfig,
         graph: Graph<'a>,
     ) -> (Self, ViewingUsers, Datastore<'a>) {
-        let feeds: Vec<AtomicChain> = (0..graph.users.len())
-            .map(|_| AtomicChain::none())
-            .collect();
-        let tweets = SharedPool::new().unwrap();
+        let (this, viewing_users) = Self::from_graph(config, &graph);
+        let data = Datastore::new(graph).unwrap();
+        (this, viewing_users, data)
+    }
 
+    /// Picks users the same way as `new` without building a `Datastore`,
+    /// for driving a remote one
+    pub fn from_graph(config: TweetGeneratorConfig, graph: &Graph) -> (Self, ViewingUsers) {
         let mut rng = WyRand::from_seed(config.seed.to_le_bytes());
         let mut tweeting_users: Vec<u32> = graph
             .users
@@ -66,30 +81,38 @@ impl TweetGenerator {
             tweeting_users,
             rng,
             ts: START_TIME,
+            hashtag_rate: config.hashtag_rate,
+            num_hashtags: config.num_hashtags,
         };
 
-        let data = Datastore {
-            graph,
-            tweets,
-            feeds,
-        };
-
-        (this, viewing_users, data)
+        (this, viewing_users)
     }
 
     pub fn gen_tweet(&mut self) -> (UserIdx, Tweet) {
         // TODO Zipf distribution or something
         let user_id: UserIdx = *self.tweeting_users.choose(&mut self.rng).unwrap();
-        let tweet = Tweet::dummy(self.ts);
+        let mut tweet = Tweet::dummy(self.ts);
+        if self.hashtag_rate > 0.0 && self.rng.gen_bool(self.hashtag_rate) {
+            let tag = (self.rng.gen::<f64>().powi(3) * self.num_hashtags as f64) as u32;
+            // straight into the content so this doesn't allocate
+            let _ = write!(&mut tweet.content[..], "#tag{tag}");
+        }
         self.ts = self.ts.saturating_add(1);
         (user_id, tweet)
     }
 
-    pub fn add_tweets(&mut self, data: &mut Datastore, n: usize) {
+    /// Timestamp the next generated tweet will get
+    pub fn next_ts(&self) -> Timestamp {
+        self.ts
+    }
+
+    pub fn add_tweets(&mut self, data: &mut Datastore, n: usize) -> std::io::Result<()> {
+        let _span = trace::span("ingest");
         for _ in 0..n {
             let (user_id, tweet) = self.gen_tweet();
-            data.add_tweet(tweet, user_id);
+            data.add_tweet(tweet, user_id)?;
         }
+        Ok(())
     }
 
     pub fn fork_seed(&mut self) -> u64 {
@@ -117,17 +140,92 @@ impl<'a> ViewGenerator<'a> {
     }
 }
 
+/// The graph files, mapped directly or copied into memory that can have huge
+/// pages and a NUMA policy, since shared file pages mostly can't
+enum GraphMap {
+    File(Mmap),
+    Copy(AnonMap),
+}
+
+impl GraphMap {
+    fn open(path: &Path, pages: PagePolicy, numa: NumaPolicy) -> std::io::Result<Self> {
+        let file = unsafe { Mmap::map(&File::open(path)?)? };
+        Ok(match (pages, numa) {
+            (PagePolicy::Small, NumaPolicy::Local) => GraphMap::File(file),
+            _ => GraphMap::Copy(AnonMap::copy_of(&file, pages, numa)?),
+        })
+    }
+
+    fn policy(&self) -> PagePolicy {
+        match self {
+            GraphMap::File(_) => PagePolicy::Small,
+            GraphMap::Copy(copy) => copy.policy,
+        }
+    }
+}
+
+impl Deref for GraphMap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            GraphMap::File(map) => map,
+            GraphMap::Copy(copy) => copy,
+        }
+    }
+}
+
 pub struct LoadGraph {
-    users: Mmap,
-    follows: Mmap,
+    users: GraphMap,
+    follows: GraphMap,
+    permutation: Option<Mmap>,
 }
 
 impl LoadGraph {
+    /// Loads from `data/` or the directory in `TWITTERPERF_DATA`,
+    /// so a relabelled graph can be benchmarked without code changes.
+    /// Placement follows `TWITTERPERF_PAGES` and `TWITTERPERF_NUMA`.
     pub fn new() -> std::io::Result<Self> {
-        Ok(Self {
-            users: unsafe { Mmap::map(&File::open("data/users.bin")?)? },
-            follows: unsafe { Mmap::map(&File::open("data/follows.bin")?)? },
-        })
+        let dir = std::env::var_os("TWITTERPERF_DATA").unwrap_or_else(|| "data".into());
+        Self::open_placed(
+            Path::new(&dir),
+            PagePolicy::from_env(),
+            NumaPolicy::from_env(),
+        )
+    }
+
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        Self::open_placed(dir, PagePolicy::Small, NumaPolicy::Local)
+    }
+
+    /// Anything but small local pages copies the graph into anonymous memory up front.
+    /// A `permutation.bin` saved for some other graph is ignored.
+    pub fn open_placed(dir: &Path, pages: PagePolicy, numa: NumaPolicy) -> std::io::Result<Self> {
+        let permutation = match File::open(dir.join("permutation.bin")) {
+            Ok(f) => Some(unsafe { Mmap::map(&f)? }),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let mut loader = Self {
+            users: GraphMap::open(&dir.join("users.bin"), pages, numa)?,
+            follows: GraphMap::open(&dir.join("follows.bin"), pages, numa)?,
+            permutation,
+        };
+        if loader.permutation().is_none() {
+            loader.permutation = None;
+        }
+        Ok(loader)
+    }
+
+    /// What the follows, the bulk of the graph, ended up on
+    pub fn page_policy(&self) -> PagePolicy {
+        self.follows.policy()
+    }
+
+    /// Original id to relabelled id, if the graph was baked with a reordering
+    pub fn permutation(&self) -> Option<&[UserIdx]> {
+        let bytes = self.permutation.as_ref()?;
+        reorder::saved_permutation(bytes, &self.graph())
     }
 
     pub fn graph<'a>(&'a self) -> Graph<'a> {
@@ -153,6 +251,31 @@ mod tests {
         ex.assert_eq(&format!("{f:.3}"));
     }
 
+    #[test]
+    fn page_policies() {
+        let mut builder = crate::import::GraphBuilder::new(4, true);
+        builder.add_edge(0, 1);
+        builder.add_edge(2, 1);
+        builder.add_edge(3, 0);
+        let baked = builder.finish();
+        let dir = std::env::temp_dir().join(format!("pages-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        baked.save(&dir).unwrap();
+        for pages in [
+            PagePolicy::Small,
+            PagePolicy::Transparent,
+            PagePolicy::HugeTlb,
+        ] {
+            let loader = LoadGraph::open_placed(&dir, pages, NumaPolicy::Interleave).unwrap();
+            assert_eq!(loader.graph().follows, baked.graph().follows);
+            assert_eq!(loader.graph().users.len(), 4);
+            if pages == PagePolicy::Small {
+                assert_eq!(loader.page_policy(), PagePolicy::Small);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn loading() {
         let loader = LoadGraph::new().unwrap();
@@ -178,24 +301,25 @@ mod tests {
 
         let n_tweets = 4_000_000;
         let config = TweetGeneratorConfig::default();
-        let (mut gen, mut data) = TweetGenerator::new(config, graph);
+        let (mut gen, viewing_users, mut data) = TweetGenerator::new(config, graph);
 
-        n_eq(gen.viewing_users.len(), expect!["9031061"]);
+        n_eq(viewing_users.len(), expect!["9031061"]);
         n_eq(gen.tweeting_users.len(), expect!["6746960"]);
 
-        gen.add_tweets(&mut data, n_tweets);
+        gen.add_tweets(&mut data, n_tweets).unwrap();
+        let mut view_gen = ViewGenerator::new(gen.fork_seed(), &viewing_users);
 
         let n_views = 100_000;
         let mut total_viewed = 0usize;
         let mut fetcher = TimelineFetcher::default();
         for _ in 0..n_views {
-            let user_idx = gen.gen_view();
+            let user_idx = view_gen.gen_view();
             let timeline = fetcher.for_user(&data, user_idx, 200, START_TIME);
             total_viewed += timeline.tweets.len();
         }
         let avg_timeline_size = total_viewed as f64 / n_views as f64;
         f_eq(avg_timeline_size, expect!["41.480"]);
-        let expansion = (avg_timeline_size * gen.viewing_users.len() as f64) / n_tweets as f64;
+        let expansion = (avg_timeline_size * viewing_users.len() as f64) / n_tweets as f64;
         f_eq(expansion, expect!["93.652"]);
     }
 }
@@ -0,0 +1,127 @@
+//! Latency histograms in the style of HdrHistogram.
+//!
+//! Buckets are log-linear: each power of two is split into `SUB_BUCKETS`
+//! equal parts, so any value is recorded within 1% using a fixed 60KB of
+//! counters whatever the range. Recording is an index computation and an
+//! increment, cheap enough to do per request, and histograms from different
+//! threads merge by adding counts.
+
+const SUB_BITS: u32 = 7;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Box<[u64]>,
+    total: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS].into_boxed_slice(),
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+#[inline]
+fn index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = value.ilog2() - SUB_BITS;
+    let mantissa = (value >> shift) as usize;
+    (shift as usize + 1) * SUB_BUCKETS + mantissa - SUB_BUCKETS
+}
+
+/// Largest value that lands in bucket `i`
+fn highest(i: usize) -> u64 {
+    if i < SUB_BUCKETS {
+        return i as u64;
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let mantissa = (i % SUB_BUCKETS + SUB_BUCKETS) as u64;
+    (mantissa << shift) + ((1 << shift) - 1)
+}
+
+impl Histogram {
+    #[inline]
+    pub fn record(&mut self, value: u64) {
+        self.counts[index(value)] += 1;
+        self.total += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts[..]) {
+            *count += other;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Smallest recorded value at or above fraction `q` of the values, to
+    /// within the bucket's precision. 0 when empty.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = ((q * self.total as f64).ceil() as u64).clamp(1, self.total.max(1));
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return highest(i).min(self.max);
+            }
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for v in (0..100_000).chain([u64::MAX / 3, u64::MAX]) {
+            let i = index(v);
+            assert!(highest(i) >= v);
+            assert!(i == 0 || highest(i - 1) < v, "{v}");
+            // within 1%
+            assert!((highest(i) - v) as f64 <= v as f64 / SUB_BUCKETS as f64);
+        }
+        assert_eq!(index(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut a = Histogram::default();
+        let mut b = Histogram::default();
+        assert_eq!(a.quantile(0.5), 0);
+        for v in 1..=1000u64 {
+            a.record(v * 1000);
+        }
+        b.record(5_000_000);
+        a.merge(&b);
+        assert_eq!(a.len(), 1001);
+        assert_eq!(a.max(), 5_000_000);
+        assert_eq!(a.quantile(1.0), 5_000_000);
+        let p50 = a.quantile(0.5);
+        assert!((500_000..=505_000).contains(&p50), "{p50}");
+        let p99 = a.quantile(0.99);
+        assert!((990_000..=1_000_000).contains(&p99), "{p99}");
+    }
+}
@@ -0,0 +1,484 @@
+//! Just enough HTTP/1.1 to drive a `Datastore` with ordinary load tools.
+//!
+//! A fixed pool of worker threads each own a `TimelineFetcher` and serve one
+//! connection at a time, with keep-alive so the per-request cost stays close
+//! to the merge itself. Idle connections are closed so they can't tie up a
+//! worker.
+
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::data::*;
+use crate::timeline::{TimelineFetcher, MAX_TIMELINE_LEN};
+
+pub const DEFAULT_TIMELINE_LEN: usize = 200;
+/// Form bodies only carry a tweet, anything bigger is refused before allocating
+pub const MAX_BODY_BYTES: usize = 64 << 10;
+/// The request line and headers together, longer ones are refused with 431
+pub const MAX_HEADER_BYTES: usize = 8 << 10;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub body: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Self {
+        Self { status, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        let mut body = String::from("{\"error\":");
+        write_json_str(&mut body, msg);
+        body.push('}');
+        Self { status, body }
+    }
+}
+
+/// Inside the error `read_request` returns for a head over `MAX_HEADER_BYTES`
+#[derive(Debug)]
+pub struct HeadersTooLarge;
+
+impl fmt::Display for HeadersTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request line and headers over {MAX_HEADER_BYTES} bytes")
+    }
+}
+
+impl std::error::Error for HeadersTooLarge {}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+/// Returns `None` when the client closed the connection between requests.
+/// Fails with `InvalidData` on malformed requests, bodies over `MAX_BODY_BYTES`
+/// and `HeadersTooLarge`.
+pub fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut head = reader.by_ref().take(MAX_HEADER_BYTES as u64);
+    let mut read_line = |line: &mut String| {
+        let n = head.read_line(line)?;
+        if !line.ends_with('\n') && head.limit() == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, HeadersTooLarge));
+        }
+        Ok(n)
+    };
+    let mut line = String::new();
+    if read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad request line",
+        ));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        body: vec![],
+        keep_alive: version == "HTTP/1.1",
+    };
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad content-length"))?;
+        } else if name.eq_ignore_ascii_case("connection") {
+            request.keep_alive = !value.eq_ignore_ascii_case("close");
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("body of {content_length} bytes is over {MAX_BODY_BYTES}"),
+        ));
+    }
+    request.body.resize(content_length, 0);
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+pub fn write_response(w: &mut impl Write, response: &Response, keep_alive: bool) -> io::Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        response.body.len(),
+        if keep_alive { "keep-alive" } else { "close" },
+        response.body
+    )
+}
+
+/// Decodes `application/x-www-form-urlencoded` pairs, used for both queries and bodies
+pub fn form_pairs(s: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    s.split('&').filter(|p| !p.is_empty()).map(|p| {
+        let (k, v) = p.split_once('=').unwrap_or((p, ""));
+        (percent_decode(k), percent_decode(v))
+    })
+}
+
+fn percent_decode(s: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    out.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_json_tweet(out: &mut String, tweet: &Tweet) {
+    let _ = write!(
+        out,
+        "{{\"ts\":{},\"likes\":{},\"quotes\":{},\"retweets\":{},\"content\":",
+        tweet.ts, tweet.likes, tweet.quotes, tweet.retweets
+    );
+    write_json_str(out, &tweet.text());
+    out.push('}');
+}
+
+pub struct Server<'a, 'g> {
+    data: &'a Datastore<'g>,
+    publisher: Publisher<'a, 'g>,
+}
+
+impl<'a, 'g> Server<'a, 'g> {
+    /// `now` should be at least the newest timestamp already in `data`
+    pub fn new(data: &'a Datastore<'g>, now: Timestamp) -> Self {
+        Self {
+            data,
+            publisher: Publisher::new(data, now),
+        }
+    }
+
+    pub fn handle(&self, fetcher: &mut TimelineFetcher, req: &Request) -> Response {
+        let segments: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+        match (&req.method[..], &segments[..]) {
+            ("POST", ["tweets"]) => self.post_tweet(req),
+            ("GET", ["tweets", idx]) => self.get_tweet(idx, &req.query),
+            ("GET", ["users", id, "timeline"]) => self.get_timeline(fetcher, id, &req.query),
+            (_, ["tweets"] | ["tweets", _] | ["users", _, "timeline"]) => {
+                Response::error(405, "method not allowed")
+            }
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    fn parse_user(&self, s: &str) -> Option<UserIdx> {
+        s.parse()
+            .ok()
+            .filter(|u: &UserIdx| (*u as usize) < self.data.graph.users.len())
+    }
+
+    fn post_tweet(&self, req: &Request) -> Response {
+        let body = String::from_utf8_lossy(&req.body);
+        let mut user = None;
+        let mut content = String::new();
+        for (k, v) in form_pairs(&body) {
+            match &k[..] {
+                "user" => user = self.parse_user(&v),
+                "content" => content = v,
+                _ => {}
+            }
+        }
+        let Some(user) = user else {
+            return Response::error(400, "missing or unknown user");
+        };
+
+        let Ok((tweet_idx, ts)) = self.publisher.publish(user, &content) else {
+            return Response::error(503, "tweet storage full");
+        };
+        Response::json(201, format!("{{\"idx\":{tweet_idx},\"ts\":{ts}}}"))
+    }
+
+    /// `?viewer=` is needed to see tweets from protected accounts
+    fn get_tweet(&self, idx: &str, query: &str) -> Response {
+        let mut viewer = None;
+        for (k, v) in form_pairs(query) {
+            if k == "viewer" {
+                match self.parse_user(&v) {
+                    Some(user) => viewer = Some(user),
+                    None => return Response::error(400, "bad query parameter"),
+                }
+            }
+        }
+        let tweet = idx
+            .parse::<TweetIdx>()
+            .ok()
+            .and_then(|i| self.data.get_tweet_as(viewer, i));
+        let Some(tweet) = tweet else {
+            return Response::error(404, "no such tweet");
+        };
+        let mut body = format!("{{\"idx\":{idx},\"tweet\":");
+        write_json_tweet(&mut body, &tweet);
+        body.push('}');
+        Response::json(200, body)
+    }
+
+    fn get_timeline(&self, fetcher: &mut TimelineFetcher, id: &str, query: &str) -> Response {
+        let Some(user) = self.parse_user(id) else {
+            return Response::error(404, "no such user");
+        };
+        let mut max_len = DEFAULT_TIMELINE_LEN;
+        let mut after = START_TIME;
+        for (k, v) in form_pairs(query) {
+            match (&k[..], v.parse::<u32>()) {
+                ("max", Ok(max)) => max_len = (max as usize).min(MAX_TIMELINE_LEN),
+                ("after", Ok(ts)) => after = Timestamp::new(ts).unwrap_or(START_TIME),
+                ("max" | "after", Err(_)) => return Response::error(400, "bad query parameter"),
+                _ => {}
+            }
+        }
+
+        let timeline = fetcher.for_user(self.data, user, max_len, after);
+        let mut body = String::with_capacity(64 + timeline.tweets.len() * 128);
+        body.push_str("{\"tweets\":[");
+        for (i, tweet) in timeline.tweets.iter().enumerate() {
+            if i > 0 {
+                body.push(',');
+            }
+            write_json_tweet(&mut body, tweet);
+        }
+        body.push_str("]}");
+        Response::json(200, body)
+    }
+
+    fn serve_connection(&self, fetcher: &mut TimelineFetcher, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let req = match read_request(&mut reader) {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                // answered, but we can't tell where the next request would start
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let too_large = e.get_ref().is_some_and(|e| e.is::<HeadersTooLarge>());
+                    let status = if too_large { 431 } else { 400 };
+                    write_response(&mut writer, &Response::error(status, &e.to_string()), false)?;
+                    writer.flush()?;
+                    // closing with input unread would reset the connection,
+                    // possibly before the client read the response
+                    writer.get_ref().shutdown(Shutdown::Write)?;
+                    let limit = (MAX_HEADER_BYTES + MAX_BODY_BYTES) as u64;
+                    let _ = io::copy(&mut reader.take(limit), &mut io::sink());
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            let response = self.handle(fetcher, &req);
+            write_response(&mut writer, &response, req.keep_alive)?;
+            // only flush once pipelined requests are drained
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if !req.keep_alive {
+                break;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Serves forever from `workers` threads, each accepting connections
+    /// with its own fetcher. Connections beyond that wait in the backlog.
+    pub fn serve(&self, listener: TcpListener, workers: usize) -> io::Result<()> {
+        let listener = &listener;
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..workers.max(1))
+                .map(|_| s.spawn(move || self.work(listener)))
+                .collect();
+            for handle in handles {
+                handle.join().unwrap()?;
+            }
+            Ok(())
+        })
+    }
+
+    fn work(&self, listener: &TcpListener) -> io::Result<()> {
+        let mut fetcher = TimelineFetcher::default();
+        loop {
+            let (stream, _) = listener.accept()?;
+            if let Err(e) = self.serve_connection(&mut fetcher, stream) {
+                match e.kind() {
+                    io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut => {}
+                    _ => eprintln!("connection error: {e}"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+
+    fn request(raw: &str) -> Request {
+        read_request(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        let req = request("POST /tweets?x=1 HTTP/1.1\r\nContent-Length: 11\r\n\r\nuser=1&a=%2");
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/tweets");
+        assert_eq!(req.query, "x=1");
+        assert!(req.keep_alive);
+        let pairs: Vec<_> = form_pairs(std::str::from_utf8(&req.body).unwrap()).collect();
+        assert_eq!(pairs[1], ("a".to_string(), "%2".to_string()));
+        assert_eq!(percent_decode("hi+there%21%e2%9c%93"), "hi there!✓");
+
+        let huge = "POST /tweets HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n";
+        let err = read_request(&mut huge.as_bytes()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn routes() {
+        let mut builder = GraphBuilder::new(3, true);
+        builder.add_edge(0, 1);
+        builder.add_edge(0, 2);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let server = Server::new(&data, START_TIME);
+        let mut fetcher = TimelineFetcher::default();
+        let mut call = |raw: &str| server.handle(&mut fetcher, &request(raw));
+
+        let posted =
+            call("POST /tweets HTTP/1.1\r\nContent-Length: 28\r\n\r\nuser=1&content=hello+%22w%22");
+        assert_eq!(posted.status, 201);
+        assert_eq!(posted.body, r#"{"idx":0,"ts":2}"#);
+        call("POST /tweets HTTP/1.1\r\nContent-Length: 16\r\n\r\nuser=2&content=x");
+        assert_eq!(
+            call("POST /tweets HTTP/1.1\r\nContent-Length: 6\r\n\r\nuser=9").status,
+            400
+        );
+
+        let tweet = call("GET /tweets/0 HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            tweet.body,
+            r#"{"idx":0,"tweet":{"ts":2,"likes":0,"quotes":0,"retweets":0,"content":"hello \"w\""}}"#
+        );
+        assert_eq!(call("GET /tweets/5 HTTP/1.1\r\n\r\n").status, 404);
+        data.set_protected(2, true);
+        assert_eq!(call("GET /tweets/1 HTTP/1.1\r\n\r\n").status, 404);
+        assert_eq!(call("GET /tweets/1?viewer=0 HTTP/1.1\r\n\r\n").status, 200);
+        data.set_protected(2, false);
+
+        let timeline = call("GET /users/0/timeline?max=1 HTTP/1.1\r\n\r\n");
+        assert_eq!(timeline.status, 200);
+        assert!(timeline.body.contains("\"ts\":3"));
+        assert!(!timeline.body.contains("\"ts\":2"));
+        let timeline = call("GET /users/0/timeline?after=3 HTTP/1.1\r\n\r\n");
+        assert_eq!(timeline.body.matches("\"ts\"").count(), 1);
+        assert_eq!(
+            call("GET /users/0/timeline?max=x HTTP/1.1\r\n\r\n").status,
+            400
+        );
+        assert_eq!(call("DELETE /tweets/0 HTTP/1.1\r\n\r\n").status, 405);
+    }
+
+    #[test]
+    fn header_limit() {
+        let head = |len| format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(len));
+        let fits = head(MAX_HEADER_BYTES - head(0).len());
+        assert_eq!(request(&fits).path, "/");
+        let err = read_request(&mut head(MAX_HEADER_BYTES).as_bytes())
+            .err()
+            .unwrap();
+        assert!(err.get_ref().unwrap().is::<HeadersTooLarge>());
+
+        let baked = GraphBuilder::new(1, true).finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let server = Server::new(&data, START_TIME);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut fetcher = TimelineFetcher::default();
+                server.serve_connection(&mut fetcher, stream).unwrap();
+            });
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(head(MAX_HEADER_BYTES).as_bytes()).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 431 "));
+            assert!(response.contains("Connection: close"));
+        });
+    }
+}
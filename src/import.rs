@@ -0,0 +1,679 @@
+//! Importers for graph formats other than the SNAP twitter-2010 edge list,
+//! all baked into the same CSR layout that `LoadGraph` mmaps.
+//!
+//! Every importer produces directed edges `(a, b)` meaning "b follows a",
+//! which matches the SNAP twitter-2010 convention. Pass `transpose` for
+//! graphs where an arc points from the follower to the followee instead.
+
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bytemuck::cast_slice;
+use memmap2::Mmap;
+
+use crate::data::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// SNAP edge lists: one `a b` pair per line, tab or space separated, `#` comments
+    Snap,
+    /// METIS adjacency lists, 1-indexed, `%` comments
+    Metis,
+    /// Matrix Market coordinate matrices, 1-indexed
+    MatrixMarket,
+    /// WebGraph BVGraph, given the basename of the `.graph` and `.properties` files
+    BvGraph,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snap" => Ok(Format::Snap),
+            "metis" => Ok(Format::Metis),
+            "mtx" | "matrix-market" => Ok(Format::MatrixMarket),
+            "bvgraph" | "webgraph" => Ok(Format::BvGraph),
+            _ => Err(format!("unknown graph format {s:?}")),
+        }
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn parse_num<T: FromStr>(s: Option<&str>, what: &str) -> io::Result<T> {
+    s.and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid(format!("expected {what}, got {s:?}")))
+}
+
+/// A graph in the baked layout, owned in memory rather than mmapped
+pub struct BakedGraph {
+    pub users: Vec<User>,
+    pub follows: Vec<UserIdx>,
+}
+
+impl BakedGraph {
+    pub fn graph(&self) -> Graph<'_> {
+        Graph {
+            users: &self.users[..],
+            follows: &self.follows[..],
+        }
+    }
+
+    /// Writes `users.bin` and `follows.bin` into `dir` for `LoadGraph`
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let mut users_f = File::create(dir.join("users.bin"))?;
+        users_f.write_all(cast_slice(&self.users[..]))?;
+        let mut follows_f = File::create(dir.join("follows.bin"))?;
+        follows_f.write_all(cast_slice(&self.follows[..]))?;
+        Ok(())
+    }
+}
+
+/// Accumulates edges in any order and lays them out as CSR at the end, with
+/// each follow list sorted. Users are numbered up to the largest id seen or the size hint, whichever is bigger.
+pub struct GraphBuilder {
+    lists: Vec<Vec<UserIdx>>,
+    total_follows: usize,
+    transpose: bool,
+}
+
+impl GraphBuilder {
+    pub fn new(num_users: usize, transpose: bool) -> Self {
+        Self {
+            lists: (0..num_users).map(|_| vec![]).collect(),
+            total_follows: 0,
+            transpose,
+        }
+    }
+
+    /// Adds an edge `(a, b)` meaning "b follows a", or the reverse if transposed
+    pub fn add_edge(&mut self, a: UserIdx, b: UserIdx) {
+        let (follower, followee) = if self.transpose { (a, b) } else { (b, a) };
+        let needed = follower.max(followee) as usize + 1;
+        if self.lists.len() < needed {
+            self.lists.resize_with(needed, Vec::new);
+        }
+        self.lists[follower as usize].push(followee);
+        self.total_follows += 1;
+    }
+
+    pub fn finish(mut self) -> BakedGraph {
+        let mut users: Vec<User> = Vec::with_capacity(self.lists.len());
+        let mut follows: Vec<UserIdx> = Vec::with_capacity(self.total_follows);
+        for ls in &mut self.lists {
+            ls.sort_unstable();
+            users.push(User {
+                follows_idx: follows.len(),
+                num_follows: ls.len() as u32,
+                num_followers: 0,
+            });
+            follows.extend_from_slice(ls);
+        }
+        for f in &follows {
+            users[*f as usize].num_followers += 1;
+        }
+        BakedGraph { users, follows }
+    }
+}
+
+/// Imports a text format from `input`. Use `import_bvgraph` for BVGraph files.
+pub fn import_text(format: Format, input: impl BufRead, transpose: bool) -> io::Result<BakedGraph> {
+    match format {
+        Format::Snap => import_snap(input, transpose),
+        Format::Metis => import_metis(input, transpose),
+        Format::MatrixMarket => import_matrix_market(input, transpose),
+        Format::BvGraph => Err(invalid("BVGraph is a binary format, pass a basename")),
+    }
+}
+
+pub fn import_snap(input: impl BufRead, transpose: bool) -> io::Result<BakedGraph> {
+    let mut builder = GraphBuilder::new(0, transpose);
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut split = line.split_whitespace();
+        let a = parse_num(split.next(), "source id")?;
+        let b = parse_num(split.next(), "target id")?;
+        builder.add_edge(a, b);
+    }
+    Ok(builder.finish())
+}
+
+/// METIS graphs are usually undirected and list each edge from both ends,
+/// so they import as mutual follows
+pub fn import_metis(input: impl BufRead, transpose: bool) -> io::Result<BakedGraph> {
+    // comment lines don't count as vertices but empty lines do
+    let mut lines = input
+        .lines()
+        .filter(|l| !matches!(l, Ok(l) if l.trim_start().starts_with('%')));
+
+    let header = lines
+        .next()
+        .ok_or_else(|| invalid("missing METIS header"))??;
+    let mut header = header.split_whitespace();
+    let num_vertices: usize = parse_num(header.next(), "vertex count")?;
+    let _num_edges: usize = parse_num(header.next(), "edge count")?;
+    // fmt is up to three flags "abc": vertex sizes, vertex weights, edge weights
+    let fmt = header.next().unwrap_or("0");
+    let flag = |pos: usize| fmt.len() > pos && fmt.as_bytes()[fmt.len() - 1 - pos] == b'1';
+    let (edge_weights, vertex_weights, vertex_sizes) = (flag(0), flag(1), flag(2));
+    let ncon: usize = match header.next() {
+        Some(n) => parse_num(Some(n), "constraint count")?,
+        None => 1,
+    };
+    let skip = vertex_sizes as usize + if vertex_weights { ncon } else { 0 };
+
+    let mut builder = GraphBuilder::new(num_vertices, transpose);
+    for v in 0..num_vertices {
+        let line = lines.next().unwrap_or_else(|| Ok(String::new()))?;
+        let mut fields = line.split_whitespace().skip(skip);
+        while let Some(n) = fields.next() {
+            let n: UserIdx = parse_num(Some(n), "neighbour")?;
+            if n == 0 || n as usize > num_vertices {
+                return Err(invalid(format!(
+                    "neighbour {n} of vertex {} out of range",
+                    v + 1
+                )));
+            }
+            builder.add_edge(v as UserIdx, n - 1);
+            if edge_weights {
+                fields.next();
+            }
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// Entry `(i, j)` imports as the edge `(i, j)`. Symmetric matrices only store
+/// one triangle, so the mirrored edge is added too.
+pub fn import_matrix_market(input: impl BufRead, transpose: bool) -> io::Result<BakedGraph> {
+    let mut lines = input.lines();
+
+    let banner = lines
+        .next()
+        .ok_or_else(|| invalid("missing Matrix Market banner"))??;
+    let banner = banner.to_ascii_lowercase();
+    let banner: Vec<&str> = banner.split_whitespace().collect();
+    if banner.len() < 5 || banner[0] != "%%matrixmarket" || banner[1] != "matrix" {
+        return Err(invalid("not a Matrix Market matrix"));
+    }
+    if banner[2] != "coordinate" {
+        return Err(invalid("only coordinate Matrix Market files are supported"));
+    }
+    let values = match banner[3] {
+        "pattern" => 0,
+        "real" | "integer" => 1,
+        "complex" => 2,
+        field => return Err(invalid(format!("unknown Matrix Market field {field}"))),
+    };
+    let symmetric = banner[4] != "general";
+
+    let mut lines = lines
+        .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty() || l.trim_start().starts_with('%')));
+    let size = lines
+        .next()
+        .ok_or_else(|| invalid("missing Matrix Market size line"))??;
+    let mut size = size.split_whitespace();
+    let rows: usize = parse_num(size.next(), "row count")?;
+    let cols: usize = parse_num(size.next(), "column count")?;
+    let entries: usize = parse_num(size.next(), "entry count")?;
+
+    let mut builder = GraphBuilder::new(rows.max(cols), transpose);
+    for _ in 0..entries {
+        let line = lines
+            .next()
+            .ok_or_else(|| invalid("truncated Matrix Market file"))??;
+        let mut fields = line.split_whitespace();
+        let i: UserIdx = parse_num(fields.next(), "row index")?;
+        let j: UserIdx = parse_num(fields.next(), "column index")?;
+        if i == 0 || j == 0 || fields.count() != values {
+            return Err(invalid(format!("bad Matrix Market entry {line:?}")));
+        }
+        builder.add_edge(i - 1, j - 1);
+        if symmetric && i != j {
+            builder.add_edge(j - 1, i - 1);
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// Reads `{basename}.properties` and decodes `{basename}.graph` sequentially,
+/// so the `.offsets` file isn't needed. Arc `x -> y` imports as the edge `(x, y)`.
+pub fn import_bvgraph(basename: &Path, transpose: bool) -> io::Result<BakedGraph> {
+    let props = std::fs::read_to_string(basename.with_extension("properties"))?;
+    let props = BvProperties::parse(&props)?;
+    let graph = unsafe { Mmap::map(&File::open(basename.with_extension("graph"))?)? };
+
+    let mut builder = GraphBuilder::new(props.nodes, transpose);
+    let mut decoder = BvDecoder::new(&props, &graph[..]);
+    for x in 0..props.nodes {
+        for &y in decoder.next_successors(x)? {
+            builder.add_edge(x as UserIdx, y);
+        }
+    }
+    Ok(builder.finish())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Code {
+    Unary,
+    Gamma,
+    Delta,
+    Zeta,
+}
+
+struct BvProperties {
+    nodes: usize,
+    window_size: usize,
+    min_interval_len: u32,
+    zeta_k: u32,
+    outdegrees: Code,
+    references: Code,
+    block_count: Code,
+    blocks: Code,
+    residuals: Code,
+}
+
+impl BvProperties {
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut props = BvProperties {
+            nodes: 0,
+            window_size: 7,
+            min_interval_len: 4,
+            zeta_k: 3,
+            outdegrees: Code::Gamma,
+            references: Code::Unary,
+            block_count: Code::Gamma,
+            blocks: Code::Gamma,
+            residuals: Code::Zeta,
+        };
+        let mut saw_nodes = false;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once(['=', ':']) else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "nodes" => {
+                    props.nodes = parse_num(Some(value), "node count")?;
+                    saw_nodes = true;
+                }
+                "windowsize" => props.window_size = parse_num(Some(value), "window size")?,
+                "minintervallength" => {
+                    props.min_interval_len = parse_num(Some(value), "min interval length")?
+                }
+                "zetak" => props.zeta_k = parse_num(Some(value), "zeta k")?,
+                "compressionflags" => props.parse_flags(value)?,
+                _ => {}
+            }
+        }
+        if !saw_nodes {
+            return Err(invalid("BVGraph properties missing node count"));
+        }
+        Ok(props)
+    }
+
+    fn parse_flags(&mut self, flags: &str) -> io::Result<()> {
+        for flag in flags.split('|').map(str::trim).filter(|f| !f.is_empty()) {
+            let (field, code) = flag
+                .rsplit_once('_')
+                .ok_or_else(|| invalid(format!("bad compression flag {flag}")))?;
+            let code = match code {
+                "UNARY" => Code::Unary,
+                "GAMMA" => Code::Gamma,
+                "DELTA" => Code::Delta,
+                "ZETA" => Code::Zeta,
+                _ => return Err(invalid(format!("unsupported BVGraph coding {flag}"))),
+            };
+            match field {
+                "OUTDEGREES" => self.outdegrees = code,
+                "REFERENCES" => self.references = code,
+                "BLOCK_COUNT" => self.block_count = code,
+                "BLOCKS" => self.blocks = code,
+                "RESIDUALS" => self.residuals = code,
+                // only needed for random access
+                "OFFSETS" => {}
+                _ => return Err(invalid(format!("unknown compression flag {flag}"))),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// MSB-first bit reader matching WebGraph's `InputBitStream`
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self) -> io::Result<u64> {
+        let byte = self
+            .bytes
+            .get(self.pos / 8)
+            .ok_or_else(|| invalid("BVGraph bitstream ended early"))?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit as u64)
+    }
+
+    fn bits(&mut self, len: u32) -> io::Result<u64> {
+        let mut x = 0;
+        for _ in 0..len {
+            x = (x << 1) | self.bit()?;
+        }
+        Ok(x)
+    }
+
+    fn unary(&mut self) -> io::Result<u64> {
+        let mut x = 0;
+        while self.bit()? == 0 {
+            x += 1;
+        }
+        Ok(x)
+    }
+
+    fn gamma(&mut self) -> io::Result<u64> {
+        let msb = self.unary()? as u32;
+        Ok(((1 << msb) | self.bits(msb)?) - 1)
+    }
+
+    fn delta(&mut self) -> io::Result<u64> {
+        let msb = self.gamma()? as u32;
+        Ok(((1 << msb) | self.bits(msb)?) - 1)
+    }
+
+    fn zeta(&mut self, k: u32) -> io::Result<u64> {
+        let h = self.unary()? as u32;
+        let left = 1 << (h * k);
+        let m = self.bits(h * k + k - 1)?;
+        if m < left {
+            Ok(m + left - 1)
+        } else {
+            Ok((m << 1) + self.bit()? - 1)
+        }
+    }
+
+    fn read(&mut self, code: Code, zeta_k: u32) -> io::Result<u64> {
+        match code {
+            Code::Unary => self.unary(),
+            Code::Gamma => self.gamma(),
+            Code::Delta => self.delta(),
+            Code::Zeta => self.zeta(zeta_k),
+        }
+    }
+}
+
+/// WebGraph stores signed gaps zig-zag encoded
+fn nat2int(x: u64) -> i64 {
+    if x & 1 == 0 {
+        (x >> 1) as i64
+    } else {
+        -(((x + 1) >> 1) as i64)
+    }
+}
+
+struct BvDecoder<'a> {
+    props: &'a BvProperties,
+    bits: BitReader<'a>,
+    /// successor lists of the last `window_size + 1` nodes, indexed by node modulo length
+    window: Vec<Vec<UserIdx>>,
+}
+
+impl<'a> BvDecoder<'a> {
+    fn new(props: &'a BvProperties, bytes: &'a [u8]) -> Self {
+        Self {
+            props,
+            bits: BitReader { bytes, pos: 0 },
+            window: vec![vec![]; props.window_size + 1],
+        }
+    }
+
+    fn read(&mut self, code: Code) -> io::Result<u64> {
+        self.bits.read(code, self.props.zeta_k)
+    }
+
+    /// Must be called for every node in order since references point backwards
+    fn next_successors(&mut self, x: usize) -> io::Result<&[UserIdx]> {
+        let props = self.props;
+        let slot = x % self.window.len();
+        let mut succ = std::mem::take(&mut self.window[slot]);
+        succ.clear();
+
+        let degree = self.read(props.outdegrees)? as usize;
+        if degree > 0 {
+            let reference = if props.window_size > 0 {
+                self.read(props.references)? as usize
+            } else {
+                0
+            };
+            if reference > x || reference > props.window_size {
+                return Err(invalid(format!("bad reference {reference} at node {x}")));
+            }
+
+            // copy blocks alternate between copying and skipping the referenced list,
+            // with an implicit trailing copy block when the count is even
+            if reference > 0 {
+                let refd = &self.window[(x - reference) % self.window.len()];
+                let block_count = self.bits.read(props.block_count, props.zeta_k)? as usize;
+                let mut i = 0;
+                for b in 0..block_count {
+                    let len =
+                        self.bits.read(props.blocks, props.zeta_k)? as usize + (b > 0) as usize;
+                    let end = (i + len).min(refd.len());
+                    if b.is_multiple_of(2) {
+                        succ.extend_from_slice(&refd[i..end]);
+                    }
+                    i = end;
+                }
+                if block_count.is_multiple_of(2) {
+                    succ.extend_from_slice(&refd[i..]);
+                }
+            }
+
+            let mut extra = degree
+                .checked_sub(succ.len())
+                .ok_or_else(|| invalid(format!("copied too many successors at node {x}")))?;
+
+            if extra > 0 && props.min_interval_len > 0 {
+                let intervals = self.bits.gamma()?;
+                let mut prev = 0i64;
+                for i in 0..intervals {
+                    let left = if i == 0 {
+                        nat2int(self.bits.gamma()?) + x as i64
+                    } else {
+                        self.bits.gamma()? as i64 + prev + 1
+                    };
+                    let len = self.bits.gamma()? as usize + props.min_interval_len as usize;
+                    if left < 0 || len > extra {
+                        return Err(invalid(format!("bad interval at node {x}")));
+                    }
+                    succ.extend((left..left + len as i64).map(|y| y as UserIdx));
+                    // exclusive, the next gap counts from one past it
+                    prev = left + len as i64;
+                    extra -= len;
+                }
+            }
+
+            let mut prev = 0i64;
+            for i in 0..extra {
+                let y = if i == 0 {
+                    nat2int(self.read(props.residuals)?) + x as i64
+                } else {
+                    self.read(props.residuals)? as i64 + prev + 1
+                };
+                if y < 0 {
+                    return Err(invalid(format!("negative residual at node {x}")));
+                }
+                succ.push(y as UserIdx);
+                prev = y;
+            }
+            succ.sort_unstable();
+        }
+
+        self.window[slot] = succ;
+        Ok(&self.window[slot])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn follows(baked: &BakedGraph) -> Vec<Vec<UserIdx>> {
+        let graph = baked.graph();
+        graph
+            .users
+            .iter()
+            .map(|u| graph.user_follows(u).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn snap() {
+        let input = "# Directed graph\n# FromNodeId\tToNodeId\n0\t1\n0 2\n\n2\t1\n";
+        let baked = import_snap(input.as_bytes(), false).unwrap();
+        assert_eq!(follows(&baked), vec![vec![], vec![0, 2], vec![0]]);
+        assert_eq!(baked.users[0].num_followers, 2);
+
+        let baked = import_snap(input.as_bytes(), true).unwrap();
+        assert_eq!(follows(&baked), vec![vec![1, 2], vec![], vec![1]]);
+    }
+
+    #[test]
+    fn metis() {
+        let input = "% comment\n3 2 011 1\n5 2 7\n\n9 1 7 3 1\n";
+        let baked = import_metis(input.as_bytes(), true).unwrap();
+        assert_eq!(follows(&baked), vec![vec![1], vec![], vec![0, 2]]);
+    }
+
+    #[test]
+    fn matrix_market() {
+        let input = "%%MatrixMarket matrix coordinate pattern symmetric\n% c\n3 3 2\n1 2\n3 3\n";
+        let baked = import_matrix_market(input.as_bytes(), true).unwrap();
+        assert_eq!(follows(&baked), vec![vec![1], vec![0], vec![2]]);
+
+        let input = "%%MatrixMarket matrix coordinate real general\n2 2 1\n2 1 0.5\n";
+        let baked = import_matrix_market(input.as_bytes(), true).unwrap();
+        assert_eq!(follows(&baked), vec![vec![], vec![0]]);
+    }
+
+    struct BitWriter {
+        bits: Vec<bool>,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, x: u64, len: u32) {
+            for i in (0..len).rev() {
+                self.bits.push((x >> i) & 1 == 1);
+            }
+        }
+
+        fn unary(&mut self, x: u64) {
+            self.bits(1, x as u32 + 1);
+        }
+
+        fn gamma(&mut self, x: u64) {
+            let msb = 63 - (x + 1).leading_zeros();
+            self.unary(msb as u64);
+            self.bits(x + 1, msb);
+        }
+
+        fn zeta3(&mut self, x: u64) {
+            let x = x + 1;
+            let msb = 63 - x.leading_zeros();
+            let h = msb / 3;
+            self.unary(h as u64);
+            let left = 1u64 << (h * 3);
+            let m = x - left;
+            if m < left {
+                self.bits(m, h * 3 + 2);
+            } else {
+                self.bits(m + left, h * 3 + 3);
+            }
+        }
+
+        fn bytes(&self) -> Vec<u8> {
+            self.bits
+                .chunks(8)
+                .map(|c| {
+                    c.iter()
+                        .enumerate()
+                        .map(|(i, b)| (*b as u8) << (7 - i))
+                        .sum()
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn bvgraph() {
+        let mut w = BitWriter { bits: vec![] };
+        // node 0 -> {1, 3}: no reference, no intervals, residuals
+        w.gamma(2);
+        w.unary(0);
+        w.gamma(0);
+        w.zeta3(2); // int2nat(1 - 0)
+        w.zeta3(1); // 3 - 1 - 1
+                    // node 1 -> {1, 2, 3}: copies all of node 0, plus one residual
+        w.gamma(3);
+        w.unary(1);
+        w.gamma(0);
+        w.gamma(0);
+        w.zeta3(2); // int2nat(2 - 1)
+                    // node 2 -> {0, 1, 2}: a single interval
+        w.gamma(3);
+        w.unary(0);
+        w.gamma(1);
+        w.gamma(3); // int2nat(0 - 2)
+        w.gamma(1); // 3 - min interval length
+                    // node 3 -> {0, 1, 4, 5}: two intervals
+        w.gamma(4);
+        w.unary(0);
+        w.gamma(2);
+        w.gamma(5); // int2nat(0 - 3)
+        w.gamma(0);
+        w.gamma(1); // 4 - (0 + 2) - 1
+        w.gamma(0);
+        // nodes 4 and 5 -> {}
+        w.gamma(0);
+        w.gamma(0);
+
+        let dir = std::env::temp_dir().join(format!("bvgraph-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let basename = dir.join("tiny");
+        std::fs::write(basename.with_extension("graph"), w.bytes()).unwrap();
+        std::fs::write(
+            basename.with_extension("properties"),
+            "#BVGraph properties\ngraphclass=it.unimi.dsi.webgraph.BVGraph\nnodes=6\narcs=12\n\
+             windowsize=7\nminintervallength=2\nzetak=3\ncompressionflags=\n",
+        )
+        .unwrap();
+
+        let baked = import_bvgraph(&basename, true).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            follows(&baked),
+            vec![
+                vec![1, 3],
+                vec![1, 2, 3],
+                vec![0, 1, 2],
+                vec![0, 1, 4, 5],
+                vec![],
+                vec![]
+            ]
+        );
+    }
+}
@@ -1,7 +1,28 @@
+pub mod compress;
 pub mod data;
+pub mod epoch;
 pub mod generate;
+pub mod histogram;
+pub mod http;
+pub mod import;
+pub mod lists;
+pub mod notify;
+pub mod numa;
+pub mod pages;
+pub mod perf;
 pub mod pool;
+pub mod rank;
+pub mod relations;
+pub mod reorder;
+pub mod replicate;
+pub mod rpc;
+pub mod search;
+pub mod shard;
+pub mod shm;
 pub mod timeline;
+pub mod trace;
+pub mod trends;
+pub mod visibility;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -0,0 +1,130 @@
+//! Lists: curated sets of accounts whose timeline is fetched like a home
+//! timeline, see `TimelineFetcher::for_list`.
+
+use std::sync::{RwLock, RwLockReadGuard};
+
+use crate::data::UserIdx;
+
+pub type ListIdx = u32;
+
+pub struct List {
+    pub owner: UserIdx,
+    pub name: String,
+    /// sorted
+    pub members: Vec<UserIdx>,
+}
+
+pub struct Lists {
+    num_users: usize,
+    lists: RwLock<Vec<List>>,
+}
+
+impl Lists {
+    pub fn new(num_users: usize) -> Self {
+        Self {
+            num_users,
+            lists: RwLock::new(vec![]),
+        }
+    }
+
+    /// None if there's no such owner
+    pub fn create(&self, owner: UserIdx, name: &str) -> Option<ListIdx> {
+        if owner as usize >= self.num_users {
+            return None;
+        }
+        let mut lists = self.lists.write().unwrap();
+        lists.push(List {
+            owner,
+            name: name.to_string(),
+            members: vec![],
+        });
+        Some((lists.len() - 1) as ListIdx)
+    }
+
+    /// Returns false if there's no such list or user, or they're already a member
+    pub fn add_member(&self, list_idx: ListIdx, user: UserIdx) -> bool {
+        if user as usize >= self.num_users {
+            return false;
+        }
+        let mut lists = self.lists.write().unwrap();
+        let Some(list) = lists.get_mut(list_idx as usize) else {
+            return false;
+        };
+        match list.members.binary_search(&user) {
+            Ok(_) => false,
+            Err(i) => {
+                list.members.insert(i, user);
+                true
+            }
+        }
+    }
+
+    /// Returns false if the user wasn't a member
+    pub fn remove_member(&self, list_idx: ListIdx, user: UserIdx) -> bool {
+        let mut lists = self.lists.write().unwrap();
+        let Some(list) = lists.get_mut(list_idx as usize) else {
+            return false;
+        };
+        match list.members.binary_search(&user) {
+            Ok(i) => {
+                list.members.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Blocks membership changes until dropped, so hold it only for a fetch
+    pub fn read(&self) -> RwLockReadGuard<'_, Vec<List>> {
+        self.lists.read().unwrap()
+    }
+
+    /// Lists owned by `owner`
+    pub fn owned_by(&self, owner: UserIdx) -> Vec<ListIdx> {
+        let lists = self.read();
+        (0..lists.len() as ListIdx)
+            .filter(|i| lists[*i as usize].owner == owner)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::*;
+    use crate::import::GraphBuilder;
+    use crate::timeline::TimelineFetcher;
+
+    #[test]
+    fn list_timelines() {
+        // nobody follows anyone, lists don't need the graph
+        let baked = GraphBuilder::new(4, true).finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        for ts in 1..=12 {
+            data.add_tweet(Tweet::dummy(Timestamp::new(ts).unwrap()), ts % 4)
+                .unwrap();
+        }
+        assert!(data.lists.create(4, "nobody").is_none());
+        let list = data.lists.create(0, "friends").unwrap();
+        assert!(data.lists.add_member(list, 3));
+        assert!(data.lists.add_member(list, 1));
+        assert!(!data.lists.add_member(list, 1));
+        assert!(!data.lists.add_member(list, 4));
+        assert_eq!(data.lists.read()[list as usize].members, [1, 3]);
+        assert_eq!(data.lists.owned_by(0), [list]);
+        assert!(data.lists.owned_by(1).is_empty());
+
+        let mut fetcher = TimelineFetcher::default();
+        let timestamps = |fetcher: &mut TimelineFetcher, max_len, after| -> Vec<u32> {
+            let after = Timestamp::new(after).unwrap();
+            let timeline = fetcher.for_list(&data, 0, list, max_len, after);
+            timeline.tweets.iter().map(|t| t.ts.get()).collect()
+        };
+        assert_eq!(timestamps(&mut fetcher, 3, 1), [11, 9, 7]);
+        assert_eq!(timestamps(&mut fetcher, 10, 6), [11, 9, 7]);
+        assert!(data.lists.remove_member(list, 3));
+        assert!(!data.lists.remove_member(list, 3));
+        assert_eq!(timestamps(&mut fetcher, 10, 1), [9, 5, 1]);
+        let timeline = fetcher.for_list(&data, 0, list + 1, 10, START_TIME);
+        assert!(timeline.tweets.is_empty());
+    }
+}
@@ -0,0 +1,416 @@
+//! Notifications: mentions, likes and retweets.
+//!
+//! Each user has a notification chain built the same way as their feed in
+//! `Datastore::feeds`, so appending is one pool push and one atomic store.
+//! Likes and retweets of the same tweet are stored one event at a time and
+//! grouped when the page is read, so a burst of likes shows up as one entry.
+//!
+//! Users have no handles, `@1234` mentions the user with index 1234.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::data::*;
+use crate::pool::SharedPool;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum NotificationKind {
+    Mention,
+    Like,
+    Retweet,
+}
+
+pub struct ChainedNotification {
+    /// `NextLink::tweet_idx` is the index in `Notifications::events` narrowed
+    /// like a `TweetIdx`, the link is stale if `ts` doesn't match
+    pub prev: FeedChain,
+    pub ts: Timestamp,
+    pub tweet_idx: TweetIdx,
+    /// Who was notified
+    pub user: UserIdx,
+    /// Who mentioned, liked or retweeted
+    pub actor: UserIdx,
+    pub kind: NotificationKind,
+}
+
+/// Mentioned user indices, in order with duplicates
+pub fn mentions(text: &str) -> impl Iterator<Item = UserIdx> + '_ {
+    text.split('@').skip(1).filter_map(|rest| {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        rest[..end].parse().ok()
+    })
+}
+
+/// Users share a lock when their indices are equal modulo this
+const LOCK_STRIPES: usize = 64;
+
+pub struct Notifications {
+    pub events: SharedPool<ChainedNotification>,
+    pub feeds: Vec<AtomicChain>,
+    /// Unlike tweets, likes come from every thread at once. Striped by user,
+    /// appends to one chain must not interleave.
+    locks: Vec<Mutex<()>>,
+}
+
+impl Notifications {
+    pub fn new(num_users: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            events: SharedPool::new()?,
+            feeds: (0..num_users).map(|_| AtomicChain::none()).collect(),
+            locks: (0..LOCK_STRIPES).map(|_| Mutex::default()).collect(),
+        })
+    }
+
+    pub fn push(
+        &self,
+        user: UserIdx,
+        kind: NotificationKind,
+        actor: UserIdx,
+        tweet_idx: TweetIdx,
+        ts: Timestamp,
+    ) -> std::io::Result<()> {
+        let _guard = self.locks[user as usize % LOCK_STRIPES].lock().unwrap();
+        let feed = &self.feeds[user as usize];
+        let event = ChainedNotification {
+            prev: feed.fetch(),
+            ts,
+            tweet_idx,
+            user,
+            actor,
+            kind,
+        };
+        // fails once the pool is full, until old ones expire
+        let idx = self.events.push(event)?;
+        feed.set(NextLink {
+            ts,
+            tweet_idx: idx as u32,
+        });
+        Ok(())
+    }
+
+    /// Notifies everyone `text` mentions once, except the author. Stops at the
+    /// first that doesn't fit.
+    pub fn record_mentions(
+        &self,
+        author: UserIdx,
+        tweet_idx: TweetIdx,
+        ts: Timestamp,
+        text: &str,
+    ) -> std::io::Result<()> {
+        let mut mentioned: Vec<UserIdx> = mentions(text)
+            .filter(|u| *u != author && (*u as usize) < self.feeds.len())
+            .collect();
+        mentioned.sort_unstable();
+        mentioned.dedup();
+        for user in mentioned {
+            self.push(user, NotificationKind::Mention, author, tweet_idx, ts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Actors listed per group, beyond this only `count` grows
+pub const MAX_ACTORS: usize = 3;
+
+/// Events looked at per page, so muted actors or a flood of likes on one
+/// tweet can't make a page walk the whole history
+pub const MAX_SCAN: usize = 4096;
+
+pub struct NotificationGroup {
+    pub kind: NotificationKind,
+    pub tweet_idx: TweetIdx,
+    /// The mentioning tweet, or the user's own tweet that was liked or retweeted
+    pub tweet: Tweet,
+    /// Newest event in the group
+    pub ts: Timestamp,
+    pub count: u32,
+    /// Newest first
+    pub actors: Vec<UserIdx>,
+}
+
+pub struct NotificationPage<'a> {
+    pub groups: &'a [NotificationGroup],
+    /// Pass as `before` to get the next page, `None` if this was the last
+    pub next: FeedChain,
+}
+
+#[derive(Default)]
+pub struct NotificationFetcher {
+    groups: Vec<NotificationGroup>,
+    /// (kind, tweet) -> index in `groups`
+    grouped: HashMap<(NotificationKind, TweetIdx), usize>,
+}
+
+impl NotificationFetcher {
+    /// Newest first, up to `max_len` groups, starting at `before` from the
+    /// previous page. Likes and retweets only group within a page, so the same
+    /// tweet can show up again on the next one. A page can come back short
+    /// with a `next` cursor once it has looked at `MAX_SCAN` events.
+    pub fn for_user<'a>(
+        &'a mut self,
+        data: &Datastore,
+        user_idx: UserIdx,
+        max_len: usize,
+        after: Timestamp,
+        before: FeedChain,
+    ) -> NotificationPage<'a> {
+        self.groups.clear();
+        self.grouped.clear();
+        let notifications = &data.notifications;
+        let mut relations = data.relations.read();
+        let hidden = relations.for_viewer(user_idx);
+        let mut visibility = data.visibility.read();
+        let _pinned = data.pin();
+        // pinned first, so nothing from where the view starts is released
+        let events = unsafe { notifications.events.view() };
+        let follow = |link: NextLink| {
+            events
+                .get_narrow(link.tweet_idx)
+                .filter(|event| event.ts == link.ts)
+        };
+
+        let mut link = match before {
+            None => notifications.feeds[user_idx as usize].fetch(),
+            // a cursor into someone else's notifications would leak them
+            Some(cursor) => follow(cursor)
+                .filter(|event| event.user == user_idx)
+                .and(Some(cursor)),
+        };
+        let mut scanned = 0;
+        while let Some(l) = link.filter(|l| l.ts >= after) {
+            if scanned >= MAX_SCAN {
+                break;
+            }
+            scanned += 1;
+            // expired
+            let Some(event) = follow(l) else {
+                link = None;
+                break;
+            };
+            let is_new = match event.kind {
+                NotificationKind::Mention => true,
+                _ => !self.grouped.contains_key(&(event.kind, event.tweet_idx)),
+            };
+            // stays the cursor, it starts the next page
+            if is_new && self.groups.len() >= max_len {
+                break;
+            }
+            link = event.prev;
+            if hidden.is_some_and(|h| h.hides(event.actor))
+                || !visibility.can_see(user_idx, event.actor)
+            {
+                continue;
+            }
+            let key = (event.kind, event.tweet_idx);
+            let existing = match event.kind {
+                NotificationKind::Mention => None,
+                _ => self.grouped.get(&key).copied(),
+            };
+            if let Some(i) = existing {
+                let group = &mut self.groups[i];
+                group.count += 1;
+                if group.actors.len() < MAX_ACTORS && !group.actors.contains(&event.actor) {
+                    group.actors.push(event.actor);
+                }
+                continue;
+            }
+            let Some(tweet) = data.get_tweet(event.tweet_idx) else {
+                continue;
+            };
+            self.grouped.insert(key, self.groups.len());
+            self.groups.push(NotificationGroup {
+                kind: event.kind,
+                tweet_idx: event.tweet_idx,
+                tweet,
+                ts: event.ts,
+                count: 1,
+                actors: vec![event.actor],
+            });
+        }
+        NotificationPage {
+            groups: &self.groups[..],
+            next: link.filter(|l| l.ts >= after),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+
+    #[test]
+    fn parsing() {
+        let found: Vec<UserIdx> = mentions("hi @3 and @12, not @ or @x but @3").collect();
+        assert_eq!(found, [3, 12, 3]);
+    }
+
+    #[test]
+    fn notification_feed() {
+        // nobody follows anyone, mentions still arrive
+        let baked = GraphBuilder::new(5, true).finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        let own = data.add_tweet(Tweet::new(ts(1), "my tweet"), 0).unwrap();
+        data.add_tweet(Tweet::new(ts(2), "hey @0 and @0 and @9"), 1)
+            .unwrap();
+        assert!(data.like_tweet(2, own, ts(3)).unwrap());
+        assert!(data.like_tweet(3, own, ts(4)).unwrap());
+        assert!(data.retweet(4, own, ts(5)).unwrap());
+        assert!(data.like_tweet(4, own, ts(6)).unwrap());
+        // liking again neither counts nor notifies
+        assert!(!data.like_tweet(3, own, ts(6)).unwrap());
+        let tweet = data.get_tweet(own).unwrap();
+        assert_eq!((tweet.likes, tweet.retweets), (3, 1));
+        let mention = data.add_tweet(Tweet::new(ts(7), "@0 again"), 2).unwrap();
+        data.add_tweet(Tweet::new(ts(8), "talking to myself @1"), 1)
+            .unwrap();
+
+        let mut fetcher = NotificationFetcher::default();
+        let page = |fetcher: &mut NotificationFetcher, max_len, before| -> (Vec<String>, _) {
+            let page = fetcher.for_user(&data, 0, max_len, START_TIME, before);
+            let groups = page
+                .groups
+                .iter()
+                .map(|g| format!("{:?} {} {} {:?}", g.kind, g.ts, g.count, g.actors))
+                .collect();
+            (groups, page.next)
+        };
+        let summary = |fetcher: &mut NotificationFetcher, max_len| page(fetcher, max_len, None).0;
+        assert_eq!(
+            summary(&mut fetcher, 10),
+            [
+                "Mention 7 1 [2]",
+                "Like 6 3 [4, 3, 2]",
+                "Retweet 5 1 [4]",
+                "Mention 2 1 [1]"
+            ]
+        );
+        // a full page stops at the next new group, the older likes group again
+        let (first, next) = page(&mut fetcher, 2, None);
+        assert_eq!(first, ["Mention 7 1 [2]", "Like 6 1 [4]"]);
+        let (second, next) = page(&mut fetcher, 2, next);
+        assert_eq!(second, ["Retweet 5 1 [4]", "Like 4 2 [3, 2]"]);
+        assert_eq!(
+            page(&mut fetcher, 2, next),
+            (vec!["Mention 2 1 [1]".into()], None)
+        );
+        // self mentions don't notify, and cursors only work for their own user
+        let own_first = NextLink {
+            ts: ts(7),
+            tweet_idx: 5,
+        };
+        assert_eq!(page(&mut fetcher, 10, Some(own_first)).0.len(), 4);
+        let page_1 = fetcher.for_user(&data, 1, 10, START_TIME, Some(own_first));
+        assert!(page_1.groups.is_empty());
+        assert!(fetcher
+            .for_user(&data, 1, 10, START_TIME, None)
+            .groups
+            .is_empty());
+
+        data.relations.mute(0, 4);
+        assert!(data.delete_tweet(mention));
+        assert_eq!(
+            summary(&mut fetcher, 10),
+            ["Like 4 2 [3, 2]", "Mention 2 1 [1]"]
+        );
+    }
+
+    #[test]
+    fn concurrent_pushes_keep_chains() {
+        // more users than stripes, so some share a lock
+        let notifications = Notifications::new(2 * LOCK_STRIPES + 1).unwrap();
+        let n_users = notifications.feeds.len() as UserIdx;
+        std::thread::scope(|s| {
+            for actor in 0..8 {
+                let notifications = &notifications;
+                s.spawn(move || {
+                    for i in 0..4 * n_users {
+                        let ts = Timestamp::new(1 + i).unwrap();
+                        let user = (i * 7 + actor) % n_users;
+                        notifications
+                            .push(user, NotificationKind::Like, actor, i, ts)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        for user in 0..n_users {
+            let mut link = notifications.feeds[user as usize].fetch();
+            let mut seen = 0;
+            while let Some(l) = link {
+                let event = &notifications.events[l.tweet_idx as usize];
+                assert_eq!((event.user, event.ts), (user, l.ts));
+                link = event.prev;
+                seen += 1;
+            }
+            assert_eq!(seen, 4 * 8);
+        }
+    }
+
+    #[test]
+    fn expired_notifications_are_released() {
+        let baked = GraphBuilder::new(3, true).finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        for t in 1..=6 {
+            data.add_tweet(Tweet::new(ts(t), "hi @0"), 1 + t % 2)
+                .unwrap();
+        }
+        assert_eq!(data.expire_before(ts(4)), 3);
+        assert_eq!(data.notifications.events.start(), 3);
+
+        let mut fetcher = NotificationFetcher::default();
+        let page = fetcher.for_user(&data, 0, 10, START_TIME, None);
+        let groups: Vec<String> = page
+            .groups
+            .iter()
+            .map(|g| format!("{:?} {} {:?}", g.kind, g.ts, g.actors))
+            .collect();
+        assert_eq!(groups, ["Mention 6 [1]", "Mention 5 [2]", "Mention 4 [1]"]);
+        assert_eq!(page.next, None);
+        // a cursor from before expiry ends the page instead of reading released events
+        let stale = NextLink {
+            ts: ts(2),
+            tweet_idx: 1,
+        };
+        let page = fetcher.for_user(&data, 0, 10, START_TIME, Some(stale));
+        assert!(page.groups.is_empty());
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn full_pool_fails_cleanly() {
+        let baked = GraphBuilder::new(3, true).finish();
+        let mut data = Datastore::new(baked.graph()).unwrap();
+        let config = crate::pool::PoolConfig {
+            segment_size: 4096,
+            limit: 2 * std::mem::size_of::<ChainedNotification>(),
+            ..Default::default()
+        };
+        data.notifications = Notifications {
+            events: SharedPool::with_config(config).unwrap(),
+            ..Notifications::new(3).unwrap()
+        };
+        let ts = |t| Timestamp::new(t).unwrap();
+        data.add_tweet(Tweet::new(ts(1), "@0"), 1).unwrap();
+        let own = data.add_tweet(Tweet::new(ts(2), "mine"), 0).unwrap();
+        assert!(data.like_tweet(2, own, ts(3)).unwrap());
+        // neither counted nor remembered as liked
+        assert!(data.like_tweet(1, own, ts(4)).is_err());
+        assert_eq!(data.get_tweet(own).unwrap().likes, 1);
+        // a tweet whose mentions don't fit is deleted
+        assert!(data.add_tweet(Tweet::new(ts(5), "@0 again"), 2).is_err());
+        assert_eq!(
+            data.tweet_counts[2].load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+
+        assert_eq!(data.expire_before(ts(2)), 1);
+        assert!(data.like_tweet(1, own, ts(6)).unwrap());
+        assert_eq!(data.get_tweet(own).unwrap().likes, 2);
+    }
+}
@@ -0,0 +1,283 @@
+//! NUMA placement for the big shared structures and the threads reading them.
+//!
+//! Left alone, every page lands on the node of whichever thread touched it
+//! first, which for tweets and feeds is the single writer, so readers on other
+//! sockets all cross the interconnect. Interleaving spreads pages round-robin
+//! over the nodes so every reader sees the same average latency and the
+//! bandwidth of all memory controllers.
+//!
+//! `TWITTERPERF_FAKE_NODES=N` splits the CPUs into N emulated nodes, like
+//! booting with `numa=fake=N`, so pinning and per-node code paths can be
+//! exercised on a single-node box. Memory policies still use the real nodes.
+
+use std::fs;
+use std::io;
+
+const MPOL_MF_MOVE: libc::c_ulong = 1 << 1;
+const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+/// Bits in the node masks we pass, plenty for any real machine
+const MAX_NODES: usize = 64;
+const PAGE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NumaPolicy {
+    /// First touch, the kernel default
+    #[default]
+    Local,
+    /// Round-robin over all nodes
+    Interleave,
+    /// Everything on one node
+    Node(u32),
+}
+
+impl NumaPolicy {
+    /// `TWITTERPERF_NUMA` set to `local`, `interleave` or a node number
+    pub fn from_env() -> Self {
+        std::env::var("TWITTERPERF_NUMA")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Applies to pages in the range not faulted in yet and moves the ones
+    /// that are. Only whole pages are covered.
+    ///
+    /// # Safety
+    /// `addr..addr + len` must be memory owned by the caller.
+    pub unsafe fn bind(self, addr: *const u8, len: usize) -> io::Result<()> {
+        let start = (addr as usize).next_multiple_of(PAGE);
+        let end = (addr as usize + len) / PAGE * PAGE;
+        if start >= end {
+            return Ok(());
+        }
+        let (mode, mask) = match self {
+            NumaPolicy::Local => (libc::MPOL_DEFAULT, 0),
+            NumaPolicy::Interleave => (
+                libc::MPOL_INTERLEAVE,
+                online_nodes()?.iter().fold(0u64, |m, n| m | 1 << n),
+            ),
+            NumaPolicy::Node(n) => (libc::MPOL_BIND, 1u64 << n),
+        };
+        let masks = [mask];
+        let (mask_ptr, max_node) = match mode {
+            libc::MPOL_DEFAULT => (std::ptr::null(), 0),
+            _ => (masks.as_ptr(), MAX_NODES + 1),
+        };
+        let ret = libc::syscall(
+            libc::SYS_mbind,
+            start,
+            end - start,
+            mode,
+            mask_ptr,
+            max_node,
+            MPOL_MF_MOVE,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for NumaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match &s.to_ascii_lowercase()[..] {
+            "local" => Ok(NumaPolicy::Local),
+            "interleave" => Ok(NumaPolicy::Interleave),
+            n => n
+                .parse()
+                .ok()
+                .filter(|n| (*n as usize) < MAX_NODES)
+                .map(NumaPolicy::Node)
+                .ok_or_else(|| format!("unknown NUMA policy {s}")),
+        }
+    }
+}
+
+/// Binds the pages wholly inside an already allocated buffer, like a `Vec`
+pub fn place<T>(items: &[T], policy: NumaPolicy) -> io::Result<()> {
+    unsafe { policy.bind(items.as_ptr() as *const u8, std::mem::size_of_val(items)) }
+}
+
+/// The `MPOL_*` mode governing the page at `addr`
+pub fn policy_at(addr: *const u8) -> io::Result<libc::c_int> {
+    let mut mode: libc::c_int = 0;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut mode as *mut libc::c_int,
+            std::ptr::null_mut::<u64>(),
+            0,
+            addr as usize,
+            MPOL_F_ADDR,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(mode)
+}
+
+/// Parses sysfs lists like `0-3,8,10-11`
+fn parse_list(text: &str) -> Option<Vec<usize>> {
+    let mut items = Vec::new();
+    for part in text.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((lo, hi)) => items.extend(lo.parse::<usize>().ok()?..=hi.parse().ok()?),
+            None => items.push(part.parse().ok()?),
+        }
+    }
+    Some(items)
+}
+
+fn read_list(path: &str) -> io::Result<Vec<usize>> {
+    parse_list(&fs::read_to_string(path)?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad list in {path}")))
+}
+
+pub fn online_nodes() -> io::Result<Vec<usize>> {
+    read_list("/sys/devices/system/node/online")
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    /// The real node memory comes from, emulated nodes share these
+    pub id: usize,
+    pub cpus: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topology {
+    pub nodes: Vec<Node>,
+}
+
+impl Topology {
+    /// Falls back to one node with every CPU on kernels without NUMA
+    pub fn detect() -> Self {
+        let nodes: Vec<Node> = online_nodes()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| {
+                let cpus = read_list(&format!("/sys/devices/system/node/node{id}/cpulist")).ok()?;
+                Some(Node { id, cpus })
+            })
+            .filter(|n| !n.cpus.is_empty())
+            .collect();
+        if !nodes.is_empty() {
+            return Self { nodes };
+        }
+        let n_cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self {
+            nodes: vec![Node {
+                id: 0,
+                cpus: (0..n_cpus).collect(),
+            }],
+        }
+    }
+
+    /// `detect`, split into `TWITTERPERF_FAKE_NODES` emulated nodes if set
+    pub fn from_env() -> Self {
+        let topology = Self::detect();
+        match std::env::var("TWITTERPERF_FAKE_NODES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            Some(n) => topology.emulate(n),
+            None => topology,
+        }
+    }
+
+    /// Splits each real node's CPUs into `per_node` contiguous groups, as
+    /// `numa=fake` would. Nodes with too few CPUs get as many groups as CPUs.
+    pub fn emulate(&self, per_node: usize) -> Self {
+        let nodes = self
+            .nodes
+            .iter()
+            .flat_map(|node| {
+                let n = per_node.clamp(1, node.cpus.len().max(1));
+                let chunk = node.cpus.len().div_ceil(n).max(1);
+                node.cpus.chunks(chunk).map(|cpus| Node {
+                    id: node.id,
+                    cpus: cpus.to_vec(),
+                })
+            })
+            .collect();
+        Self { nodes }
+    }
+
+    /// Restricts the calling thread to the CPUs of `node`, wrapping around
+    pub fn pin_current_thread(&self, node: usize) -> io::Result<()> {
+        let node = &self.nodes[node % self.nodes.len()];
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for &cpu in &node.cpus {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            parse_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_list(""), Some(vec![]));
+        assert_eq!(parse_list("1-x"), None);
+        assert_eq!("Interleave".parse(), Ok(NumaPolicy::Interleave));
+        assert_eq!("2".parse(), Ok(NumaPolicy::Node(2)));
+        assert!("far".parse::<NumaPolicy>().is_err());
+    }
+
+    #[test]
+    fn emulated_nodes() {
+        let real = Topology {
+            nodes: vec![Node {
+                id: 0,
+                cpus: (0..6).collect(),
+            }],
+        };
+        let fake = real.emulate(4);
+        let cpus: Vec<&[usize]> = fake.nodes.iter().map(|n| &n.cpus[..]).collect();
+        assert_eq!(cpus, [&[0, 1][..], &[2, 3], &[4, 5]]);
+        assert!(fake.nodes.iter().all(|n| n.id == 0));
+
+        let topology = Topology::from_env().emulate(2);
+        std::thread::spawn(move || topology.pin_current_thread(1).unwrap())
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn binding() {
+        let pool = crate::pool::SharedPool::<u64>::with_config(crate::pool::PoolConfig {
+            segment_size: 1 << 20,
+            limit: 1 << 20,
+            numa: NumaPolicy::Interleave,
+            ..Default::default()
+        })
+        .unwrap();
+        let idx = pool.push(7).unwrap();
+        let feeds = vec![0u64; 4096];
+        match place(&feeds, NumaPolicy::Interleave) {
+            // kernel built without NUMA, nothing to check
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => return,
+            placed => placed.unwrap(),
+        }
+        let addr = &pool[idx] as *const u64 as *const u8;
+        assert_eq!(policy_at(addr).unwrap(), libc::MPOL_INTERLEAVE);
+        let addr = feeds[1024..].as_ptr() as *const u8;
+        assert_eq!(policy_at(addr).unwrap(), libc::MPOL_INTERLEAVE);
+    }
+}
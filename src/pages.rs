@@ -0,0 +1,231 @@
+//! Page size policy for the big mappings: the tweet pools and the graph.
+//!
+//! Timeline fetches are dominated by random reads across gigabytes, so with
+//! 4K pages nearly every tweet touched is a TLB miss. Huge pages cover 512
+//! times as much per entry. Explicit hugetlb pages must be reserved ahead of
+//! time (`sysctl -w vm.nr_hugepages=N`) or faulting them in SIGBUSes, so each
+//! policy is checked before use and falls back to the next smaller one.
+
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::ptr;
+
+use crate::numa::NumaPolicy;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PagePolicy {
+    /// Plain 4K pages
+    #[default]
+    Small,
+    /// Transparent huge pages requested with `madvise(MADV_HUGEPAGE)`
+    Transparent,
+    /// Reserved hugetlb pages via `MAP_HUGETLB`
+    HugeTlb,
+}
+
+impl PagePolicy {
+    /// `TWITTERPERF_PAGES` set to `4k`, `thp` or `hugetlb`, else `Small`
+    pub fn from_env() -> Self {
+        std::env::var("TWITTERPERF_PAGES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// What a mapping of `len` bytes can actually get on this machine
+    pub fn resolve(self, len: usize) -> Self {
+        match self {
+            PagePolicy::HugeTlb => match huge_page_size() {
+                Some(size) if len.is_multiple_of(size) && free_huge_pages() * size >= len => self,
+                _ => PagePolicy::Transparent.resolve(len),
+            },
+            PagePolicy::Transparent if !thp_enabled() => PagePolicy::Small,
+            _ => self,
+        }
+    }
+
+    /// Maps `len` bytes of private anonymous memory, returning the policy that
+    /// was used. Only `Small` mappings skip reserving swap, hugetlb mappings
+    /// must reserve their pages up front so faults can't SIGBUS.
+    pub fn map_anonymous(self, len: usize) -> io::Result<(*mut u8, PagePolicy)> {
+        let policy = self.resolve(len);
+        let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        flags |= match policy {
+            PagePolicy::HugeTlb => libc::MAP_HUGETLB,
+            _ => libc::MAP_NORESERVE,
+        };
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                -1,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED || map.is_null() {
+            let err = io::Error::last_os_error();
+            // someone else took the reserved pages since we checked
+            if policy == PagePolicy::HugeTlb {
+                return PagePolicy::Transparent.map_anonymous(len);
+            }
+            return Err(err);
+        }
+        let map = map as *mut u8;
+        unsafe { policy.advise(map, len) };
+        Ok((map, policy))
+    }
+
+    /// Asks for transparent huge pages on an existing mapping if that's the
+    /// policy. Harmless when the kernel can't back it with them, as for most
+    /// file mappings.
+    ///
+    /// # Safety
+    /// `addr..addr + len` must be a mapping owned by the caller.
+    pub unsafe fn advise(self, addr: *mut u8, len: usize) {
+        if self == PagePolicy::Transparent {
+            libc::madvise(addr as *mut libc::c_void, len, libc::MADV_HUGEPAGE);
+        }
+    }
+}
+
+impl std::str::FromStr for PagePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match &s.to_ascii_lowercase()[..] {
+            "4k" | "small" => Ok(PagePolicy::Small),
+            "thp" | "transparent" => Ok(PagePolicy::Transparent),
+            "hugetlb" => Ok(PagePolicy::HugeTlb),
+            _ => Err(format!("unknown page policy {s}")),
+        }
+    }
+}
+
+/// A `/proc/meminfo`-style field, in kB for sizes
+fn field(text: &str, key: &str) -> Option<usize> {
+    text.lines().find_map(|line| {
+        let rest = line.strip_prefix(key)?.strip_prefix(':')?;
+        rest.split_whitespace().next()?.parse().ok()
+    })
+}
+
+/// Default hugetlb page size, if the kernel has hugetlbfs
+pub fn huge_page_size() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    field(&meminfo, "Hugepagesize").map(|kb| kb * 1024)
+}
+
+/// Reserved hugetlb pages nobody has mapped yet
+pub fn free_huge_pages() -> usize {
+    fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|m| field(&m, "HugePages_Free"))
+        .unwrap_or(0)
+}
+
+/// THP is `always` or `madvise`, either way `MADV_HUGEPAGE` gets them
+pub fn thp_enabled() -> bool {
+    fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+        .is_ok_and(|s| !s.contains("[never]"))
+}
+
+/// How much of this process is backed by huge pages, in bytes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HugePageUsage {
+    pub transparent: usize,
+    pub hugetlb: usize,
+}
+
+impl HugePageUsage {
+    pub fn current() -> io::Result<Self> {
+        let smaps = fs::read_to_string("/proc/self/smaps_rollup")?;
+        let kb = |key| field(&smaps, key).unwrap_or(0) * 1024;
+        Ok(Self {
+            transparent: kb("AnonHugePages"),
+            hugetlb: kb("Private_Hugetlb") + kb("Shared_Hugetlb"),
+        })
+    }
+}
+
+/// Anonymous memory mapped with a page policy, for copies of files that want
+/// hugetlb pages, which file mappings can't have
+pub struct AnonMap {
+    ptr: *mut u8,
+    len: usize,
+    map_len: usize,
+    pub policy: PagePolicy,
+}
+
+unsafe impl Send for AnonMap {}
+unsafe impl Sync for AnonMap {}
+
+impl AnonMap {
+    /// Rounds the mapping up to whole huge pages so hugetlb can apply. The
+    /// NUMA policy is only a hint and is skipped where mbind isn't supported.
+    pub fn copy_of(bytes: &[u8], policy: PagePolicy, numa: NumaPolicy) -> io::Result<Self> {
+        let page = match policy {
+            PagePolicy::HugeTlb => huge_page_size().unwrap_or(4096),
+            _ => 4096,
+        };
+        let map_len = bytes.len().max(1).next_multiple_of(page);
+        let (ptr, policy) = policy.map_anonymous(map_len)?;
+        if numa != NumaPolicy::Local {
+            let _ = unsafe { numa.bind(ptr, map_len) };
+        }
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+        Ok(Self {
+            ptr,
+            len: bytes.len(),
+            map_len,
+            policy,
+        })
+    }
+}
+
+impl Deref for AnonMap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AnonMap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.map_len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let meminfo = "MemFree:  1234 kB\nHugePages_Free:     7\nHugepagesize:    2048 kB\n";
+        assert_eq!(field(meminfo, "HugePages_Free"), Some(7));
+        assert_eq!(field(meminfo, "Hugepagesize"), Some(2048));
+        assert_eq!(field(meminfo, "HugePages"), None);
+        assert_eq!("THP".parse(), Ok(PagePolicy::Transparent));
+        assert!("1g".parse::<PagePolicy>().is_err());
+    }
+
+    #[test]
+    fn falls_back() {
+        // never a whole number of huge pages
+        assert_ne!(PagePolicy::HugeTlb.resolve(4096 * 3), PagePolicy::HugeTlb);
+        for policy in [
+            PagePolicy::Small,
+            PagePolicy::Transparent,
+            PagePolicy::HugeTlb,
+        ] {
+            let bytes: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+            let copy = AnonMap::copy_of(&bytes, policy, NumaPolicy::Local).unwrap();
+            assert_eq!(&copy[..], &bytes[..]);
+            assert_eq!(copy.policy, policy.resolve(copy.map_len));
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! Hardware performance counters through `perf_event_open`.
+//!
+//! Counters are often unavailable, in VMs, containers or with
+//! `kernel.perf_event_paranoid` set high, so `Counter::open` errors are meant
+//! to be reported and skipped rather than unwrapped. `Counters` does that for
+//! a set of events around a benchmark phase.
+
+use std::io;
+
+// from linux/perf_event.h, which libc doesn't carry
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_HW_CACHE: u32 = 3;
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+const PERF_COUNT_HW_CACHE_DTLB: u64 = 3;
+const PERF_COUNT_HW_CACHE_OP_READ: u64 = 0;
+const PERF_COUNT_HW_CACHE_RESULT_MISS: u64 = 1;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+const PERF_EVENT_IOC_RESET: libc::c_ulong = 0x2403;
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+
+/// `struct perf_event_attr` up to `PERF_ATTR_SIZE_VER5`
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+const FLAG_DISABLED: u64 = 1 << 0;
+const FLAG_INHERIT: u64 = 1 << 1;
+const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Cycles,
+    Instructions,
+    /// Last level cache misses, i.e. trips to DRAM
+    CacheMisses,
+    /// Data loads that missed every level of the TLB and walked the page table
+    DtlbLoadMisses,
+}
+
+impl Event {
+    pub const ALL: [Event; 4] = [
+        Event::Cycles,
+        Event::Instructions,
+        Event::CacheMisses,
+        Event::DtlbLoadMisses,
+    ];
+
+    /// As `perf stat` calls it
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Cycles => "cycles",
+            Event::Instructions => "instructions",
+            Event::CacheMisses => "cache-misses",
+            Event::DtlbLoadMisses => "dTLB-load-misses",
+        }
+    }
+
+    fn attr(self) -> PerfEventAttr {
+        let (type_, config) = match self {
+            Event::Cycles => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES),
+            Event::Instructions => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS),
+            Event::CacheMisses => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_MISSES),
+            Event::DtlbLoadMisses => (
+                PERF_TYPE_HW_CACHE,
+                PERF_COUNT_HW_CACHE_DTLB
+                    | PERF_COUNT_HW_CACHE_OP_READ << 8
+                    | PERF_COUNT_HW_CACHE_RESULT_MISS << 16,
+            ),
+        };
+        PerfEventAttr {
+            type_,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            // scale up when more events are open than the PMU has counters
+            read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+            flags: FLAG_DISABLED | FLAG_INHERIT | FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV,
+            ..Default::default()
+        }
+    }
+}
+
+/// Counts one event for this thread and threads it spawns after `open`.
+/// Spawned threads' counts only show up once they've exited.
+pub struct Counter {
+    fd: libc::c_int,
+}
+
+impl Counter {
+    /// Starts disabled
+    pub fn open(event: Event) -> io::Result<Self> {
+        let attr = event.attr();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0,
+                -1,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: fd as libc::c_int,
+        })
+    }
+
+    fn ioctl(&self, request: libc::c_ulong) {
+        unsafe { libc::ioctl(self.fd, request as _, 0) };
+    }
+
+    /// Zeroes the count and starts counting
+    pub fn start(&self) {
+        self.ioctl(PERF_EVENT_IOC_RESET);
+        self.ioctl(PERF_EVENT_IOC_ENABLE);
+    }
+
+    /// Stops counting and returns the count since `start`, estimated from the
+    /// time it was scheduled if the PMU was shared with other events. Fails if
+    /// it was never scheduled, there's nothing to estimate from then.
+    pub fn stop(&self) -> io::Result<Count> {
+        self.ioctl(PERF_EVENT_IOC_DISABLE);
+        // value, time enabled, time running
+        let mut read = [0u64; 3];
+        let n = unsafe { libc::read(self.fd, read.as_mut_ptr() as *mut libc::c_void, 24) };
+        if n != 24 {
+            return Err(io::Error::last_os_error());
+        }
+        let [value, enabled, running] = read;
+        if running == 0 {
+            return Err(io::Error::other(
+                "never scheduled, other events held the PMU",
+            ));
+        }
+        Ok(Count {
+            value: (value as u128 * enabled as u128 / running as u128) as u64,
+            scaled: running != enabled,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Count {
+    pub value: u64,
+    /// Estimated from part of the phase, so ratios with other counts that
+    /// were scheduled at other times are off
+    pub scaled: bool,
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Whichever of a set of events could be opened, for measuring a phase
+pub struct Counters {
+    counters: Vec<(Event, io::Result<Counter>)>,
+}
+
+impl Counters {
+    /// Never fails, events that can't be counted report why in `stop`
+    pub fn open(events: &[Event]) -> Self {
+        Self {
+            counters: events.iter().map(|&e| (e, Counter::open(e))).collect(),
+        }
+    }
+
+    /// Counting is opt-in with `TWITTERPERF_PERF=1` so it doesn't perturb
+    /// plain runs
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os("TWITTERPERF_PERF").map(|_| Self::open(&Event::ALL))
+    }
+
+    pub fn start(&self) {
+        for counter in self.counters.iter().filter_map(|(_, c)| c.as_ref().ok()) {
+            counter.start();
+        }
+    }
+
+    pub fn stop(&self) -> Sample {
+        let counts = self
+            .counters
+            .iter()
+            .map(|(event, counter)| {
+                let count = match counter {
+                    Ok(counter) => counter.stop(),
+                    Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                };
+                (*event, count)
+            })
+            .collect();
+        Sample { counts }
+    }
+}
+
+pub struct Sample {
+    pub counts: Vec<(Event, io::Result<Count>)>,
+}
+
+impl Sample {
+    pub fn get(&self, event: Event) -> Option<u64> {
+        self.count(event).map(|c| c.value)
+    }
+
+    fn count(&self, event: Event) -> Option<Count> {
+        self.counts
+            .iter()
+            .find(|(e, _)| *e == event)
+            .and_then(|(_, c)| c.as_ref().ok().copied())
+    }
+
+    /// One line with each count divided by `n`, e.g. per tweet or per timeline
+    pub fn per(&self, n: u64, unit: &str) -> String {
+        let mut line = format!("per {unit}:");
+        for (event, count) in &self.counts {
+            match count {
+                Ok(count) => {
+                    let per = count.value as f64 / n.max(1) as f64;
+                    line += &format!(" {} {per:.1}", event.name());
+                }
+                Err(e) => line += &format!(" {} unavailable ({e})", event.name()),
+            }
+        }
+        // scaled counts weren't measured over the same time, so their ratio isn't IPC
+        if let (Some(instructions), Some(cycles)) =
+            (self.count(Event::Instructions), self.count(Event::Cycles))
+        {
+            if !instructions.scaled && !cycles.scaled {
+                let ipc = instructions.value as f64 / cycles.value.max(1) as f64;
+                line += &format!(" IPC {ipc:.2}");
+            }
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_or_errors() {
+        assert_eq!(std::mem::size_of::<PerfEventAttr>(), 112);
+        // most CI machines won't have counters, that has to be an error not a crash
+        let counters = Counters::open(&Event::ALL);
+        counters.start();
+        let v: Vec<u64> = (0..1 << 20).collect();
+        std::hint::black_box(v.iter().step_by(512).sum::<u64>());
+        let sample = counters.stop();
+        assert_eq!(sample.counts.len(), 4);
+        let line = sample.per(1000, "item");
+        assert!(line.starts_with("per item: cycles "), "{line}");
+        if let Some(instructions) = sample.get(Event::Instructions) {
+            assert!(instructions > 0);
+        }
+    }
+
+    #[test]
+    fn scaled_counts_have_no_ipc() {
+        let count = |value, scaled| Ok(Count { value, scaled });
+        let sample = |scaled| Sample {
+            counts: vec![
+                (Event::Cycles, count(2000, false)),
+                (Event::Instructions, count(3000, scaled)),
+            ],
+        };
+        assert_eq!(
+            sample(false).per(1000, "item"),
+            "per item: cycles 2.0 instructions 3.0 IPC 1.50"
+        );
+        assert_eq!(
+            sample(true).per(1000, "item"),
+            "per item: cycles 2.0 instructions 3.0"
+        );
+    }
+}
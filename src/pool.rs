@@ -1,67 +1,549 @@
+use std::fs::File;
 use std::io;
 use std::ops::Index;
-use std::ptr::{self, NonNull};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::os::fd::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::Mutex;
 
+use crate::numa::NumaPolicy;
+use crate::pages::PagePolicy;
+
+/// Indices are stable and only grow. Items live in fixed-size segments,
+/// each its own mapping, mapped as `push` reaches them. Items below `start`
+/// have been released, and their segment slots get reused.
+///
+/// A pool can also live in a file, see `create_shared`, so other processes
+/// can read it. Segments are then fixed regions of the file.
+///
+/// Ids handed out elsewhere are often indices narrowed to `u32`, which wrap
+/// every 2^32 items. At most `MAX_LIVE` items are live at once, so `PoolView`
+/// can tell which live item an id means.
 pub struct SharedPool<T> {
-    len: AtomicUsize,
+    /// Points into `backing`
+    meta: *const Meta,
+    backing: Backing,
     lock: Mutex<()>,
-    buf: NonNull<T>,
+    /// Items per segment is `1 << shift`
+    shift: u32,
+    /// Most items live at once, `push` fails beyond this
+    limit: usize,
+    pages: PagePolicy,
+    numa: NumaPolicy,
+    /// Segment `s` lives at `segments[s & (segments.len() - 1)]`, null when
+    /// unmapped. The length is a power of two so that's a mask, not a division.
+    segments: Box<[AtomicPtr<T>]>,
 }
 
 unsafe impl<T: Sync> Sync for SharedPool<T> {}
 unsafe impl<T: Send> Send for SharedPool<T> {}
 
-// https://vgel.me/posts/mmap-arena-alloc/
-impl<T> SharedPool<T> {
+/// What readers need to agree on, in the file header for shared pools
+#[repr(C)]
+struct Meta {
+    len: AtomicUsize,
+    start: AtomicUsize,
+    /// Everything below has been through `release`
+    released: AtomicUsize,
+}
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    item_size: u64,
+    shift: u64,
+    limit: u64,
+    meta: Meta,
+}
+
+const MAGIC: u64 = u64::from_le_bytes(*b"twtpool2");
+/// Caps `PoolConfig::limit`, small enough that narrowed ids of live items are
+/// unique and compare correctly with wrapping arithmetic
+pub const MAX_LIVE: usize = 1 << 30;
+/// The header gets a page to itself so segments stay page aligned
+const HEADER_SIZE: usize = 4096;
+
+enum Backing {
+    Private {
+        /// What `SharedPool::meta` points to
+        _meta: Box<Meta>,
+    },
+    /// The whole file mapped `MAP_SHARED`
+    Shared {
+        map: *mut u8,
+        map_len: usize,
+        writable: bool,
+    },
+}
+
+/// Sizes in bytes. Segments are rounded down to a power of two items, the
+/// limit to whole items and at most `MAX_LIVE`.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+    /// Mappings are lazy so this mostly bounds how much is mapped at a time
+    pub segment_size: usize,
     /// It doesn't matter that much how large this is but let's go for 34GB
-    const MAP_SIZE: usize = 1 << 35;
+    pub limit: usize,
+    pub pages: PagePolicy,
+    pub numa: NumaPolicy,
+}
 
-    pub fn new() -> io::Result<Self> {
-        // TODO use hugepages only on linux
-        let map = unsafe {
-            libc::mmap(
-                ptr::null_mut(),
-                Self::MAP_SIZE,
-                libc::PROT_READ | libc::PROT_WRITE,
-                // MAP_PRIVATE:   this is not shared memory
-                // MAP_ANONYMOUS: this is RAM, not a file-backed mmap
-                // MAP_NORESERVE: don't reserve swap
-                // MAP_HUGETLB:   use huge pages for better performance
-                //                (make sure huge pages are enabled or this will SIGBUS:
-                //                 # sysctl -w vm.nr_hugepages=2048)
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, // | libc::MAP_HUGETLB,
-                -1,
-                0,
-            )
-        };
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            segment_size: 1 << 30,
+            limit: 1 << 35,
+            pages: PagePolicy::from_env(),
+            numa: NumaPolicy::from_env(),
+        }
+    }
+}
 
-        let buf = if map == libc::MAP_FAILED || map.is_null() {
-            return Err(io::Error::last_os_error());
-        } else {
-            NonNull::new(map as *mut T).unwrap()
-        };
+// https://vgel.me/posts/mmap-arena-alloc/
+impl<T> SharedPool<T> {
+    pub fn new() -> io::Result<Self> {
+        Self::with_config(PoolConfig::default())
+    }
 
+    pub fn with_config(config: PoolConfig) -> io::Result<Self> {
+        let (shift, limit) = Self::layout(&config)?;
+        let meta = Box::new(Meta {
+            len: AtomicUsize::new(0),
+            start: AtomicUsize::new(0),
+            released: AtomicUsize::new(0),
+        });
         Ok(Self {
-            buf,
+            meta: &*meta,
+            backing: Backing::Private { _meta: meta },
             lock: Mutex::new(()),
-            len: AtomicUsize::new(0),
+            shift,
+            limit,
+            pages: config
+                .pages
+                .resolve((1 << shift) * std::mem::size_of::<T>()),
+            numa: config.numa,
+            segments: Self::empty_segments(shift, limit),
         })
     }
 
+    /// (shift, limit in items)
+    fn layout(config: &PoolConfig) -> io::Result<(u32, usize)> {
+        let size = std::mem::size_of::<T>().max(1);
+        let shift = (config.segment_size / size).max(1).ilog2();
+        let limit = (config.limit / size).min(MAX_LIVE);
+        if limit == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pool limit is smaller than one item",
+            ));
+        }
+        Ok((shift, limit))
+    }
+
+    fn empty_segments(shift: u32, limit: usize) -> Box<[AtomicPtr<T>]> {
+        // the live range can straddle one more segment than it fills
+        let n_segments = (limit.div_ceil(1 << shift) + 1).next_power_of_two();
+        (0..n_segments)
+            .map(|_| AtomicPtr::new(ptr::null_mut()))
+            .collect()
+    }
+
+    /// Lays a new empty pool out in `file`, replacing its contents. The file
+    /// is sized for `config.limit` but stays sparse until written. Pages are
+    /// always small, the NUMA policy applies.
+    ///
+    /// # Safety
+    /// `T` must be plain data, without pointers or anything to drop, since
+    /// other processes read it. Only one process may write to the file.
+    pub unsafe fn create_shared(file: &File, config: PoolConfig) -> io::Result<Self> {
+        let (shift, limit) = Self::layout(&config)?;
+        let segments = Self::empty_segments(shift, limit);
+        let map_len = HEADER_SIZE + segments.len() * (std::mem::size_of::<T>() << shift);
+        file.set_len(0)?;
+        file.set_len(map_len as u64)?;
+        let map = map_shared(file, map_len, true)?;
+        if config.numa != NumaPolicy::Local {
+            let _ = config.numa.bind(map, map_len);
+        }
+        ptr::write(
+            map as *mut Header,
+            Header {
+                magic: MAGIC,
+                item_size: std::mem::size_of::<T>() as u64,
+                shift: shift as u64,
+                limit: limit as u64,
+                meta: Meta {
+                    len: AtomicUsize::new(0),
+                    start: AtomicUsize::new(0),
+                    released: AtomicUsize::new(0),
+                },
+            },
+        );
+        Ok(Self::from_shared_map(
+            map, map_len, true, shift, limit, segments,
+        ))
+    }
+
+    /// Attaches read-only to a pool another process is writing with
+    /// `create_shared`. `push` and `truncate_front` fail on it.
+    ///
+    /// # Safety
+    /// Same as `create_shared`, and the file must have been made by it for
+    /// the same `T`.
+    pub unsafe fn open_shared(file: &File) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let file_len = file.metadata()?.len() as usize;
+        if file_len < HEADER_SIZE {
+            return Err(invalid("not a shared pool"));
+        }
+        let header = map_shared(file, HEADER_SIZE, false)?;
+        let (magic, item_size, shift, limit) = {
+            let h = &*(header as *const Header);
+            (h.magic, h.item_size, h.shift as u32, h.limit as usize)
+        };
+        libc::munmap(header as *mut libc::c_void, HEADER_SIZE);
+        if magic != MAGIC || item_size != std::mem::size_of::<T>() as u64 {
+            return Err(invalid("not a shared pool of this type"));
+        }
+        let segments = Self::empty_segments(shift, limit);
+        let map_len = HEADER_SIZE + segments.len() * (std::mem::size_of::<T>() << shift);
+        if file_len < map_len {
+            return Err(invalid("shared pool file is truncated"));
+        }
+        let map = map_shared(file, map_len, false)?;
+        Ok(Self::from_shared_map(
+            map, map_len, false, shift, limit, segments,
+        ))
+    }
+
+    fn from_shared_map(
+        map: *mut u8,
+        map_len: usize,
+        writable: bool,
+        shift: u32,
+        limit: usize,
+        segments: Box<[AtomicPtr<T>]>,
+    ) -> Self {
+        let segment_bytes = std::mem::size_of::<T>() << shift;
+        for (k, segment) in segments.iter().enumerate() {
+            let at = unsafe { map.add(HEADER_SIZE + k * segment_bytes) };
+            segment.store(at as *mut T, Ordering::SeqCst);
+        }
+        Self {
+            meta: unsafe { &(*(map as *const Header)).meta },
+            backing: Backing::Shared {
+                map,
+                map_len,
+                writable,
+            },
+            lock: Mutex::new(()),
+            shift,
+            limit,
+            pages: PagePolicy::Small,
+            numa: NumaPolicy::Local,
+            segments,
+        }
+    }
+
+    #[inline]
+    fn meta(&self) -> &Meta {
+        unsafe { &*self.meta }
+    }
+
+    fn is_shared(&self) -> bool {
+        matches!(self.backing, Backing::Shared { .. })
+    }
+
+    /// Fails on pools opened with `open_shared`
+    pub fn check_writable(&self) -> io::Result<()> {
+        match self.backing {
+            Backing::Shared {
+                writable: false, ..
+            } => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "pool was opened read-only",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Items that can be live at once
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.limit
+    }
+
+    #[inline]
+    fn segment_len(&self) -> usize {
+        1 << self.shift
+    }
+
+    #[inline]
+    fn segment(&self, s: usize) -> &AtomicPtr<T> {
+        &self.segments[s & (self.segments.len() - 1)]
+    }
+
     #[inline]
-    pub fn push(&self, value: T) -> usize {
+    fn slot(&self, i: usize) -> *mut T {
+        // pairs with the store in `push`, which happens before `len` covers `i`
+        let segment = self.segment(i >> self.shift).load(Ordering::Acquire);
+        unsafe { segment.add(i & (self.segment_len() - 1)) }
+    }
+
+    fn map_segment(&self) -> io::Result<*mut T> {
+        let bytes = self.segment_len() * std::mem::size_of::<T>();
+        let (map, _) = self.pages.map_anonymous(bytes)?;
+        if self.numa != NumaPolicy::Local {
+            // only a hint, kernels without NUMA don't have mbind
+            let _ = unsafe { self.numa.bind(map, bytes) };
+        }
+        Ok(map as *mut T)
+    }
+
+    /// Page policy segments get, after falling back from what was configured
+    pub fn page_policy(&self) -> PagePolicy {
+        self.pages
+    }
+
+    unsafe fn unmap_segment(&self, segment: *mut T) {
+        let bytes = self.segment_len() * std::mem::size_of::<T>();
+        libc::munmap(segment as *mut libc::c_void, bytes);
+    }
+
+    /// Fails once `capacity` items are live, or while the segment it needs is
+    /// still waiting for `release`
+    #[inline]
+    pub fn push(&self, value: T) -> io::Result<usize> {
+        self.check_writable()?;
         // TODO either be clever about queueing these up or
         // split this type into a reader and a writer to avoid the lock
         let _guard = self.lock.lock();
-        let i = self.len.load(Ordering::SeqCst);
+        let meta = self.meta();
+        let i = meta.len.load(Ordering::SeqCst);
+        let full = || {
+            io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("pool full at {} items, release old items first", self.limit),
+            )
+        };
+        if i - self.start() >= self.limit {
+            return Err(full());
+        }
+        if i & (self.segment_len() - 1) == 0 {
+            // the segment last in this slot must be gone
+            let s = i >> self.shift;
+            let reused_end = (s + 1).saturating_sub(self.segments.len()) << self.shift;
+            if meta.released.load(Ordering::SeqCst) < reused_end {
+                return Err(full());
+            }
+            if !self.is_shared() {
+                self.segment(s).store(self.map_segment()?, Ordering::SeqCst);
+            }
+        }
+        unsafe {
+            ptr::write(self.slot(i), value);
+        }
+        // publishes the write, to other processes too
+        meta.len.fetch_add(1, Ordering::SeqCst);
+        Ok(i)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.meta().len.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// First index that hasn't been released
+    #[inline]
+    pub fn start(&self) -> usize {
+        self.meta().start.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if i < self.len() && i >= self.start() {
+            Some(&self[i])
+        } else {
+            None
+        }
+    }
+
+    /// Reads the items live as of now. Unlike `get` and `Index`, which check
+    /// against `start` as it moves, these stay readable while the view lives.
+    ///
+    /// # Safety
+    /// Nothing from the current `start` on may be released while the view
+    /// lives, e.g. pin `crate::epoch` before calling this.
+    #[inline]
+    pub unsafe fn view(&self) -> PoolView<'_, T> {
+        PoolView {
+            pool: self,
+            start: self.start(),
+        }
+    }
+}
+
+/// See `SharedPool::view`
+pub struct PoolView<'a, T> {
+    pool: &'a SharedPool<T>,
+    start: usize,
+}
+
+impl<T> PoolView<'_, T> {
+    /// `SharedPool::start` when the view was taken
+    #[inline]
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    #[inline]
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if i >= self.start && i < self.pool.len() {
+            Some(unsafe { self.pool.get_unchecked(i) })
+        } else {
+            None
+        }
+    }
+
+    /// Index of the item in the view whose index narrows to `id`
+    #[inline]
+    pub fn position(&self, id: u32) -> Option<usize> {
+        let i = self.start + id.wrapping_sub(self.start as u32) as usize;
+        (i < self.pool.len()).then_some(i)
+    }
+
+    /// `get` by an index narrowed to `u32`
+    #[inline]
+    pub fn get_narrow(&self, id: u32) -> Option<&T> {
+        self.position(id)
+            .map(|i| unsafe { self.pool.get_unchecked(i) })
+    }
+}
+
+/// Orders ids narrowed to `u32` by when they were pushed, which `Ord` stops
+/// doing once they wrap. Only valid for ids of items less than 2^31 apart.
+#[inline]
+pub fn id_cmp(a: u32, b: u32) -> std::cmp::Ordering {
+    (a.wrapping_sub(b) as i32).cmp(&0)
+}
+
+impl<T> SharedPool<T> {
+    /// Makes everything below `new_start` unreachable through `get` and `Index`.
+    /// Returns the old start, pass both to `release` once no reader can still
+    /// hold a reference into the range.
+    ///
+    /// Panics on pools opened read-only.
+    pub fn truncate_front(&self, new_start: usize) -> usize {
+        self.check_writable().unwrap();
+        let _guard = self.lock.lock();
+        let new_start = new_start.min(self.len());
+        self.meta().start.fetch_max(new_start, Ordering::SeqCst)
+    }
+
+    /// Skips the bounds checks, for looking at truncated items before `release`
+    ///
+    /// # Safety
+    /// `i` must have been pushed and not released.
+    #[inline]
+    pub unsafe fn get_unchecked(&self, i: usize) -> &T {
+        &*self.slot(i)
+    }
+
+    /// Drops items in `from..to`, unmaps segments that are now empty and gives
+    /// whole pages of the last one back to the OS. Shared pools punch holes
+    /// in the file instead of unmapping.
+    ///
+    /// # Safety
+    /// The range must have been truncated off with `truncate_front`, ranges
+    /// must be released in order, and no references into them may remain,
+    /// see `crate::epoch`.
+    pub unsafe fn release(&self, from: usize, to: usize) {
+        if from >= to {
+            return;
+        }
+        for i in from..to {
+            ptr::drop_in_place(self.slot(i));
+        }
+        let segment_bytes = self.segment_len() * std::mem::size_of::<T>();
+        // everything before `from` in its segment went in an earlier release
+        for s in (from >> self.shift)..(to >> self.shift) {
+            if self.is_shared() {
+                let segment = self.segment(s).load(Ordering::SeqCst) as *mut u8;
+                self.release_pages(segment, 0, segment_bytes);
+            } else {
+                let segment = self.segment(s).swap(ptr::null_mut(), Ordering::SeqCst);
+                self.unmap_segment(segment);
+            }
+        }
+        let last_start = to & !(self.segment_len() - 1);
+        if last_start < to {
+            let segment = self.segment(to >> self.shift).load(Ordering::SeqCst) as *mut u8;
+            let offset = |i: usize| (i & (self.segment_len() - 1)) * std::mem::size_of::<T>();
+            self.release_pages(segment, offset(from.max(last_start)), offset(to));
+        }
+        self.meta().released.store(to, Ordering::SeqCst);
+    }
+
+    /// Pages only partly inside the range may still hold live items
+    unsafe fn release_pages(&self, segment: *mut u8, from: usize, to: usize) {
+        const PAGE: usize = 4096;
+        let from = from.next_multiple_of(PAGE);
+        let to = to / PAGE * PAGE;
+        let advice = match self.backing {
+            Backing::Shared { .. } => libc::MADV_REMOVE,
+            Backing::Private { .. } => libc::MADV_DONTNEED,
+        };
+        if from < to {
+            libc::madvise(segment.add(from) as *mut libc::c_void, to - from, advice);
+        }
+    }
+}
+
+unsafe fn map_shared(file: &File, len: usize, writable: bool) -> io::Result<*mut u8> {
+    let prot = match writable {
+        true => libc::PROT_READ | libc::PROT_WRITE,
+        false => libc::PROT_READ,
+    };
+    let map = libc::mmap(
+        ptr::null_mut(),
+        len,
+        prot,
+        libc::MAP_SHARED,
+        file.as_raw_fd(),
+        0,
+    );
+    if map == libc::MAP_FAILED || map.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(map as *mut u8)
+}
+
+impl<T> Drop for SharedPool<T> {
+    fn drop(&mut self) {
         unsafe {
-            let end = self.buf.as_ptr().add(i);
-            ptr::write(end, value);
+            match self.backing {
+                // other processes may still be reading, nothing to drop anyway
+                Backing::Shared { map, map_len, .. } => {
+                    libc::munmap(map as *mut libc::c_void, map_len);
+                }
+                Backing::Private { .. } => {
+                    for i in self.start()..self.len() {
+                        ptr::drop_in_place(self.slot(i));
+                    }
+                    for segment in self.segments.iter() {
+                        let segment = segment.load(Ordering::SeqCst);
+                        if !segment.is_null() {
+                            self.unmap_segment(segment);
+                        }
+                    }
+                }
+            }
         }
-        self.len.fetch_add(1, Ordering::SeqCst);
-        i
     }
 }
 
@@ -70,14 +552,15 @@ impl<T> Index<usize> for SharedPool<T> {
 
     #[inline]
     fn index(&self, i: usize) -> &T {
-        let len = self.len.load(Ordering::SeqCst);
+        let len = self.len();
         if i >= len {
             panic!("index out of bounds {i} for length {len}")
         }
-        unsafe {
-            let item = self.buf.as_ptr().add(i);
-            &*item
+        let start = self.start();
+        if i < start {
+            panic!("index {i} was released, pool starts at {start}")
         }
+        unsafe { &*self.slot(i) }
     }
 }
 
@@ -88,9 +571,116 @@ mod tests {
     #[test]
     fn basic_pool() {
         let pool = SharedPool::new().unwrap();
-        pool.push(5);
-        pool.push(6);
+        pool.push(5).unwrap();
+        pool.push(6).unwrap();
         assert_eq!(pool[0], 5);
         assert_eq!(pool[1], 6);
     }
+
+    #[test]
+    fn release_keeps_indices() {
+        let pool = SharedPool::new().unwrap();
+        for i in 0..10_000u64 {
+            pool.push(i).unwrap();
+        }
+        let old = pool.truncate_front(9_000);
+        assert_eq!((old, pool.start()), (0, 9_000));
+        unsafe { pool.release(old, 9_000) };
+        assert!(pool.get(8_999).is_none());
+        assert_eq!(pool.get(9_000), Some(&9_000));
+        let view = unsafe { pool.view() };
+        assert_eq!(view.get_narrow(9_500), Some(&9_500));
+        assert_eq!(view.get_narrow(8_999), None);
+        assert_eq!(pool.push(10_000).unwrap(), 10_000);
+        assert_eq!(pool[10_000], 10_000);
+        // never moves backwards
+        pool.truncate_front(5);
+        assert_eq!(pool.start(), 9_000);
+    }
+
+    #[test]
+    fn narrowed_ids() {
+        let pool = SharedPool::<u64>::new().unwrap();
+        for i in 0..10u64 {
+            pool.push(i).unwrap();
+        }
+        let old = pool.truncate_front(4);
+        let view = unsafe { pool.view() };
+        // a view only sees what was live when taken, however far start moves
+        pool.truncate_front(8);
+        assert_eq!(view.get(5), Some(&5));
+        assert_eq!(pool.get(5), None);
+        assert_eq!(view.position(7), Some(7));
+        assert_eq!(view.position(3), None);
+        assert_eq!(view.position(u32::MAX), None);
+        unsafe { pool.release(old, 8) };
+
+        assert!(id_cmp(3, 5).is_lt());
+        assert!(id_cmp(u32::MAX, 2).is_lt());
+        assert!(id_cmp(2, u32::MAX - 1).is_gt());
+        assert!(id_cmp(7, 7).is_eq());
+    }
+
+    #[test]
+    fn grows_to_limit() {
+        // 512 items per segment, 1536 live at most, so 4 segment slots
+        let pool = SharedPool::<u64>::with_config(PoolConfig {
+            segment_size: 4096,
+            limit: 12288,
+            pages: PagePolicy::Transparent,
+            numa: NumaPolicy::Local,
+        })
+        .unwrap();
+        assert_eq!((pool.capacity(), pool.segments.len()), (1536, 4));
+        for i in 0..1536 {
+            assert_eq!(pool.push(i).unwrap(), i as usize);
+        }
+        let err = pool.push(1536).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+        assert_eq!(pool.len(), 1536);
+
+        // truncating makes room, but the segment slot comes back with `release`
+        let old = pool.truncate_front(700);
+        assert_eq!(pool.push(1536).unwrap(), 1536);
+        for i in 1537..2048 {
+            pool.push(i).unwrap();
+        }
+        assert!(pool.push(2048).is_err());
+        unsafe { pool.release(old, 700) };
+        for i in 2048..2236 {
+            assert_eq!(pool.push(i).unwrap(), i as usize);
+        }
+        assert!(pool.push(2236).is_err());
+        assert_eq!(pool.get(699), None);
+        assert!((700..2236).all(|i| pool[i] == i as u64));
+    }
+
+    #[test]
+    fn shared_between_mappings() {
+        let file = crate::shm::memfd("pool-test").unwrap();
+        let config = PoolConfig {
+            segment_size: 4096,
+            limit: 16384,
+            pages: PagePolicy::Small,
+            numa: NumaPolicy::Local,
+        };
+        let writer = unsafe { SharedPool::<u64>::create_shared(&file, config) }.unwrap();
+        let reader = unsafe { SharedPool::<u64>::open_shared(&file) }.unwrap();
+        assert!(unsafe { SharedPool::<u32>::open_shared(&file) }.is_err());
+        for i in 0..2048 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!((reader.len(), reader[2047]), (2048, 2047));
+        assert!(reader.push(0).is_err());
+        assert!(writer.push(2048).is_err());
+
+        let old = writer.truncate_front(1000);
+        unsafe { writer.release(old, 1000) };
+        assert_eq!(reader.get(999), None);
+        // the first segment's slot in the file gets reused
+        for i in 2048..2700 {
+            writer.push(i).unwrap();
+        }
+        assert!((1000..2700).all(|i| reader[i] == i as u64));
+    }
 }
@@ -0,0 +1,168 @@
+//! Ranked "For You" timelines.
+//!
+//! Pulls the newest `candidates` tweets out of the usual chronological merge
+//! and keeps the `max_len` best according to a `Scorer`. The candidate window
+//! bounds the cost, so a great tweet older than the window is never considered.
+
+use crate::data::*;
+use crate::timeline::{Timeline, TimelineFetcher};
+
+pub trait Scorer {
+    /// Higher is better. `now` is the newest candidate's timestamp so scores
+    /// don't depend on the wall clock.
+    fn score(&self, tweet: &Tweet, author: &User, now: Timestamp) -> f32;
+}
+
+/// Engagement and author reach, decayed exponentially with age
+#[derive(Clone, Copy, Debug)]
+pub struct EngagementScorer {
+    /// Seconds for a score to halve
+    pub half_life: f32,
+    pub like_weight: f32,
+    pub retweet_weight: f32,
+    pub quote_weight: f32,
+    /// Applied to ln(1 + followers) so huge accounts don't drown everyone out
+    pub follower_weight: f32,
+}
+
+impl Default for EngagementScorer {
+    fn default() -> Self {
+        Self {
+            half_life: 6. * 3600.,
+            like_weight: 1.,
+            retweet_weight: 2.,
+            quote_weight: 3.,
+            follower_weight: 0.5,
+        }
+    }
+}
+
+impl Scorer for EngagementScorer {
+    #[inline]
+    fn score(&self, tweet: &Tweet, author: &User, now: Timestamp) -> f32 {
+        let engagement = self.like_weight * tweet.likes as f32
+            + self.retweet_weight * tweet.retweets as f32
+            + self.quote_weight * tweet.quotes as f32;
+        let reach = self.follower_weight * (author.num_followers as f32).ln_1p();
+        let age = now.get().saturating_sub(tweet.ts.get()) as f32;
+        (1. + engagement + reach) * (-age / self.half_life).exp2()
+    }
+}
+
+pub struct RankedFetcher {
+    fetcher: TimelineFetcher,
+    /// (score, candidate index)
+    scored: Vec<(f32, usize)>,
+    tweets: Vec<Tweet>,
+    authors: Vec<UserIdx>,
+}
+
+impl Default for RankedFetcher {
+    fn default() -> Self {
+        Self {
+            fetcher: TimelineFetcher::with_authors(),
+            scored: vec![],
+            tweets: vec![],
+            authors: vec![],
+        }
+    }
+}
+
+impl RankedFetcher {
+    /// Best `max_len` of the newest `candidates` tweets, best first.
+    /// Equal scores keep chronological order.
+    pub fn for_user<'a>(
+        &'a mut self,
+        data: &Datastore,
+        scorer: &impl Scorer,
+        user_idx: UserIdx,
+        candidates: usize,
+        max_len: usize,
+        after: Timestamp,
+    ) -> Timeline<'a> {
+        self.scored.clear();
+        self.tweets.clear();
+        self.authors.clear();
+
+        let window = self
+            .fetcher
+            .for_user(data, user_idx, candidates.max(max_len), after);
+        if let Some(newest) = window.tweets.first() {
+            let now = newest.ts;
+            for (i, (tweet, author)) in window.tweets.iter().zip(window.authors).enumerate() {
+                let author = &data.graph.users[*author as usize];
+                self.scored.push((scorer.score(tweet, author, now), i));
+            }
+        }
+
+        let by_score = |a: &(f32, usize), b: &(f32, usize)| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1));
+        if self.scored.len() > max_len && max_len > 0 {
+            self.scored.select_nth_unstable_by(max_len - 1, by_score);
+        }
+        self.scored.truncate(max_len);
+        self.scored.sort_unstable_by(by_score);
+        for (_, i) in &self.scored {
+            self.tweets.push(window.tweets[*i].clone());
+            self.authors.push(window.authors[*i]);
+        }
+
+        Timeline {
+            tweets: &self.tweets[..],
+            authors: &self.authors[..],
+            idxs: &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+
+    #[test]
+    fn ranks_candidates() {
+        // 0 follows 1 and 2, and 3..12 also follow 2
+        let mut builder = GraphBuilder::new(12, true);
+        builder.add_edge(0, 1);
+        for follower in [0].into_iter().chain(3..12) {
+            builder.add_edge(follower, 2);
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let tweet = |ts: u32, likes: u32| {
+            let mut tweet = Tweet::dummy(Timestamp::new(ts).unwrap());
+            tweet.likes = likes;
+            tweet
+        };
+        data.add_tweet(tweet(100, 50), 1).unwrap();
+        data.add_tweet(tweet(200, 0), 1).unwrap();
+        data.add_tweet(tweet(300, 0), 2).unwrap();
+        data.add_tweet(tweet(400, 0), 1).unwrap();
+        data.add_tweet(tweet(500, 0), 1).unwrap();
+
+        // with 1 follower and no likes the newest tweet scores 1.35, the one 100s
+        // older 1.17 and 2's tweet 1.67 thanks to 10 followers
+        let scorer = EngagementScorer {
+            half_life: 500.,
+            ..Default::default()
+        };
+        let mut fetcher = RankedFetcher::default();
+        let ranked = |fetcher: &mut RankedFetcher, candidates, max_len| -> Vec<u32> {
+            let timeline = fetcher.for_user(&data, &scorer, 0, candidates, max_len, START_TIME);
+            timeline.tweets.iter().map(|t| t.ts.get()).collect()
+        };
+        // likes beat recency, then the author with more followers
+        assert_eq!(ranked(&mut fetcher, 10, 3), [100, 300, 500]);
+        // the liked tweet is outside a window of the newest 4
+        assert_eq!(ranked(&mut fetcher, 4, 2), [300, 500]);
+        assert_eq!(ranked(&mut fetcher, 10, 10).len(), 5);
+
+        // likes after posting count too, 3 of them lift the newest tweet past 2's
+        for user in 3..6 {
+            assert!(data
+                .like_tweet(user, 4, Timestamp::new(600).unwrap())
+                .unwrap());
+        }
+        assert_eq!(ranked(&mut fetcher, 4, 2), [500, 300]);
+    }
+}
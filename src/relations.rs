@@ -0,0 +1,292 @@
+//! Per-viewer mute and block lists, applied while seeding the timeline merge.
+//!
+//! Every feed chain belongs to a single author, so hiding an author means
+//! skipping their feed head instead of filtering tweet by tweet, and the
+//! merge still fills `max_len` from everyone else.
+//!
+//! Only users that have muted, blocked or been blocked by someone get an
+//! entry, with sorted `u32` lists, plus one bit per user so the common case
+//! of a viewer with no relations never takes the lock.
+//!
+//! Changes are appended to `Relations::log` so replicas can apply them in the
+//! same order.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use crate::data::UserIdx;
+use crate::pool::SharedPool;
+use crate::shm::ReaderGate;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelationChange {
+    Mute { viewer: UserIdx, target: UserIdx },
+    Unmute { viewer: UserIdx, target: UserIdx },
+    Block { blocker: UserIdx, target: UserIdx },
+    Unblock { blocker: UserIdx, target: UserIdx },
+}
+
+impl RelationChange {
+    /// (viewer or blocker, target)
+    pub fn users(self) -> (UserIdx, UserIdx) {
+        match self {
+            RelationChange::Mute { viewer, target } | RelationChange::Unmute { viewer, target } => {
+                (viewer, target)
+            }
+            RelationChange::Block { blocker, target }
+            | RelationChange::Unblock { blocker, target } => (blocker, target),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct UserRelations {
+    mutes: Vec<UserIdx>,
+    blocks: Vec<UserIdx>,
+    /// reverse of `blocks`, since a block hides tweets in both directions
+    blocked_by: Vec<UserIdx>,
+}
+
+fn insert(list: &mut Vec<UserIdx>, x: UserIdx) {
+    if let Err(i) = list.binary_search(&x) {
+        list.insert(i, x);
+    }
+}
+
+fn remove(list: &mut Vec<UserIdx>, x: UserIdx) {
+    if let Ok(i) = list.binary_search(&x) {
+        list.remove(i);
+    }
+}
+
+fn contains(users: &HashMap<UserIdx, UserRelations>, user: UserIdx, x: UserIdx) -> [bool; 2] {
+    users.get(&user).map_or([false; 2], |r| {
+        [
+            r.mutes.binary_search(&x).is_ok(),
+            r.blocks.binary_search(&x).is_ok(),
+        ]
+    })
+}
+
+impl UserRelations {
+    /// Whether the viewer these relations belong to should see tweets by `author`
+    #[inline]
+    pub fn hides(&self, author: UserIdx) -> bool {
+        self.mutes.binary_search(&author).is_ok()
+            || self.blocks.binary_search(&author).is_ok()
+            || self.blocked_by.binary_search(&author).is_ok()
+    }
+
+    /// Blocks hide profiles too, mutes only hide tweets from timelines
+    #[inline]
+    pub fn blocks_either_way(&self, other: UserIdx) -> bool {
+        self.blocks.binary_search(&other).is_ok() || self.blocked_by.binary_search(&other).is_ok()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.mutes.is_empty() && self.blocks.is_empty() && self.blocked_by.is_empty()
+    }
+}
+
+pub struct Relations {
+    users: RwLock<HashMap<UserIdx, UserRelations>>,
+    has_entry: Vec<AtomicU64>,
+    /// Appended under the `users` lock, so in the order changes applied.
+    /// `Datastore::expire_before` truncates it.
+    pub log: SharedPool<RelationChange>,
+    /// Mutes and blocks fail while shared readers are attached, see `crate::shm`
+    gate: Option<Arc<ReaderGate>>,
+}
+
+/// Read access for one fetch, see `Relations::read`
+pub struct RelationsView<'a> {
+    relations: &'a Relations,
+    users: Option<RwLockReadGuard<'a, HashMap<UserIdx, UserRelations>>>,
+}
+
+impl<'a> RelationsView<'a> {
+    /// `None` if `viewer` doesn't hide anyone
+    #[inline]
+    pub fn for_viewer(&mut self, viewer: UserIdx) -> Option<&UserRelations> {
+        if !self.relations.has_entry(viewer) {
+            return None;
+        }
+        let relations = self.relations;
+        self.users
+            .get_or_insert_with(|| relations.users.read().unwrap())
+            .get(&viewer)
+    }
+}
+
+impl Relations {
+    pub fn new(num_users: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            users: RwLock::new(HashMap::new()),
+            has_entry: (0..num_users.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            log: SharedPool::new()?,
+            gate: None,
+        })
+    }
+
+    pub fn gated(self, gate: Arc<ReaderGate>) -> Self {
+        Self {
+            gate: Some(gate),
+            ..self
+        }
+    }
+
+    #[inline]
+    fn has_entry(&self, user: UserIdx) -> bool {
+        let word = self.has_entry[user as usize / 64].load(Ordering::SeqCst);
+        word & (1 << (user % 64)) != 0
+    }
+
+    /// Only takes the lock if a viewer passed to `for_viewer` has relations
+    pub fn read(&self) -> RelationsView<'_> {
+        RelationsView {
+            relations: self,
+            users: None,
+        }
+    }
+
+    fn update(
+        &self,
+        users: &mut HashMap<UserIdx, UserRelations>,
+        user: UserIdx,
+        f: impl FnOnce(&mut UserRelations),
+    ) {
+        let entry = users.entry(user).or_default();
+        f(entry);
+        let bit = 1 << (user % 64);
+        let word = &self.has_entry[user as usize / 64];
+        if entry.is_empty() {
+            users.remove(&user);
+            word.fetch_and(!bit, Ordering::SeqCst);
+        } else {
+            entry.mutes.shrink_to_fit();
+            entry.blocks.shrink_to_fit();
+            entry.blocked_by.shrink_to_fit();
+            word.fetch_or(bit, Ordering::SeqCst);
+        }
+    }
+
+    /// `viewer` stops seeing tweets by `target`. Like the others, returns
+    /// false if it changed nothing, the change log is full or it's gated and
+    /// readers are attached.
+    pub fn mute(&self, viewer: UserIdx, target: UserIdx) -> bool {
+        self.apply(RelationChange::Mute { viewer, target })
+    }
+
+    pub fn unmute(&self, viewer: UserIdx, target: UserIdx) -> bool {
+        self.apply(RelationChange::Unmute { viewer, target })
+    }
+
+    /// Neither user sees the other's tweets
+    pub fn block(&self, blocker: UserIdx, target: UserIdx) -> bool {
+        self.apply(RelationChange::Block { blocker, target })
+    }
+
+    pub fn unblock(&self, blocker: UserIdx, target: UserIdx) -> bool {
+        self.apply(RelationChange::Unblock { blocker, target })
+    }
+
+    /// Applies and logs a change, the way a replica replays the leader's log
+    pub fn apply(&self, change: RelationChange) -> bool {
+        let mut users = self.users.write().unwrap();
+        let (user, target) = change.users();
+        let [muted, blocked] = contains(&users, user, target);
+        let changes = match change {
+            RelationChange::Mute { .. } => !muted,
+            RelationChange::Unmute { .. } => muted,
+            RelationChange::Block { .. } => !blocked,
+            RelationChange::Unblock { .. } => blocked,
+        };
+        if !changes {
+            return false;
+        }
+        let restricts = matches!(
+            change,
+            RelationChange::Mute { .. } | RelationChange::Block { .. }
+        );
+        if restricts && self.gate.as_ref().is_some_and(|g| !g.restrict()) {
+            return false;
+        }
+        if self.log.push(change).is_err() {
+            return false;
+        }
+        match change {
+            RelationChange::Mute { .. } => {
+                self.update(&mut users, user, |r| insert(&mut r.mutes, target))
+            }
+            RelationChange::Unmute { .. } => {
+                self.update(&mut users, user, |r| remove(&mut r.mutes, target))
+            }
+            RelationChange::Block { .. } => {
+                self.update(&mut users, user, |r| insert(&mut r.blocks, target));
+                self.update(&mut users, target, |r| insert(&mut r.blocked_by, user));
+            }
+            RelationChange::Unblock { .. } => {
+                self.update(&mut users, user, |r| remove(&mut r.blocks, target));
+                self.update(&mut users, target, |r| remove(&mut r.blocked_by, user));
+            }
+        }
+        true
+    }
+
+    pub fn hides(&self, viewer: UserIdx, author: UserIdx) -> bool {
+        self.read()
+            .for_viewer(viewer)
+            .is_some_and(|r| r.hides(author))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::*;
+    use crate::import::GraphBuilder;
+    use crate::timeline::TimelineFetcher;
+
+    #[test]
+    fn mutes_and_blocks() {
+        let mut builder = GraphBuilder::new(5, true);
+        for f in 1..5 {
+            builder.add_edge(0, f);
+        }
+        builder.add_edge(3, 0);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        for ts in 1..=12 {
+            data.add_tweet(Tweet::dummy(Timestamp::new(ts).unwrap()), ts % 5)
+                .unwrap();
+        }
+        let authors = |fetcher: &mut TimelineFetcher, viewer, max_len| -> Vec<u32> {
+            let timeline = fetcher.for_user(&data, viewer, max_len, START_TIME);
+            timeline.tweets.iter().map(|t| t.ts.get() % 5).collect()
+        };
+        let mut fetcher = TimelineFetcher::default();
+        assert_eq!(authors(&mut fetcher, 0, 4), [2, 1, 4, 3]);
+
+        assert!(data.relations.mute(0, 1));
+        assert!(data.relations.mute(0, 4));
+        assert!(!data.relations.mute(0, 4));
+        assert!(data.relations.hides(0, 4));
+        assert!(!data.relations.hides(4, 0));
+        // still fills max_len from the remaining followees
+        assert_eq!(authors(&mut fetcher, 0, 4), [2, 3, 2, 3]);
+
+        assert!(data.relations.unmute(0, 1));
+        assert!(data.relations.unmute(0, 4));
+        assert!(data.relations.block(3, 0));
+        assert_eq!(authors(&mut fetcher, 0, 3), [2, 1, 4]);
+        assert!(authors(&mut fetcher, 3, 10).is_empty());
+
+        assert!(data.relations.unblock(3, 0));
+        assert!(!data.relations.unblock(3, 0));
+        assert!(data.relations.users.read().unwrap().is_empty());
+        assert_eq!(data.relations.log.len(), 6);
+        assert_eq!(authors(&mut fetcher, 3, 10), [0, 0]);
+    }
+}
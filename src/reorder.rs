@@ -0,0 +1,309 @@
+//! Offline relabelling of users so that feeds read together sit together in memory.
+//!
+//! `Datastore::feeds` is indexed by `UserIdx`, so with raw SNAP ids the feed heads
+//! a timeline fetch seeds its heap from are scattered all over a 300MB array.
+//! Relabelling the graph moves hot and co-followed accounts next to each other.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bytemuck::cast_slice;
+
+use crate::data::*;
+use crate::import::BakedGraph;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reordering {
+    /// Most followed accounts first, so the feeds everyone reads share cache lines
+    Degree,
+    /// Reverse Cuthill-McKee over the follow edges, grouping accounts followed together
+    Rcm,
+}
+
+impl FromStr for Reordering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "degree" => Ok(Reordering::Degree),
+            "rcm" => Ok(Reordering::Rcm),
+            _ => Err(format!("unknown reordering {s:?}")),
+        }
+    }
+}
+
+/// Maps the original id of each user to its new id
+pub struct Permutation {
+    pub new_of_old: Vec<UserIdx>,
+}
+
+impl Permutation {
+    pub fn compute(graph: &Graph, reordering: Reordering) -> Self {
+        let order = match reordering {
+            Reordering::Degree => degree_order(graph),
+            Reordering::Rcm => rcm_order(graph),
+        };
+        Self::from_order(&order)
+    }
+
+    /// `order` lists the old ids in their new order
+    fn from_order(order: &[UserIdx]) -> Self {
+        let mut new_of_old = vec![0; order.len()];
+        for (new, old) in order.iter().enumerate() {
+            new_of_old[*old as usize] = new as UserIdx;
+        }
+        Self { new_of_old }
+    }
+
+    pub fn old_of_new(&self) -> Vec<UserIdx> {
+        let mut old_of_new = vec![0; self.new_of_old.len()];
+        for (old, new) in self.new_of_old.iter().enumerate() {
+            old_of_new[*new as usize] = old as UserIdx;
+        }
+        old_of_new
+    }
+
+    /// Relabels every user. Follow lists come out sorted so seeding the heap
+    /// walks `Datastore::feeds` in address order.
+    pub fn apply(&self, graph: &Graph) -> BakedGraph {
+        let mut users: Vec<User> = Vec::with_capacity(graph.users.len());
+        let mut follows: Vec<UserIdx> = Vec::with_capacity(graph.follows.len());
+        for old in self.old_of_new() {
+            let user = &graph.users[old as usize];
+            let start = follows.len();
+            follows.extend(
+                graph
+                    .user_follows(user)
+                    .iter()
+                    .map(|f| self.new_of_old[*f as usize]),
+            );
+            follows[start..].sort_unstable();
+            users.push(User {
+                follows_idx: start,
+                ..*user
+            });
+        }
+        BakedGraph { users, follows }
+    }
+
+    /// Writes `permutation.bin` next to the relabelled graph so results can be mapped back.
+    /// The header ties it to `relabelled`, a later bake over the same directory
+    /// without a reordering leaves it stale and `LoadGraph` ignores it.
+    pub fn save(&self, dir: &Path, relabelled: &Graph) -> io::Result<()> {
+        let mut f = File::create(dir.join("permutation.bin"))?;
+        for word in [
+            PERMUTATION_MAGIC,
+            relabelled.users.len() as u64,
+            relabelled.follows.len() as u64,
+            fingerprint(relabelled),
+        ] {
+            f.write_all(&word.to_le_bytes())?;
+        }
+        f.write_all(cast_slice(&self.new_of_old[..]))
+    }
+}
+
+const PERMUTATION_MAGIC: u64 = u64::from_le_bytes(*b"twtperm1");
+/// Magic, users, follows and fingerprint of the relabelled graph
+const PERMUTATION_HEADER: usize = 32;
+
+/// Cheap identity for a graph, from its sizes and a sample of its users
+pub fn fingerprint(graph: &Graph) -> u64 {
+    // FNV-1a
+    let mut hash = 0xcbf29ce484222325u64;
+    let mut add = |x: u64| {
+        for b in x.to_le_bytes() {
+            hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    };
+    add(graph.users.len() as u64);
+    add(graph.follows.len() as u64);
+    let step = (graph.users.len() / 4096).max(1);
+    for user in graph.users.iter().step_by(step) {
+        add(user.follows_idx as u64);
+        add(user.num_follows as u64);
+        add(user.num_followers as u64);
+    }
+    hash
+}
+
+/// The original to relabelled ids in a saved `permutation.bin`, if it was
+/// saved for `graph`
+pub fn saved_permutation<'a>(bytes: &'a [u8], graph: &Graph) -> Option<&'a [UserIdx]> {
+    let (header, entries) = bytes.split_at_checked(PERMUTATION_HEADER)?;
+    let word = |i: usize| u64::from_le_bytes(header[i * 8..][..8].try_into().unwrap());
+    let matches = word(0) == PERMUTATION_MAGIC
+        && word(1) == graph.users.len() as u64
+        && word(2) == graph.follows.len() as u64
+        && word(3) == fingerprint(graph)
+        && entries.len() == graph.users.len() * std::mem::size_of::<UserIdx>();
+    matches.then(|| cast_slice(entries))
+}
+
+/// Distinct cache lines and pages of `Datastore::feeds` a timeline fetch
+/// reads seeding its heap, averaged over viewers
+#[derive(Clone, Copy, Debug)]
+pub struct FeedLocality {
+    pub lines: f64,
+    pub pages: f64,
+}
+
+/// `feed_of` maps a followed user to the feed index it would have, e.g. back
+/// to the original id through `Permutation::old_of_new` to compare orders
+/// on the same graph
+pub fn feed_locality(
+    graph: &Graph,
+    viewers: &[UserIdx],
+    feed_of: impl Fn(UserIdx) -> UserIdx,
+) -> FeedLocality {
+    const FEEDS_PER_LINE: usize = 64 / std::mem::size_of::<AtomicChain>();
+    const FEEDS_PER_PAGE: usize = 4096 / std::mem::size_of::<AtomicChain>();
+    let (mut lines, mut pages) = (0, 0);
+    let mut feeds = vec![];
+    for viewer in viewers {
+        feeds.clear();
+        let follows = graph.user_follows(&graph.users[*viewer as usize]);
+        feeds.extend(follows.iter().map(|f| feed_of(*f) as usize));
+        feeds.sort_unstable();
+        let distinct = |per: usize| {
+            let mut last = None;
+            feeds
+                .iter()
+                .filter(|f| last.replace(**f / per) != Some(**f / per))
+                .count()
+        };
+        lines += distinct(FEEDS_PER_LINE);
+        pages += distinct(FEEDS_PER_PAGE);
+    }
+    let n = viewers.len().max(1) as f64;
+    FeedLocality {
+        lines: lines as f64 / n,
+        pages: pages as f64 / n,
+    }
+}
+
+fn degree_order(graph: &Graph) -> Vec<UserIdx> {
+    let mut order: Vec<UserIdx> = (0..graph.users.len() as UserIdx).collect();
+    order.sort_by_key(|u| std::cmp::Reverse(graph.users[*u as usize].num_followers));
+    order
+}
+
+fn degree(user: &User) -> u64 {
+    user.num_follows as u64 + user.num_followers as u64
+}
+
+/// Breadth first from the lowest degree unvisited user, visiting followees in
+/// increasing degree order, then reversed. Only follow edges are walked since
+/// those are what a timeline fetch touches.
+fn rcm_order(graph: &Graph) -> Vec<UserIdx> {
+    let n = graph.users.len();
+    let mut starts: Vec<UserIdx> = (0..n as UserIdx).collect();
+    starts.sort_by_key(|u| degree(&graph.users[*u as usize]));
+
+    let mut visited = vec![false; n];
+    let mut order: Vec<UserIdx> = Vec::with_capacity(n);
+    let mut queue = VecDeque::new();
+    let mut neighbours = vec![];
+    for start in starts {
+        if visited[start as usize] {
+            continue;
+        }
+        visited[start as usize] = true;
+        queue.push_back(start);
+        while let Some(u) = queue.pop_front() {
+            order.push(u);
+            neighbours.clear();
+            neighbours.extend(
+                graph
+                    .user_follows(&graph.users[u as usize])
+                    .iter()
+                    .filter(|f| !visited[**f as usize]),
+            );
+            neighbours.sort_by_key(|f| degree(&graph.users[*f as usize]));
+            for f in &neighbours {
+                // lists can contain duplicate edges
+                if !visited[*f as usize] {
+                    visited[*f as usize] = true;
+                    queue.push_back(*f);
+                }
+            }
+        }
+    }
+    order.reverse();
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+
+    fn check(baked: &BakedGraph, reordering: Reordering) -> Permutation {
+        let graph = baked.graph();
+        let perm = Permutation::compute(&graph, reordering);
+        let mut seen = perm.new_of_old.clone();
+        seen.sort();
+        assert_eq!(seen, (0..graph.users.len() as UserIdx).collect::<Vec<_>>());
+
+        let relabelled = perm.apply(&graph);
+        let new_graph = relabelled.graph();
+        for (old, user) in graph.users.iter().enumerate() {
+            let new_user = &new_graph.users[perm.new_of_old[old] as usize];
+            assert_eq!(new_user.num_followers, user.num_followers);
+            let mut expected: Vec<UserIdx> = graph
+                .user_follows(user)
+                .iter()
+                .map(|f| perm.new_of_old[*f as usize])
+                .collect();
+            expected.sort();
+            assert_eq!(new_graph.user_follows(new_user), &expected[..]);
+        }
+        perm
+    }
+
+    #[test]
+    fn relabelling() {
+        let mut builder = GraphBuilder::new(6, true);
+        for (a, b) in [(0, 5), (1, 5), (2, 5), (3, 4), (0, 4), (4, 5), (5, 3)] {
+            builder.add_edge(a, b);
+        }
+        let baked = builder.finish();
+
+        let perm = check(&baked, Reordering::Degree);
+        assert_eq!(perm.new_of_old[5], 0);
+        assert_eq!(perm.new_of_old[4], 1);
+        check(&baked, Reordering::Rcm);
+
+        // everyone follows someone, and six feeds fit in a cache line whichever order
+        let relabelled = perm.apply(&baked.graph());
+        let viewers: Vec<UserIdx> = (0..6).collect();
+        let locality = feed_locality(&relabelled.graph(), &viewers, |f| f);
+        assert_eq!((locality.lines, locality.pages), (1.0, 1.0));
+    }
+
+    #[test]
+    fn stale_permutation() {
+        let mut builder = GraphBuilder::new(6, true);
+        for (a, b) in [(0, 5), (1, 5), (2, 5), (3, 4), (0, 4)] {
+            builder.add_edge(a, b);
+        }
+        let baked = builder.finish();
+        let perm = Permutation::compute(&baked.graph(), Reordering::Degree);
+        let relabelled = perm.apply(&baked.graph());
+        let dir = std::env::temp_dir().join(format!("perm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        relabelled.save(&dir).unwrap();
+        perm.save(&dir, &relabelled.graph()).unwrap();
+        let loader = crate::generate::LoadGraph::open(&dir).unwrap();
+        assert_eq!(loader.permutation(), Some(&perm.new_of_old[..]));
+
+        // baked again without a reordering, same sizes
+        baked.save(&dir).unwrap();
+        let loader = crate::generate::LoadGraph::open(&dir).unwrap();
+        assert!(loader.permutation().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,670 @@
+//! Leader/follower replication of the tweet stream.
+//!
+//! The tweet pool is append-only and each `ChainedTweet` records its author,
+//! so the pool already is a log of every feed update: replaying it in index
+//! order on a follower reproduces the same tweet indices, chains and feed
+//! heads. A follower connects with the index it wants next and the leader
+//! streams from there, so catching up after a reconnect is the same as
+//! starting from scratch.
+//!
+//! Edits are appended to their own pool, which is replayed the same way so
+//! edit indices match too. Deletes and edits then change a tweet's version in
+//! place, so they're logged in `Datastore::version_log` and streamed as
+//! updates. Each tweet record carries its version as of the edits sent so far,
+//! and an update is only sent once the tweet and edit it refers to have been.
+//!
+//! Protecting accounts and approving or removing their followers is logged in
+//! `Visibility::log` and streamed the same way, independent of the tweets.
+//! So are mutes and blocks, from `Relations::log`. Expiry truncates every
+//! log, so a follower that falls behind one is disconnected.
+//!
+//! The follower sends `u64 next_tweet, u64 next_edit, u64 next_update,
+//! u64 next_visibility, u64 next_relation` and the leader sends batches of
+//! `u64 leader_len, u32 edits, u32 tweets, u32 updates, u32 visibility_changes,
+//! u32 relation_changes` followed by that many fixed size records of each, in
+//! that order. Positions are `u64` since the ids in records wrap. Empty
+//! batches are heartbeats so lag stays fresh while idle.
+//!
+//! Pending follow requests, and likes and retweets after a tweet was sent
+//! don't reach followers. Mentions are rebuilt from the log.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::data::*;
+use crate::pool::id_cmp;
+use crate::relations::RelationChange;
+use crate::visibility::VisibilityChange;
+
+/// Records per batch, bounds how long a catching-up follower waits to see progress
+const MAX_BATCH: usize = 4096;
+const HEARTBEAT: Duration = Duration::from_millis(100);
+const POLL: Duration = Duration::from_micros(200);
+
+const RECORD_BYTES: usize = 4 * 8 + TWEET_BYTES;
+const EDIT_BYTES: usize = 4 * 3 + TWEET_BYTES;
+const UPDATE_BYTES: usize = 8;
+const VISIBILITY_BYTES: usize = 12;
+const RELATION_BYTES: usize = 12;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Whether the edit `version` points at is among the first `edits_sent`
+fn edit_sent(version: u32, edits_sent: usize) -> bool {
+    id_cmp(version.wrapping_sub(1), edits_sent as u32).is_lt()
+}
+
+/// `version` of a live tweet going back to the newest edit among the first
+/// `edits_sent`, the rest arrive as updates
+fn version_before(pinned: &Pinned, mut version: u32, edits_sent: usize) -> u32 {
+    while version != DELETED && version != ORIGINAL && !edit_sent(version, edits_sent) {
+        version = pinned.edit(version).prev_version;
+    }
+    version
+}
+
+fn encode_record(out: &mut Vec<u8>, chained: &ChainedTweet, version: u32) {
+    // engagement as of sending, later likes and retweets aren't streamed
+    let t = chained.tweet.load();
+    let (prev_ts, prev_idx) = chained
+        .prev_tweet
+        .map_or((0, 0), |l| (l.ts.get(), l.tweet_idx));
+    for x in [chained.author, t.ts.get(), t.likes, t.quotes, t.retweets] {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+    out.extend_from_slice(&t.content);
+    out.extend_from_slice(&prev_ts.to_le_bytes());
+    out.extend_from_slice(&prev_idx.to_le_bytes());
+    out.extend_from_slice(&version.to_le_bytes());
+}
+
+fn decode_record(rec: &[u8; RECORD_BYTES]) -> io::Result<ChainedTweet> {
+    let word = |i: usize| u32::from_le_bytes(rec[i * 4..][..4].try_into().unwrap());
+    let tail =
+        |i: usize| u32::from_le_bytes(rec[20 + TWEET_BYTES + i * 4..][..4].try_into().unwrap());
+    let ts = Timestamp::new(word(1)).ok_or_else(|| invalid("zero timestamp".into()))?;
+    let tweet = Tweet {
+        content: rec[20..20 + TWEET_BYTES].try_into().unwrap(),
+        ts,
+        likes: word(2),
+        quotes: word(3),
+        retweets: word(4),
+    };
+    let prev_tweet = Timestamp::new(tail(0)).map(|ts| NextLink {
+        ts,
+        tweet_idx: tail(1),
+    });
+    let chained = ChainedTweet::new(tweet, prev_tweet, word(0));
+    chained.version.store(tail(2), Ordering::SeqCst);
+    Ok(chained)
+}
+
+fn encode_edit(out: &mut Vec<u8>, edit: &TweetEdit) {
+    out.extend_from_slice(&edit.content);
+    out.extend_from_slice(&edit.edited_at.get().to_le_bytes());
+    out.extend_from_slice(&edit.prev_version.to_le_bytes());
+    out.extend_from_slice(&edit.tweet_idx.to_le_bytes());
+}
+
+fn decode_edit(rec: &[u8; EDIT_BYTES]) -> io::Result<TweetEdit> {
+    let word = |i: usize| u32::from_le_bytes(rec[TWEET_BYTES + i * 4..][..4].try_into().unwrap());
+    Ok(TweetEdit {
+        tweet_idx: word(2),
+        content: rec[..TWEET_BYTES].try_into().unwrap(),
+        edited_at: Timestamp::new(word(0)).ok_or_else(|| invalid("zero timestamp".into()))?,
+        prev_version: word(1),
+    })
+}
+
+fn encode_update(out: &mut Vec<u8>, update: &VersionUpdate) {
+    out.extend_from_slice(&update.tweet_idx.to_le_bytes());
+    out.extend_from_slice(&update.version.to_le_bytes());
+}
+
+fn decode_update(rec: &[u8; UPDATE_BYTES]) -> VersionUpdate {
+    let word = |i: usize| u32::from_le_bytes(rec[i * 4..][..4].try_into().unwrap());
+    VersionUpdate {
+        tweet_idx: word(0),
+        version: word(1),
+    }
+}
+
+fn encode_visibility(out: &mut Vec<u8>, change: &VisibilityChange) {
+    let (kind, author, follower) = match *change {
+        VisibilityChange::Protect(author) => (0u32, author, 0),
+        VisibilityChange::Unprotect(author) => (1, author, 0),
+        VisibilityChange::Approve { author, follower } => (2, author, follower),
+        VisibilityChange::Remove { author, follower } => (3, author, follower),
+    };
+    for x in [kind, author, follower] {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+fn decode_visibility(rec: &[u8; VISIBILITY_BYTES]) -> io::Result<VisibilityChange> {
+    let word = |i: usize| u32::from_le_bytes(rec[i * 4..][..4].try_into().unwrap());
+    let (author, follower) = (word(1), word(2));
+    Ok(match word(0) {
+        0 => VisibilityChange::Protect(author),
+        1 => VisibilityChange::Unprotect(author),
+        2 => VisibilityChange::Approve { author, follower },
+        3 => VisibilityChange::Remove { author, follower },
+        kind => return Err(invalid(format!("unknown visibility change {kind}"))),
+    })
+}
+
+fn encode_relation(out: &mut Vec<u8>, change: &RelationChange) {
+    let kind = match change {
+        RelationChange::Mute { .. } => 0u32,
+        RelationChange::Unmute { .. } => 1,
+        RelationChange::Block { .. } => 2,
+        RelationChange::Unblock { .. } => 3,
+    };
+    let (user, target) = change.users();
+    for x in [kind, user, target] {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+fn decode_relation(rec: &[u8; RELATION_BYTES]) -> io::Result<RelationChange> {
+    let word = |i: usize| u32::from_le_bytes(rec[i * 4..][..4].try_into().unwrap());
+    let (user, target) = (word(1), word(2));
+    Ok(match word(0) {
+        0 => RelationChange::Mute {
+            viewer: user,
+            target,
+        },
+        1 => RelationChange::Unmute {
+            viewer: user,
+            target,
+        },
+        2 => RelationChange::Block {
+            blocker: user,
+            target,
+        },
+        3 => RelationChange::Unblock {
+            blocker: user,
+            target,
+        },
+        kind => return Err(invalid(format!("unknown relation change {kind}"))),
+    })
+}
+
+pub struct Leader<'a, 'g> {
+    data: &'a Datastore<'g>,
+}
+
+impl<'a, 'g> Leader<'a, 'g> {
+    pub fn new(data: &'a Datastore<'g>) -> Self {
+        Self { data }
+    }
+
+    /// Streams edits, tweets, updates, visibility and relation changes to one
+    /// follower until it disconnects
+    pub fn serve_stream(&self, mut reader: impl Read, writer: impl Write) -> io::Result<()> {
+        let data = self.data;
+        let mut from = [0u8; 40];
+        reader.read_exact(&mut from)?;
+        let word = |i: usize| u64::from_le_bytes(from[i * 8..][..8].try_into().unwrap()) as usize;
+        let (mut next, mut next_edit, mut next_update, mut next_visibility, mut next_relation) =
+            (word(0), word(1), word(2), word(3), word(4));
+        let log = &data.version_log;
+        let visibility_log = &data.visibility.log;
+        let relation_log = &data.relations.log;
+        if next > data.tweets.len()
+            || next_edit > data.edits.len()
+            || next_update > log.len()
+            || next_visibility > visibility_log.len()
+            || next_relation > relation_log.len()
+        {
+            return Err(invalid(format!(
+                "follower wants tweet {next}, edit {next_edit}, update {next_update}, \
+                 visibility change {next_visibility} and relation change {next_relation} \
+                 but leader only has {}, {}, {}, {} and {}",
+                data.tweets.len(),
+                data.edits.len(),
+                log.len(),
+                visibility_log.len(),
+                relation_log.len()
+            )));
+        }
+
+        let mut writer = BufWriter::with_capacity(1 << 16, writer);
+        let mut batch = Vec::with_capacity(
+            28 + MAX_BATCH
+                * (EDIT_BYTES + RECORD_BYTES + UPDATE_BYTES + VISIBILITY_BYTES + RELATION_BYTES),
+        );
+        let mut last_sent = Instant::now();
+        loop {
+            // not across the write, a slow follower mustn't hold up expiry
+            let pinned = data.pin();
+            // expiry releases the logs along with the tweets, once this is unpinned
+            let (updates, visibility, relations) =
+                unsafe { (log.view(), visibility_log.view(), relation_log.view()) };
+            if next < pinned.tweets.start()
+                || next_edit < pinned.edits.start()
+                || next_update < updates.start()
+                || next_visibility < visibility.start()
+                || next_relation < relations.start()
+            {
+                return Err(invalid(format!(
+                    "tweet {next}, edit {next_edit}, update {next_update}, visibility change \
+                     {next_visibility} or relation change {next_relation} expired before the \
+                     follower got it"
+                )));
+            }
+            // updates first, the tweets and edits they refer to were pushed before them
+            let updates_len = log.len();
+            let edit_end = data.edits.len().min(next_edit + MAX_BATCH);
+            let len = data.tweets.len();
+            let end = len.min(next + MAX_BATCH);
+            let mut update_end = next_update;
+            while update_end < updates_len.min(next_update + MAX_BATCH) {
+                let update = updates.get(update_end).unwrap();
+                let sent = update.version == DELETED || edit_sent(update.version, edit_end);
+                if !id_cmp(update.tweet_idx, end as u32).is_lt() || !sent {
+                    break;
+                }
+                update_end += 1;
+            }
+            let visibility_end = visibility_log.len().min(next_visibility + MAX_BATCH);
+            let relation_end = relation_log.len().min(next_relation + MAX_BATCH);
+            if end == next
+                && edit_end == next_edit
+                && update_end == next_update
+                && visibility_end == next_visibility
+                && relation_end == next_relation
+                && last_sent.elapsed() < HEARTBEAT
+            {
+                drop(pinned);
+                thread::sleep(POLL);
+                continue;
+            }
+
+            batch.clear();
+            batch.extend_from_slice(&(len as u64).to_le_bytes());
+            for x in [
+                edit_end - next_edit,
+                end - next,
+                update_end - next_update,
+                visibility_end - next_visibility,
+                relation_end - next_relation,
+            ] {
+                batch.extend_from_slice(&(x as u32).to_le_bytes());
+            }
+            for i in next_edit..edit_end {
+                encode_edit(&mut batch, pinned.edits.get(i).unwrap());
+            }
+            for i in next..end {
+                let chained = pinned.tweets.get(i).unwrap();
+                let version = chained.version.load(Ordering::SeqCst);
+                let version = version_before(&pinned, version, edit_end);
+                encode_record(&mut batch, chained, version);
+            }
+            for i in next_update..update_end {
+                encode_update(&mut batch, updates.get(i).unwrap());
+            }
+            for i in next_visibility..visibility_end {
+                encode_visibility(&mut batch, visibility.get(i).unwrap());
+            }
+            for i in next_relation..relation_end {
+                encode_relation(&mut batch, relations.get(i).unwrap());
+            }
+            drop(pinned);
+            writer.write_all(&batch)?;
+            writer.flush()?;
+            next = end;
+            next_edit = edit_end;
+            next_update = update_end;
+            next_visibility = visibility_end;
+            next_relation = relation_end;
+            last_sent = Instant::now();
+        }
+    }
+
+    /// Serves forever, one thread per follower
+    pub fn serve_unix(&self, listener: UnixListener) -> io::Result<()> {
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                let stream = stream?;
+                s.spawn(move || {
+                    if let Err(e) = self.serve_stream(&stream, &stream) {
+                        eprintln!("follower disconnected: {e}");
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A read replica. `data` can serve `TimelineFetcher` reads from other threads
+/// while `replicate` applies the stream.
+pub struct Follower<'g> {
+    pub data: Datastore<'g>,
+    /// Leader's tweet count as of the last batch
+    leader_len: AtomicUsize,
+    /// Position in the leader's `version_log`
+    updates_applied: AtomicUsize,
+    /// Position in the leader's `Visibility::log`
+    visibility_applied: AtomicUsize,
+    /// Position in the leader's `Relations::log`
+    relations_applied: AtomicUsize,
+}
+
+impl<'g> Follower<'g> {
+    pub fn new(graph: Graph<'g>) -> io::Result<Self> {
+        Ok(Self {
+            data: Datastore::new(graph)?,
+            leader_len: AtomicUsize::new(0),
+            updates_applied: AtomicUsize::new(0),
+            visibility_applied: AtomicUsize::new(0),
+            relations_applied: AtomicUsize::new(0),
+        })
+    }
+
+    /// Tweets the leader had that aren't applied yet, as of the last batch or heartbeat
+    pub fn lag(&self) -> usize {
+        self.leader_len
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.data.tweets.len())
+    }
+
+    /// Only one thread may apply at a time, like `Datastore::add_tweet`
+    fn apply(&self, chained: ChainedTweet) -> io::Result<()> {
+        let expected = self.data.tweets.len();
+        let author = chained.author;
+        if author as usize >= self.data.feeds.len() {
+            return Err(invalid(format!("unknown author {author}")));
+        }
+        let version = chained.version.load(Ordering::SeqCst);
+        let arrived = matches!(version, ORIGINAL | DELETED)
+            || self.data.pin().edits.position(version - 1).is_some();
+        if !arrived {
+            return Err(invalid(format!(
+                "tweet at edit {version} which hasn't arrived"
+            )));
+        }
+        let ts = chained.tweet.ts;
+        let deleted = chained.is_deleted();
+        let tweet_idx = self.data.tweets.push(chained)?;
+        debug_assert_eq!(tweet_idx, expected);
+        self.data.feeds[author as usize].set(NextLink {
+            ts,
+            tweet_idx: tweet_idx as TweetIdx,
+        });
+        if !deleted {
+            self.data.tweet_counts[author as usize].fetch_add(1, Ordering::SeqCst);
+        }
+        let pinned = self.data.pin();
+        let Some(chained) = pinned.tweets.get(tweet_idx) else {
+            // expired on this follower already
+            return Ok(());
+        };
+        let text = chained.tweet.text();
+        self.data.search.index(tweet_idx as TweetIdx, &text);
+        let version = chained.version.load(Ordering::SeqCst);
+        if version != ORIGINAL && version != DELETED {
+            let edit = pinned.edit(version);
+            self.data.search.index(tweet_idx as TweetIdx, &edit.text());
+        }
+        self.data.trends.record(ts, &text);
+        let tweet_idx = tweet_idx as TweetIdx;
+        self.data
+            .notifications
+            .record_mentions(author, tweet_idx, ts, &text)
+    }
+
+    fn apply_update(&self, update: VersionUpdate) -> io::Result<()> {
+        if !id_cmp(update.tweet_idx, self.data.tweets.len() as u32).is_lt() {
+            return Err(invalid(format!(
+                "update for tweet {} which hasn't arrived",
+                update.tweet_idx
+            )));
+        }
+        let arrived = |version: u32| self.data.pin().edits.position(version - 1).is_some();
+        match update.version {
+            // false when it arrived deleted or already expired here
+            DELETED => self.data.delete_tweet(update.tweet_idx),
+            version if version == ORIGINAL || !arrived(version) => {
+                return Err(invalid(format!("update to bad version {version}")));
+            }
+            version => self.data.apply_edit(update.tweet_idx, version - 1),
+        };
+        self.updates_applied.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn apply_visibility(&self, change: VisibilityChange) -> io::Result<()> {
+        let users = self.data.feeds.len();
+        let (author, follower) = match change {
+            VisibilityChange::Protect(author) | VisibilityChange::Unprotect(author) => (author, 0),
+            VisibilityChange::Approve { author, follower }
+            | VisibilityChange::Remove { author, follower } => (author, follower),
+        };
+        if author as usize >= users || follower as usize >= users {
+            return Err(invalid(format!(
+                "visibility change {change:?} for unknown user"
+            )));
+        }
+        // replayed in the leader's order, so only a full log leaves it unapplied
+        if !self.data.visibility.apply(change) {
+            return Err(io::Error::other(format!("couldn't apply {change:?}")));
+        }
+        self.visibility_applied.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn apply_relation(&self, change: RelationChange) -> io::Result<()> {
+        let (user, target) = change.users();
+        let users = self.data.feeds.len();
+        if user as usize >= users || target as usize >= users {
+            return Err(invalid(format!(
+                "relation change {change:?} for unknown user"
+            )));
+        }
+        if !self.data.relations.apply(change) {
+            return Err(io::Error::other(format!("couldn't apply {change:?}")));
+        }
+        self.relations_applied.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Resumes from whatever this follower already has and applies the stream
+    /// until the leader goes away
+    pub fn replicate(&self, reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+        for cursor in [
+            self.data.tweets.len(),
+            self.data.edits.len(),
+            self.updates_applied.load(Ordering::SeqCst),
+            self.visibility_applied.load(Ordering::SeqCst),
+            self.relations_applied.load(Ordering::SeqCst),
+        ] {
+            writer.write_all(&(cursor as u64).to_le_bytes())?;
+        }
+        writer.flush()?;
+
+        let mut reader = BufReader::with_capacity(1 << 16, reader);
+        let mut header = [0u8; 28];
+        let mut edit = [0u8; EDIT_BYTES];
+        let mut record = [0u8; RECORD_BYTES];
+        let mut update = [0u8; UPDATE_BYTES];
+        let mut visibility = [0u8; VISIBILITY_BYTES];
+        let mut relation = [0u8; RELATION_BYTES];
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let leader_len = u64::from_le_bytes(header[..8].try_into().unwrap());
+            let word = |i: usize| u32::from_le_bytes(header[8 + i * 4..][..4].try_into().unwrap());
+            let (edits, count, updates) = (word(0), word(1), word(2));
+            let (visibility_changes, relation_changes) = (word(3), word(4));
+            for _ in 0..edits {
+                reader.read_exact(&mut edit)?;
+                // followers don't edit, so indices line up with the leader's
+                self.data.edits.push(decode_edit(&edit)?)?;
+            }
+            for _ in 0..count {
+                reader.read_exact(&mut record)?;
+                self.apply(decode_record(&record)?)?;
+            }
+            for _ in 0..updates {
+                reader.read_exact(&mut update)?;
+                self.apply_update(decode_update(&update))?;
+            }
+            for _ in 0..visibility_changes {
+                reader.read_exact(&mut visibility)?;
+                self.apply_visibility(decode_visibility(&visibility)?)?;
+            }
+            for _ in 0..relation_changes {
+                reader.read_exact(&mut relation)?;
+                self.apply_relation(decode_relation(&relation)?)?;
+            }
+            self.leader_len.store(leader_len as usize, Ordering::SeqCst);
+        }
+    }
+
+    pub fn replicate_unix(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let stream = UnixStream::connect(path)?;
+        self.replicate(&stream, &stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+    use crate::timeline::TimelineFetcher;
+    use crate::visibility::FollowStatus;
+
+    fn wait_until(f: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn catch_up_after_reconnect() {
+        let mut builder = GraphBuilder::new(4, true);
+        for f in 1..4 {
+            builder.add_edge(0, f);
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let follower = Follower::new(baked.graph()).unwrap();
+        let leader = Leader::new(&data);
+
+        let add = |from: u32, to: u32| {
+            for i in from..to {
+                let tweet = Tweet::new(Timestamp::new(i).unwrap(), &format!("tweet {i}"));
+                data.add_tweet(tweet, 1 + i % 3).unwrap();
+            }
+        };
+        let timeline = |data: &Datastore| -> Vec<String> {
+            let mut fetcher = TimelineFetcher::default();
+            let timeline = fetcher.for_user(data, 0, 100, START_TIME);
+            timeline
+                .tweets
+                .iter()
+                .map(|t| t.text().into_owned())
+                .collect()
+        };
+
+        let counts = |data: &Datastore| -> Vec<u32> {
+            data.tweet_counts
+                .iter()
+                .map(|c| c.load(Ordering::SeqCst))
+                .collect()
+        };
+
+        add(1, 20);
+        // before the follower ever connects
+        assert!(data.delete_tweet(3));
+        let edited_at = Timestamp::new(100).unwrap();
+        assert!(data.edit_tweet(4, "four, edited", edited_at));
+        assert!(data.edit_tweet(4, "four, edited again", edited_at));
+        assert!(data.set_protected(2, true));
+        assert!(data.visibility.remove_follower(2, 0));
+        assert!(data.relations.mute(0, 1));
+        assert!(data.relations.block(3, 0));
+        for round in 0..2 {
+            let (leader_end, follower_end) = UnixStream::pair().unwrap();
+            thread::scope(|s| {
+                s.spawn(|| leader.serve_stream(&leader_end, &leader_end));
+                let replica = s.spawn(|| follower.replicate(&follower_end, &follower_end));
+                wait_until(|| follower.data.tweets.len() == data.tweets.len());
+                if round == 0 {
+                    // while connected new tweets and deletes stream through
+                    add(20, 30);
+                    assert!(data.delete_tweet(5));
+                    assert!(data.delete_tweet(25));
+                    assert!(data.edit_tweet(6, "six, edited", edited_at));
+                    wait_until(|| follower.data.tweets.len() == 29);
+                    wait_until(|| follower.data.get_tweet(25).is_none());
+                    wait_until(|| follower.data.get_tweet(6).unwrap().text() == "six, edited");
+                    wait_until(|| !follower.data.visibility.can_see(0, 2));
+                    assert!(data.set_protected(3, true));
+                    wait_until(|| follower.data.visibility.is_protected(3));
+                    assert!(data.relations.unblock(3, 0));
+                    wait_until(|| !follower.data.relations.hides(0, 3));
+                } else {
+                    assert!(follower.data.get_tweet(35).is_none());
+                }
+                wait_until(|| follower.lag() == 0);
+                wait_until(|| {
+                    follower.visibility_applied.load(Ordering::SeqCst) == data.visibility.log.len()
+                });
+                wait_until(|| {
+                    follower.relations_applied.load(Ordering::SeqCst) == data.relations.log.len()
+                });
+                follower_end.shutdown(std::net::Shutdown::Both).unwrap();
+                replica.join().unwrap().unwrap();
+            });
+            assert_eq!(timeline(&follower.data), timeline(&data));
+            assert_eq!(counts(&follower.data), counts(&data));
+            // written while disconnected, the next round has to catch up from index 29
+            add(30 + round * 10, 40 + round * 10);
+            if round == 0 {
+                assert!(data.delete_tweet(35));
+                assert!(data.edit_tweet(30, "thirty, edited", edited_at));
+                assert!(data.edit_tweet(6, "six, edited again", edited_at));
+                assert_eq!(data.visibility.request_follow(0, 2), FollowStatus::Pending);
+                assert!(data.visibility.answer_request(2, 0, true));
+                assert!(data.set_protected(3, false));
+                assert!(data.relations.unmute(0, 1));
+                assert!(data.relations.mute(0, 2));
+            }
+        }
+        assert_eq!(follower.data.tweets.len(), 39);
+        for idx in [3, 5, 25, 35] {
+            assert!(follower.data.get_tweet(idx).is_none());
+        }
+        for idx in [4, 6, 30] {
+            let texts = |data: &Datastore| -> Vec<String> {
+                data.tweet_versions(idx)
+                    .iter()
+                    .map(|t| t.text().into_owned())
+                    .collect()
+            };
+            assert_eq!(texts(&follower.data), texts(&data));
+        }
+        assert_eq!(
+            follower.data.get_tweet(6).unwrap().text(),
+            "six, edited again"
+        );
+        assert!(follower.data.visibility.can_see(0, 2));
+        assert!(!follower.data.visibility.is_protected(3));
+        assert!(!follower.data.relations.hides(0, 1));
+        assert!(follower.data.relations.hides(0, 2));
+    }
+}
@@ -0,0 +1,692 @@
+//! Length-prefixed binary protocol for serving timelines over TCP or Unix sockets.
+//!
+//! Every frame is a little-endian `u32` length followed by that many bytes:
+//! a one byte opcode and its fields, all little-endian. Responses come back
+//! in request order so clients can pipeline as deep as they like, and the
+//! server only flushes once it has drained every request already buffered,
+//! so a pipelined burst is answered with a single write.
+//!
+//! A batch frame carries several requests as nested frames, after a `u32`
+//! count, and is answered by one batch frame of responses in the same order.
+//! Batches don't nest, and one whose responses wouldn't fit in a frame is
+//! answered with a single error instead.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use crate::data::*;
+use crate::timeline::{TimelineFetcher, MAX_TIMELINE_LEN};
+
+const OP_ADD_TWEET: u8 = 1;
+const OP_FOR_USER: u8 = 2;
+const OP_BATCH: u8 = 3;
+const OP_ERROR: u8 = 0xff;
+
+/// Frames bigger than this are rejected rather than allocated
+pub const MAX_FRAME: usize = 1 << 24;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Request {
+    AddTweet {
+        user: UserIdx,
+        content: String,
+    },
+    ForUser {
+        user: UserIdx,
+        max_len: u32,
+        after: Timestamp,
+    },
+    Batch(Vec<Request>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WireTweet {
+    pub ts: Timestamp,
+    pub likes: u32,
+    pub quotes: u32,
+    pub retweets: u32,
+    pub content: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Response {
+    Added { tweet_idx: TweetIdx, ts: Timestamp },
+    Timeline(Vec<WireTweet>),
+    Error(String),
+    Batch(Vec<Response>),
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Cursor over a received frame
+struct Frame<'a>(&'a [u8]);
+
+impl<'a> Frame<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("truncated frame"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn ts(&mut self) -> io::Result<Timestamp> {
+        Timestamp::new(self.u32()?).ok_or_else(|| invalid("zero timestamp"))
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = u16::from_le_bytes(self.bytes(2)?.try_into().unwrap());
+        Ok(String::from_utf8_lossy(self.bytes(len as usize)?).into_owned())
+    }
+
+    /// A nested frame inside a batch
+    fn frame(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    /// Nested frames after their count, decoded with `decode`
+    fn batch<T>(&mut self, decode: impl Fn(&[u8]) -> io::Result<T>) -> io::Result<Vec<T>> {
+        let n = self.u32()? as usize;
+        // each takes at least a length and an opcode
+        let mut items = Vec::with_capacity(n.min(self.0.len() / 5));
+        for _ in 0..n {
+            items.push(decode(self.frame()?)?);
+        }
+        Ok(items)
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+/// Strings over `u16::MAX` bytes are cut short at a char boundary
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    let mut len = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    out.extend_from_slice(&(len as u16).to_le_bytes());
+    out.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Reads one frame into `buf`, returning false on a clean EOF between frames
+fn read_frame(r: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid("frame too large"));
+    }
+    buf.resize(len, 0);
+    r.read_exact(buf)?;
+    Ok(true)
+}
+
+/// Appends a frame whose body `fill` appends, backpatching the length
+fn put_frame(out: &mut Vec<u8>, fill: impl FnOnce(&mut Vec<u8>)) {
+    let at = out.len();
+    out.extend_from_slice(&[0; 4]);
+    fill(out);
+    let len = (out.len() - at - 4) as u32;
+    out[at..at + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+fn write_frame(
+    w: &mut impl Write,
+    buf: &mut Vec<u8>,
+    fill: impl FnOnce(&mut Vec<u8>),
+) -> io::Result<()> {
+    buf.clear();
+    put_frame(buf, fill);
+    w.write_all(buf)
+}
+
+fn encode_batch(out: &mut Vec<u8>, reqs: &[Request]) {
+    out.push(OP_BATCH);
+    put_u32(out, reqs.len() as u32);
+    for req in reqs {
+        put_frame(out, |out| req.encode(out));
+    }
+}
+
+impl Request {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Request::AddTweet { user, content } => {
+                out.push(OP_ADD_TWEET);
+                put_u32(out, *user);
+                put_str(out, content);
+            }
+            Request::ForUser {
+                user,
+                max_len,
+                after,
+            } => {
+                out.push(OP_FOR_USER);
+                put_u32(out, *user);
+                put_u32(out, *max_len);
+                put_u32(out, after.get());
+            }
+            Request::Batch(reqs) => encode_batch(out, reqs),
+        }
+    }
+
+    fn decode(frame: &[u8]) -> io::Result<Self> {
+        let mut f = Frame(frame);
+        match f.u8()? {
+            OP_ADD_TWEET => Ok(Request::AddTweet {
+                user: f.u32()?,
+                content: f.str()?,
+            }),
+            OP_FOR_USER => Ok(Request::ForUser {
+                user: f.u32()?,
+                max_len: f.u32()?,
+                after: f.ts()?,
+            }),
+            OP_BATCH => Ok(Request::Batch(f.batch(|frame| {
+                match Request::decode(frame)? {
+                    Request::Batch(_) => Err(invalid("nested batch")),
+                    req => Ok(req),
+                }
+            })?)),
+            _ => Err(invalid("unknown request opcode")),
+        }
+    }
+}
+
+fn encode_tweet(out: &mut Vec<u8>, tweet: &Tweet) {
+    put_u32(out, tweet.ts.get());
+    put_u32(out, tweet.likes);
+    put_u32(out, tweet.quotes);
+    put_u32(out, tweet.retweets);
+    put_str(out, &tweet.text());
+}
+
+impl Response {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Response::Added { tweet_idx, ts } => {
+                out.push(OP_ADD_TWEET);
+                put_u32(out, *tweet_idx);
+                put_u32(out, ts.get());
+            }
+            Response::Timeline(tweets) => {
+                out.push(OP_FOR_USER);
+                put_u32(out, tweets.len() as u32);
+                for t in tweets {
+                    put_u32(out, t.ts.get());
+                    put_u32(out, t.likes);
+                    put_u32(out, t.quotes);
+                    put_u32(out, t.retweets);
+                    put_str(out, &t.content);
+                }
+            }
+            Response::Error(msg) => {
+                out.push(OP_ERROR);
+                put_str(out, msg);
+            }
+            Response::Batch(responses) => {
+                out.push(OP_BATCH);
+                put_u32(out, responses.len() as u32);
+                for response in responses {
+                    put_frame(out, |out| response.encode(out));
+                }
+            }
+        }
+    }
+
+    fn decode(frame: &[u8]) -> io::Result<Self> {
+        let mut f = Frame(frame);
+        match f.u8()? {
+            OP_ADD_TWEET => Ok(Response::Added {
+                tweet_idx: f.u32()?,
+                ts: f.ts()?,
+            }),
+            OP_FOR_USER => {
+                let n = f.u32()? as usize;
+                let mut tweets = Vec::with_capacity(n.min(MAX_FRAME / 18));
+                for _ in 0..n {
+                    tweets.push(WireTweet {
+                        ts: f.ts()?,
+                        likes: f.u32()?,
+                        quotes: f.u32()?,
+                        retweets: f.u32()?,
+                        content: f.str()?,
+                    });
+                }
+                Ok(Response::Timeline(tweets))
+            }
+            OP_ERROR => Ok(Response::Error(f.str()?)),
+            OP_BATCH => Ok(Response::Batch(f.batch(Response::decode)?)),
+            _ => Err(invalid("unknown response opcode")),
+        }
+    }
+}
+
+pub struct RpcServer<'a, 'g> {
+    data: &'a Datastore<'g>,
+    publisher: Publisher<'a, 'g>,
+    /// Largest response frame, `MAX_FRAME` like the client accepts
+    max_frame: usize,
+}
+
+impl<'a, 'g> RpcServer<'a, 'g> {
+    /// `now` should be at least the newest timestamp already in `data`
+    pub fn new(data: &'a Datastore<'g>, now: Timestamp) -> Self {
+        Self {
+            data,
+            publisher: Publisher::new(data, now),
+            max_frame: MAX_FRAME,
+        }
+    }
+
+    /// Handles one request, writing the response frame straight from the fetcher's
+    /// timeline so tweets aren't copied into a `Response` first
+    fn respond(
+        &self,
+        fetcher: &mut TimelineFetcher,
+        req: Request,
+        w: &mut impl Write,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let n_users = self.data.graph.users.len();
+        match req {
+            Request::AddTweet { user, .. } | Request::ForUser { user, .. }
+                if user as usize >= n_users =>
+            {
+                write_frame(w, buf, |out| {
+                    Response::Error(format!("unknown user {user}")).encode(out)
+                })
+            }
+            Request::AddTweet { user, content } => {
+                let response = match self.publisher.publish(user, &content) {
+                    Ok((tweet_idx, ts)) => Response::Added { tweet_idx, ts },
+                    Err(e) => Response::Error(e.to_string()),
+                };
+                write_frame(w, buf, |out| response.encode(out))
+            }
+            Request::ForUser {
+                user,
+                max_len,
+                after,
+            } => {
+                let max_len = (max_len as usize).min(MAX_TIMELINE_LEN);
+                let timeline = fetcher.for_user(self.data, user, max_len, after);
+                write_frame(w, buf, |out| {
+                    out.push(OP_FOR_USER);
+                    put_u32(out, timeline.tweets.len() as u32);
+                    for tweet in timeline.tweets {
+                        encode_tweet(out, tweet);
+                    }
+                })
+            }
+            Request::Batch(reqs) => {
+                // responses go in nested frames, built up before the outer one
+                let mut nested = Vec::new();
+                let mut scratch = Vec::new();
+                let count = reqs.len() as u32;
+                for req in reqs {
+                    self.respond(fetcher, req, &mut nested, &mut scratch)?;
+                    // opcode and count come on top, stop before buffering past that
+                    if nested.len() + 5 > self.max_frame {
+                        let msg = format!("batch response over {} bytes", self.max_frame);
+                        return write_frame(w, buf, |out| Response::Error(msg).encode(out));
+                    }
+                }
+                write_frame(w, buf, |out| {
+                    out.push(OP_BATCH);
+                    put_u32(out, count);
+                    out.extend_from_slice(&nested);
+                })
+            }
+        }
+    }
+
+    pub fn serve_stream(&self, reader: impl Read, writer: impl Write) -> io::Result<()> {
+        let mut reader = BufReader::with_capacity(1 << 16, reader);
+        let mut writer = BufWriter::with_capacity(1 << 16, writer);
+        let mut fetcher = TimelineFetcher::default();
+        let mut frame = vec![];
+        let mut out = vec![];
+        while read_frame(&mut reader, &mut frame)? {
+            let req = Request::decode(&frame)?;
+            self.respond(&mut fetcher, req, &mut writer, &mut out)?;
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Only accepts while fewer than `max_connections` are open, so the rest
+    /// wait in the listen backlog
+    fn spawn_connections<'s, S: Read + Write + Send + 's>(
+        &'s self,
+        max_connections: usize,
+        mut incoming: impl Iterator<Item = io::Result<(S, S)>>,
+    ) -> io::Result<()> {
+        let open = &(Mutex::new(0usize), Condvar::new());
+        thread::scope(|s| loop {
+            drop(
+                open.1
+                    .wait_while(open.0.lock().unwrap(), |n| *n >= max_connections.max(1))
+                    .unwrap(),
+            );
+            let Some(conn) = incoming.next() else {
+                return Ok(());
+            };
+            let (r, w) = conn?;
+            *open.0.lock().unwrap() += 1;
+            s.spawn(move || {
+                if let Err(e) = self.serve_stream(r, w) {
+                    eprintln!("connection error: {e}");
+                }
+                *open.0.lock().unwrap() -= 1;
+                open.1.notify_one();
+            });
+        })
+    }
+
+    /// Serves forever, with one thread and fetcher per connection and at most
+    /// `max_connections` of them
+    pub fn serve_tcp(&self, listener: TcpListener, max_connections: usize) -> io::Result<()> {
+        self.spawn_connections(
+            max_connections,
+            listener.incoming().map(|s| {
+                let s = s?;
+                s.set_nodelay(true)?;
+                Ok((s.try_clone()?, s))
+            }),
+        )
+    }
+
+    pub fn serve_unix(&self, listener: UnixListener, max_connections: usize) -> io::Result<()> {
+        self.spawn_connections(
+            max_connections,
+            listener.incoming().map(|s| {
+                let s = s?;
+                Ok((s.try_clone()?, s))
+            }),
+        )
+    }
+}
+
+/// Requests can be queued with `send` and their responses collected later
+/// with `recv`, in the same order, to keep several in flight.
+pub struct Client<R: Read, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    frame: Vec<u8>,
+    in_flight: usize,
+}
+
+impl Client<TcpStream, TcpStream> {
+    pub fn connect_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let s = TcpStream::connect(addr)?;
+        s.set_nodelay(true)?;
+        Ok(Self::new(s.try_clone()?, s))
+    }
+}
+
+impl Client<UnixStream, UnixStream> {
+    pub fn connect_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let s = UnixStream::connect(path)?;
+        Ok(Self::new(s.try_clone()?, s))
+    }
+}
+
+impl<R: Read, W: Write> Client<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::with_capacity(1 << 16, reader),
+            writer: BufWriter::with_capacity(1 << 16, writer),
+            frame: vec![],
+            in_flight: 0,
+        }
+    }
+
+    /// Buffers a request, it's only written once the buffer fills or on `recv`/`flush`
+    pub fn send(&mut self, req: &Request) -> io::Result<()> {
+        write_frame(&mut self.writer, &mut self.frame, |out| req.encode(out))?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Response to the oldest outstanding request
+    pub fn recv(&mut self) -> io::Result<Response> {
+        // don't wait on a response to a request still sitting in our buffer
+        if self.reader.buffer().is_empty() {
+            self.writer.flush()?;
+        }
+        if !read_frame(&mut self.reader, &mut self.frame)? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.in_flight -= 1;
+        Response::decode(&self.frame)
+    }
+
+    pub fn call(&mut self, req: &Request) -> io::Result<Response> {
+        self.send(req)?;
+        self.recv()
+    }
+
+    /// Sends the requests as one batch frame and waits for its responses
+    pub fn call_batch(&mut self, reqs: &[Request]) -> io::Result<Vec<Response>> {
+        write_frame(&mut self.writer, &mut self.frame, |out| {
+            encode_batch(out, reqs)
+        })?;
+        self.in_flight += 1;
+        match self.recv()? {
+            Response::Batch(responses) if responses.len() == reqs.len() => Ok(responses),
+            Response::Error(msg) => Err(io::Error::other(msg)),
+            _ => Err(invalid("batch answered with something else")),
+        }
+    }
+
+    /// Skips the `WireTweet` allocations, for load generation
+    pub fn recv_timeline_len(&mut self) -> io::Result<Option<usize>> {
+        if self.reader.buffer().is_empty() {
+            self.writer.flush()?;
+        }
+        if !read_frame(&mut self.reader, &mut self.frame)? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.in_flight -= 1;
+        let mut f = Frame(&self.frame);
+        match f.u8()? {
+            OP_FOR_USER => Ok(Some(f.u32()? as usize)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<R: Read, W: Write> Drop for Client<R, W> {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+    use std::time::Duration;
+
+    #[test]
+    fn pipelined() {
+        let mut builder = GraphBuilder::new(3, true);
+        builder.add_edge(0, 1);
+        builder.add_edge(0, 2);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let server = RpcServer::new(&data, START_TIME);
+
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| server.serve_stream(server_end.try_clone().unwrap(), &server_end));
+
+            let mut client = Client::new(client_end.try_clone().unwrap(), client_end);
+            let add = |user: UserIdx, content: &str| Request::AddTweet {
+                user,
+                content: content.to_string(),
+            };
+            let responses = client
+                .call_batch(&[add(1, "one"), add(2, "two"), add(7, "nobody")])
+                .unwrap();
+            let ts = |t| Timestamp::new(t).unwrap();
+            assert_eq!(
+                responses[..2],
+                [
+                    Response::Added {
+                        tweet_idx: 0,
+                        ts: ts(2)
+                    },
+                    Response::Added {
+                        tweet_idx: 1,
+                        ts: ts(3)
+                    },
+                ]
+            );
+            assert!(matches!(responses[2], Response::Error(_)));
+            assert_eq!(client.in_flight(), 0);
+
+            let for_user = |max_len, after| Request::ForUser {
+                user: 0,
+                max_len,
+                after: ts(after),
+            };
+            client.send(&for_user(10, 1)).unwrap();
+            client.send(&for_user(10, 3)).unwrap();
+            assert_eq!(client.in_flight(), 2);
+            let Response::Timeline(all) = client.recv().unwrap() else {
+                panic!("expected a timeline");
+            };
+            let contents: Vec<&str> = all.iter().map(|t| &t.content[..]).collect();
+            assert_eq!(contents, ["two", "one"]);
+            assert_eq!(client.recv_timeline_len().unwrap(), Some(1));
+            drop(client);
+        });
+    }
+
+    #[test]
+    fn framing() {
+        let mut out = vec![];
+        put_str(&mut out, &"é".repeat(40_000));
+        let s = Frame(&out).str().unwrap();
+        assert_eq!(s.len(), u16::MAX as usize - 1);
+        assert!(s.chars().all(|c| c == 'é'));
+
+        let batch = Request::Batch(vec![
+            Request::AddTweet {
+                user: 1,
+                content: "x".into(),
+            },
+            Request::ForUser {
+                user: 0,
+                max_len: 5,
+                after: START_TIME,
+            },
+        ]);
+        let mut out = vec![];
+        batch.encode(&mut out);
+        assert_eq!(Request::decode(&out).unwrap(), batch);
+        let mut nested = vec![];
+        Request::Batch(vec![batch]).encode(&mut nested);
+        assert!(Request::decode(&nested).is_err());
+    }
+
+    #[test]
+    fn oversized_batch() {
+        let baked = GraphBuilder::new(2, true).finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let mut server = RpcServer::new(&data, START_TIME);
+        server.max_frame = 64;
+        let add = |n| {
+            Request::Batch(
+                (0..n)
+                    .map(|i| Request::AddTweet {
+                        user: 1,
+                        content: format!("{i}"),
+                    })
+                    .collect(),
+            )
+        };
+        let mut fetcher = TimelineFetcher::default();
+        let mut call = |req| {
+            let (mut out, mut buf) = (vec![], vec![]);
+            server
+                .respond(&mut fetcher, req, &mut out, &mut buf)
+                .unwrap();
+            assert!(out.len() - 4 <= 64);
+            Response::decode(&out[4..]).unwrap()
+        };
+        // 4 + 9 bytes per nested response
+        assert!(matches!(call(add(4)), Response::Batch(r) if r.len() == 4));
+        assert!(matches!(call(add(5)), Response::Error(_)));
+    }
+
+    #[test]
+    fn connection_limit() {
+        let baked = GraphBuilder::new(1, true).finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let server = RpcServer::new(&data, START_TIME);
+        let (a, a_server) = UnixStream::pair().unwrap();
+        let (b, b_server) = UnixStream::pair().unwrap();
+        let incoming = [a_server, b_server]
+            .into_iter()
+            .map(|s| Ok((s.try_clone()?, s)));
+        let req = Request::ForUser {
+            user: 0,
+            max_len: 1,
+            after: START_TIME,
+        };
+        thread::scope(|s| {
+            s.spawn(|| server.spawn_connections(1, incoming).unwrap());
+            let mut a = Client::new(a.try_clone().unwrap(), a);
+            assert_eq!(a.call(&req).unwrap(), Response::Timeline(vec![]));
+
+            b.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            let mut b_client = Client::new(b.try_clone().unwrap(), b.try_clone().unwrap());
+            b_client.send(&req).unwrap();
+            // waits for a slot
+            assert_eq!(
+                b_client.recv().unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+            drop(a);
+            b.set_read_timeout(None).unwrap();
+            assert_eq!(b_client.recv().unwrap(), Response::Timeline(vec![]));
+            // lets the server run out of connections
+            drop((b_client, b));
+        });
+    }
+}
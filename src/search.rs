@@ -0,0 +1,486 @@
+//! Full-text search over tweet content.
+//!
+//! `Datastore::add_tweet` indexes every tweet as it's added, and tweet indices
+//! only grow, so postings are appended in `TweetIdx` order and a query can walk
+//! them backwards to return the newest matches first without sorting. Ids
+//! wrap, so that order is `id_cmp`'s.
+//!
+//! Postings only record which tweets contain a term. Every candidate is checked
+//! against its current content, which handles phrases, deletes and edits: an
+//! edit indexes the new terms but leaves stale postings behind to be filtered.
+//! An edited tweet is older than the newest postings, so its new terms go in a
+//! small side list per term that's merged in once it fills, rather than being
+//! inserted mid-list.
+//!
+//! A query copies a batch of matching indices out of the postings and drops
+//! the shard locks before checking content and visibility, so slow filtering
+//! doesn't hold up adds to the same terms.
+//!
+//! Terms are spread over `SHARDS` independently locked maps by hash, so
+//! concurrent adds mostly touch different locks and pruning holds one shard at
+//! a time. Each distinct term is allocated once, when it's first seen.
+
+use std::collections::HashMap;
+use std::sync::{RwLock, RwLockReadGuard};
+
+use crate::data::*;
+use crate::pool::id_cmp;
+
+/// Lowercased runs of alphanumeric characters, lowercased a char at a time
+/// like `SearchIndex::index` does
+pub fn tokens(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.chars().flat_map(char::to_lowercase).collect())
+}
+
+const SHARDS: usize = 64;
+
+/// Edits collected per term before they're merged into the appended postings
+const EDITED_LIMIT: usize = 64;
+
+/// How many candidates a query copies out per batch at least
+const MIN_BATCH: usize = 64;
+
+/// The tweets containing one term, both lists sorted by `id_cmp`
+#[derive(Default)]
+struct PostingList {
+    appended: Vec<TweetIdx>,
+    /// Edits of tweets older than the end of `appended`, not in it
+    edited: Vec<TweetIdx>,
+}
+
+impl PostingList {
+    fn len(&self) -> usize {
+        self.appended.len() + self.edited.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains(&self, tweet_idx: TweetIdx) -> bool {
+        let find = |l: &[TweetIdx]| l.binary_search_by(|i| id_cmp(*i, tweet_idx)).is_ok();
+        find(&self.appended) || find(&self.edited)
+    }
+
+    fn add(&mut self, tweet_idx: TweetIdx) {
+        // only edits land anywhere but the end
+        match self.appended.last() {
+            Some(last) if id_cmp(*last, tweet_idx).is_ge() => {
+                if self.contains(tweet_idx) {
+                    return;
+                }
+                let i = self
+                    .edited
+                    .partition_point(|i| id_cmp(*i, tweet_idx).is_lt());
+                self.edited.insert(i, tweet_idx);
+                if self.edited.len() >= EDITED_LIMIT {
+                    // two sorted runs, which the stable sort merges in one pass
+                    self.appended.append(&mut self.edited);
+                    self.appended.sort_by(|a, b| id_cmp(*a, *b));
+                }
+            }
+            _ => self.appended.push(tweet_idx),
+        }
+    }
+
+    fn prune(&mut self, start: TweetIdx) {
+        for list in [&mut self.appended, &mut self.edited] {
+            let expired = list.partition_point(|i| id_cmp(*i, start).is_lt());
+            list.drain(..expired);
+        }
+    }
+}
+
+type Postings = HashMap<Box<str>, PostingList>;
+
+/// FNV-1a, only needs to spread terms over shards
+fn shard_of(term: &str) -> usize {
+    let hash = term.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % SHARDS as u64) as usize
+}
+
+pub struct SearchIndex {
+    shards: Vec<RwLock<Postings>>,
+}
+
+impl Default for SearchIndex {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+impl SearchIndex {
+    pub fn index(&self, tweet_idx: TweetIdx, text: &str) {
+        // every term lowercased into one buffer, then sliced
+        let mut lower = String::with_capacity(text.len());
+        let mut ends = vec![];
+        for token in text.split(|c: char| !c.is_alphanumeric()) {
+            if !token.is_empty() {
+                lower.extend(token.chars().flat_map(char::to_lowercase));
+                ends.push(lower.len());
+            }
+        }
+        let mut terms: Vec<(usize, &str)> = ends
+            .iter()
+            .scan(0, |start, &end| {
+                let term = &lower[*start..end];
+                *start = end;
+                Some((shard_of(term), term))
+            })
+            .collect();
+        terms.sort_unstable();
+        terms.dedup();
+
+        for group in terms.chunk_by(|a, b| a.0 == b.0) {
+            let mut postings = self.shards[group[0].0].write().unwrap();
+            for (_, term) in group {
+                let list = match postings.get_mut(*term) {
+                    Some(list) => list,
+                    None => postings.entry(Box::from(*term)).or_default(),
+                };
+                list.add(tweet_idx);
+            }
+        }
+    }
+
+    /// Drops postings of tweets below `start`, after they've expired. Locks
+    /// one shard at a time so adds and searches elsewhere carry on.
+    pub fn prune(&self, start: TweetIdx) {
+        for shard in &self.shards {
+            shard.write().unwrap().retain(|_, list| {
+                list.prune(start);
+                !list.is_empty()
+            });
+        }
+    }
+
+    /// Read locks on the shards holding `terms`, in shard order
+    fn read<'a>(
+        &self,
+        terms: impl Iterator<Item = &'a String>,
+    ) -> Vec<(usize, RwLockReadGuard<'_, Postings>)> {
+        let mut shards: Vec<usize> = terms.map(|t| shard_of(t)).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards
+            .into_iter()
+            .map(|shard| (shard, self.shards[shard].read().unwrap()))
+            .collect()
+    }
+
+    /// Appends to `out` up to `limit` tweets containing every term of `query`
+    /// with index below `before`, newest first. Returns whether there may be
+    /// more, and holds the shard locks only while copying.
+    fn intersect(
+        &self,
+        query: &Query,
+        before: Option<TweetIdx>,
+        limit: usize,
+        out: &mut Vec<TweetIdx>,
+    ) -> bool {
+        let postings = self.read(query.required());
+        let mut lists = vec![];
+        for term in query.required() {
+            let shard = shard_of(term);
+            let (_, shard) = postings.iter().find(|(s, _)| *s == shard).unwrap();
+            match shard.get(term.as_str()) {
+                Some(list) => lists.push(list),
+                // no tweet has ever contained it
+                None => return false,
+            }
+        }
+        lists.sort_by_key(|l| l.len());
+        let Some((shortest, rest)) = lists.split_first() else {
+            return false;
+        };
+
+        let below = |l: &[TweetIdx]| match before {
+            Some(b) => l.partition_point(|i| id_cmp(*i, b).is_lt()),
+            None => l.len(),
+        };
+        let mut appended = &shortest.appended[..below(&shortest.appended)];
+        let mut edited = &shortest.edited[..below(&shortest.edited)];
+        loop {
+            // newest of the two lists first
+            let newer = match (appended.last(), edited.last()) {
+                (None, None) => return false,
+                (Some(a), Some(e)) if id_cmp(*a, *e).is_lt() => &mut edited,
+                (Some(_), _) => &mut appended,
+                (None, Some(_)) => &mut edited,
+            };
+            let (&tweet_idx, older) = newer.split_last().unwrap();
+            *newer = older;
+            if rest.iter().all(|l| l.contains(tweet_idx)) {
+                out.push(tweet_idx);
+                if out.len() >= limit {
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Number of distinct terms
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Terms and quoted phrases that must all match, like `rust "zero copy" mmap`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub phrases: Vec<Vec<String>>,
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Self {
+        let mut terms = vec![];
+        let mut phrases = vec![];
+        // odd pieces between quotes are phrases, an unclosed quote runs to the end
+        for (i, piece) in s.split('"').enumerate() {
+            let words: Vec<String> = tokens(piece).collect();
+            if i % 2 == 1 && words.len() > 1 {
+                phrases.push(words);
+            } else {
+                terms.extend(words);
+            }
+        }
+        terms.sort_unstable();
+        terms.dedup();
+        Self { terms, phrases }
+    }
+
+    /// Every term a match has to contain, phrases included
+    fn required(&self) -> impl Iterator<Item = &String> {
+        self.terms.iter().chain(self.phrases.iter().flatten())
+    }
+
+    fn matches(&self, words: &[String]) -> bool {
+        self.terms.iter().all(|t| words.contains(t))
+            && self
+                .phrases
+                .iter()
+                .all(|p| words.windows(p.len()).any(|w| w == &p[..]))
+    }
+}
+
+pub struct SearchPage<'a> {
+    pub tweets: &'a [Tweet],
+    pub idxs: &'a [TweetIdx],
+    /// Pass as `before` to get the next page, `None` if this was the last
+    pub next: Option<TweetIdx>,
+}
+
+/// Reusable buffers for queries, like `TimelineFetcher`
+#[derive(Default)]
+pub struct Searcher {
+    tweets: Vec<Tweet>,
+    idxs: Vec<TweetIdx>,
+    followees: Vec<UserIdx>,
+    words: Vec<String>,
+    /// Matching postings copied out of the index, so filtering runs unlocked
+    candidates: Vec<TweetIdx>,
+}
+
+impl Searcher {
+    /// Newest matches first, only counting tweets with index below `before`.
+    /// A `None` viewer is logged out and only sees public accounts, and
+    /// `followees_only` limits results to accounts the viewer follows.
+    pub fn search<'a>(
+        &'a mut self,
+        data: &Datastore,
+        viewer: Option<UserIdx>,
+        query: &Query,
+        followees_only: bool,
+        max_len: usize,
+        before: Option<TweetIdx>,
+    ) -> SearchPage<'a> {
+        self.tweets.clear();
+        self.idxs.clear();
+        self.followees.clear();
+        if let (Some(viewer), true) = (viewer, followees_only) {
+            let user = &data.graph.users[viewer as usize];
+            self.followees.extend(data.graph.user_follows(user));
+            self.followees.sort_unstable();
+        }
+
+        let batch = max_len.saturating_add(1).max(MIN_BATCH);
+        let mut before = before;
+        let mut next = None;
+        'batches: loop {
+            self.candidates.clear();
+            let more = data
+                .search
+                .intersect(query, before, batch, &mut self.candidates);
+            let pinned = data.pin();
+            let mut relations = data.relations.read();
+            let mut visibility = data.visibility.read();
+            for &tweet_idx in &self.candidates {
+                if self.tweets.len() >= max_len {
+                    next = Some(tweet_idx.wrapping_add(1));
+                    break 'batches;
+                }
+                // expired before we pinned, and so is everything older
+                let Some(chained) = pinned.tweet(tweet_idx) else {
+                    break 'batches;
+                };
+                let author = chained.author;
+                let visible = match viewer {
+                    Some(viewer) => {
+                        visibility.can_see(viewer, author)
+                            && !relations
+                                .for_viewer(viewer)
+                                .is_some_and(|r| r.hides(author))
+                    }
+                    None => !data.visibility.is_protected(author),
+                };
+                if !visible || (followees_only && self.followees.binary_search(&author).is_err()) {
+                    continue;
+                }
+                let Some(tweet) = pinned.current_tweet(chained) else {
+                    continue;
+                };
+                self.words.clear();
+                self.words.extend(tokens(&tweet.text()));
+                if query.matches(&self.words) {
+                    self.tweets.push(tweet);
+                    self.idxs.push(tweet_idx);
+                }
+            }
+            if !more {
+                break;
+            }
+            before = self.candidates.last().copied();
+        }
+
+        SearchPage {
+            tweets: &self.tweets[..],
+            idxs: &self.idxs[..],
+            next,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+
+    #[test]
+    fn parsing() {
+        let query = Query::parse(r#"Rust "zero-copy mmap" rust "fast""#);
+        assert_eq!(query.terms, ["fast", "rust"]);
+        assert_eq!(query.phrases, [["zero", "copy", "mmap"]]);
+    }
+
+    #[test]
+    fn pruning() {
+        let index = SearchIndex::default();
+        index.index(0, "Alpha beta BETA");
+        index.index(1, "beta gamma");
+        index.index(2, "gamma delta");
+        assert_eq!(index.len(), 4);
+        index.prune(2);
+        assert_eq!(index.len(), 2);
+        let postings = index.read([String::from("gamma")].iter());
+        assert_eq!(postings[0].1.get("gamma").unwrap().appended, [2]);
+    }
+
+    #[test]
+    fn edits() {
+        let index = SearchIndex::default();
+        for i in 0..100 {
+            index.index(i, "alpha");
+        }
+        index.index(100, "alpha beta");
+        // every older tweet edited to add beta, out of order
+        let evens = (0..100).step_by(2).rev();
+        for i in evens.chain((1..100).step_by(2)) {
+            index.index(i, "alpha beta");
+        }
+        let postings = index.read([String::from("beta")].iter());
+        let beta = postings[0].1.get("beta").unwrap();
+        assert_eq!(beta.appended.len(), 1 + EDITED_LIMIT);
+        assert_eq!(beta.edited.len(), 100 - EDITED_LIMIT);
+        drop(postings);
+
+        // in batches the way `Searcher::search` pages
+        let query = Query::parse("beta alpha");
+        let mut found = vec![];
+        let mut before = None;
+        while index.intersect(&query, before, 7, &mut found) {
+            before = found.last().copied();
+        }
+        assert!(found.iter().rev().eq(&(0..=100).collect::<Vec<_>>()));
+
+        index.prune(50);
+        found.clear();
+        assert!(!index.intersect(&query, Some(60), 100, &mut found));
+        assert_eq!(found, (50..60).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn queries() {
+        // 0 follows 1, nobody follows 2
+        let mut builder = GraphBuilder::new(3, true);
+        builder.add_edge(0, 1);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let texts = [
+            "the quick brown fox",
+            "brown bread is quick to make",
+            "a quick brown dog",
+            "Quick! Brown paint",
+            "slow brown fox",
+        ];
+        let idxs: Vec<TweetIdx> = texts
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let ts = Timestamp::new(i as u32 + 1).unwrap();
+                data.add_tweet(Tweet::new(ts, text), 1 + i as u32 % 2)
+                    .unwrap()
+            })
+            .collect();
+
+        let mut searcher = Searcher::default();
+        let mut search = |viewer, query: &str, followees_only, max_len, before| {
+            let query = Query::parse(query);
+            let page = searcher.search(&data, viewer, &query, followees_only, max_len, before);
+            (page.idxs.to_vec(), page.next)
+        };
+        assert_eq!(search(None, "quick brown", false, 10, None).0, [3, 2, 1, 0]);
+        assert_eq!(
+            search(None, "\"quick brown\"", false, 10, None).0,
+            [3, 2, 0]
+        );
+        assert_eq!(search(None, "fox", false, 10, None).0, [4, 0]);
+        assert!(search(None, "fox cat", false, 10, None).0.is_empty());
+        assert_eq!(search(Some(0), "brown", true, 10, None).0, [4, 2, 0]);
+
+        let (page, next) = search(None, "brown", false, 2, None);
+        assert_eq!(page, [4, 3]);
+        let (page, next) = search(None, "brown", false, 2, next);
+        assert_eq!(page, [2, 1]);
+        assert_eq!(search(None, "brown", false, 2, next), (vec![0], None));
+
+        assert!(data.delete_tweet(idxs[3]));
+        assert!(data.edit_tweet(idxs[0], "the lazy dog", Timestamp::new(9).unwrap()));
+        assert_eq!(search(None, "\"quick brown\"", false, 10, None).0, [2]);
+        assert_eq!(search(None, "dog", false, 10, None).0, [2, 0]);
+        data.set_protected(1, true);
+        assert!(search(None, "dog", false, 10, None).0.is_empty());
+        assert!(search(Some(2), "dog", false, 10, None).0.is_empty());
+        assert_eq!(search(Some(0), "dog", false, 10, None).0, [2, 0]);
+    }
+}
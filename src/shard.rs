@@ -0,0 +1,270 @@
+//! Models partitioning feeds across shards by author.
+//!
+//! Each shard is an ordinary `Datastore` that only receives tweets from the
+//! authors routed to it. A timeline fetch asks every shard for a partial
+//! timeline over the followees it owns and then merges those by timestamp,
+//! which is what a scatter-gather over the network would do.
+//!
+//! Unsharded, tweets with equal timestamps merge newest first by their index
+//! in the one pool, see `NextLink`. Shards each number their own tweets, so
+//! every tweet also gets a sequence number across all shards to merge by.
+
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::data::*;
+use crate::pool::SharedPool;
+use crate::timeline::{Timeline, TimelineFetcher};
+use crate::visibility::Visibility;
+
+pub struct ShardedDatastore<'a> {
+    /// For simplicity every shard has a feed table sized for all users,
+    /// only the entries of the authors it owns are ever set.
+    /// Tweets must be added through `ShardedDatastore::add_tweet`.
+    pub shards: Vec<Datastore<'a>>,
+    /// Sequence number of each shard's tweets by index in that shard. Never
+    /// truncated, so shards take at most `MAX_LIVE` tweets in total.
+    seqs: Vec<SharedPool<u32>>,
+    next_seq: AtomicU32,
+    /// Keeps each shard's `seqs` in step with its tweets
+    adding: Vec<Mutex<()>>,
+}
+
+impl<'a> ShardedDatastore<'a> {
+    pub fn new(graph: Graph<'a>, n_shards: usize) -> io::Result<Self> {
+        assert!(n_shards > 0);
+        let shards = (0..n_shards)
+            .map(|_| Datastore::new(graph))
+            .collect::<Result<_, _>>()?;
+        let seqs = (0..n_shards)
+            .map(|_| SharedPool::new())
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            shards,
+            seqs,
+            next_seq: AtomicU32::new(0),
+            adding: (0..n_shards).map(|_| Mutex::default()).collect(),
+        })
+    }
+
+    pub fn graph(&self) -> &Graph<'a> {
+        &self.shards[0].graph
+    }
+
+    #[inline]
+    pub fn shard_of(&self, user_id: UserIdx) -> usize {
+        user_id as usize % self.shards.len()
+    }
+
+    /// Relations belong to viewers rather than authors, so every shard keeps a
+    /// copy. Returns whether any shard changed.
+    pub fn mute(&self, viewer: UserIdx, target: UserIdx) -> bool {
+        self.shards.iter().fold(false, |changed, s| {
+            s.relations.mute(viewer, target) | changed
+        })
+    }
+
+    pub fn unmute(&self, viewer: UserIdx, target: UserIdx) -> bool {
+        self.shards.iter().fold(false, |changed, s| {
+            s.relations.unmute(viewer, target) | changed
+        })
+    }
+
+    pub fn block(&self, blocker: UserIdx, target: UserIdx) -> bool {
+        self.shards.iter().fold(false, |changed, s| {
+            s.relations.block(blocker, target) | changed
+        })
+    }
+
+    pub fn unblock(&self, blocker: UserIdx, target: UserIdx) -> bool {
+        self.shards.iter().fold(false, |changed, s| {
+            s.relations.unblock(blocker, target) | changed
+        })
+    }
+
+    /// Visibility is checked against the author's feed, so only their shard needs it
+    pub fn set_protected(&self, author: UserIdx, protected: bool) -> bool {
+        self.shards[self.shard_of(author)].set_protected(author, protected)
+    }
+
+    pub fn visibility(&self, author: UserIdx) -> &Visibility<'a> {
+        &self.shards[self.shard_of(author)].visibility
+    }
+
+    /// Returns the shard the tweet went to and its index within that shard
+    pub fn add_tweet(&self, tweet: Tweet, user_id: UserIdx) -> io::Result<(usize, TweetIdx)> {
+        let shard = self.shard_of(user_id);
+        let (data, seqs) = (&self.shards[shard], &self.seqs[shard]);
+        let _guard = self.adding[shard].lock().unwrap();
+        // checked up front, a tweet without a sequence number would shift the rest
+        if seqs.len() >= seqs.capacity() {
+            return Err(io::Error::other("sequence numbers full"));
+        }
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let pushed = data.tweets.len();
+        let added = data.add_tweet(tweet, user_id);
+        // a failed add can still have pushed the tweet, deleted again
+        for _ in pushed..data.tweets.len() {
+            seqs.push(seq)?;
+        }
+        Ok((shard, added?))
+    }
+
+    /// When a tweet was added relative to those in every shard
+    #[inline]
+    fn seq(&self, shard: usize, tweet_idx: TweetIdx) -> u32 {
+        // under `MAX_LIVE` entries, so narrowing didn't change the index
+        self.seqs[shard]
+            .get(tweet_idx as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+pub struct ShardedFetcher {
+    fetchers: Vec<TimelineFetcher>,
+    tweets: Vec<Tweet>,
+    authors: Vec<UserIdx>,
+    /// (newest remaining timestamp, its sequence number, shard, position in
+    /// that shard's partial timeline)
+    heap: BinaryHeap<(Timestamp, u32, usize, usize)>,
+}
+
+impl ShardedFetcher {
+    pub fn for_user<'a>(
+        &'a mut self,
+        data: &ShardedDatastore,
+        user_idx: UserIdx,
+        max_len: usize,
+        after: Timestamp,
+    ) -> Timeline<'a> {
+        let n_shards = data.shards.len();
+        self.fetchers
+            .resize_with(n_shards, TimelineFetcher::with_idxs);
+        self.tweets.clear();
+        self.authors.clear();
+        self.heap.clear();
+
+        let graph = data.graph();
+        let follows = graph.user_follows(&graph.users[user_idx as usize]);
+
+        // scatter: each shard merges the followees it owns, up to max_len
+        for (shard, (fetcher, shard_data)) in self.fetchers.iter_mut().zip(&data.shards).enumerate()
+        {
+            let owned = follows
+                .iter()
+                .copied()
+                .filter(|f| data.shard_of(*f) == shard);
+            fetcher.for_users(shard_data, user_idx, owned, max_len, after);
+        }
+
+        // gather: final merge of the sorted partial timelines
+        let entry = |shard: usize, partial: &Timeline, pos: usize| {
+            let seq = data.seq(shard, partial.idxs[pos]);
+            (partial.tweets[pos].ts, seq, shard, pos)
+        };
+        for (shard, fetcher) in self.fetchers.iter().enumerate() {
+            let partial = fetcher.timeline();
+            if !partial.tweets.is_empty() {
+                self.heap.push(entry(shard, &partial, 0));
+            }
+        }
+        while let Some((_, _, shard, pos)) = self.heap.pop() {
+            let partial = self.fetchers[shard].timeline();
+            self.tweets.push(partial.tweets[pos].clone());
+            // only there if the shard fetchers were asked for them
+            self.authors.extend(partial.authors.get(pos));
+            if self.tweets.len() >= max_len {
+                break;
+            }
+            if pos + 1 < partial.tweets.len() {
+                self.heap.push(entry(shard, &partial, pos + 1));
+            }
+        }
+
+        Timeline {
+            tweets: &self.tweets[..],
+            authors: &self.authors[..],
+            idxs: &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+
+    #[test]
+    fn matches_unsharded() {
+        let mut builder = GraphBuilder::new(8, true);
+        for f in 1..8 {
+            builder.add_edge(0, f);
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let sharded = ShardedDatastore::new(baked.graph(), 3).unwrap();
+
+        for i in 0..40u32 {
+            let user = 1 + (i * 5) % 7;
+            let ts = Timestamp::new(i + 1).unwrap();
+            data.add_tweet(Tweet::dummy(ts), user).unwrap();
+            sharded.add_tweet(Tweet::dummy(ts), user).unwrap();
+        }
+        let total: usize = sharded.shards.iter().map(|s| s.tweets.len()).sum();
+        assert_eq!(total, 40);
+
+        let mut fetcher = TimelineFetcher::default();
+        let mut sharded_fetcher = ShardedFetcher::default();
+        for (max_len, after) in [(10, 1), (100, 1), (100, 30)] {
+            let after = Timestamp::new(after).unwrap();
+            let expected: Vec<Timestamp> = fetcher
+                .for_user(&data, 0, max_len, after)
+                .tweets
+                .iter()
+                .map(|t| t.ts)
+                .collect();
+            let actual: Vec<Timestamp> = sharded_fetcher
+                .for_user(&sharded, 0, max_len, after)
+                .tweets
+                .iter()
+                .map(|t| t.ts)
+                .collect();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn ties_merge_like_unsharded() {
+        let mut builder = GraphBuilder::new(8, true);
+        for f in 1..8 {
+            builder.add_edge(0, f);
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let sharded = ShardedDatastore::new(baked.graph(), 3).unwrap();
+
+        // four tweets a second, from authors on different shards
+        for i in 0..40u32 {
+            let user = 1 + (i * 3) % 7;
+            let tweet = Tweet::new(Timestamp::new(1 + i / 4).unwrap(), &format!("{i}"));
+            data.add_tweet(tweet.clone(), user).unwrap();
+            sharded.add_tweet(tweet, user).unwrap();
+        }
+
+        let mut fetcher = TimelineFetcher::default();
+        let mut sharded_fetcher = ShardedFetcher::default();
+        for (max_len, after) in [(7, 1), (100, 1), (100, 5)] {
+            let after = Timestamp::new(after).unwrap();
+            let texts = |timeline: Timeline| -> Vec<String> {
+                timeline.tweets.iter().map(|t| t.text().into()).collect()
+            };
+            let expected = texts(fetcher.for_user(&data, 0, max_len, after));
+            let actual = texts(sharded_fetcher.for_user(&sharded, 0, max_len, after));
+            assert_eq!(actual, expected);
+        }
+    }
+}
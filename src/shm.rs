@@ -0,0 +1,307 @@
+//! Tweets and feed heads in shared memory, so separate reader processes can
+//! serve timelines while one writer process appends.
+//!
+//! The tweet and edit pools and the per-user feed heads and tweet counts each
+//! live in a file, which can be a memfd passed to children or a file on tmpfs.
+//! Readers map them read-only with `Datastore::open_shared`.
+//!
+//! Publication works exactly as between threads, since atomics on a shared
+//! mapping are atomics on the same physical memory: the writer fills in a
+//! tweet, bumps the pool's `len` and then stores the author's feed head, all
+//! `SeqCst`. A reader that loads a head therefore sees the whole tweet, and
+//! any older tweet the chain leads to.
+//!
+//! Only tweets, edits, deletes and feeds are shared. Mutes, blocks, protected
+//! accounts, lists, search and notifications stay in the writer process, so
+//! readers may only serve what every viewer may see. `ReaderGate` enforces
+//! that: while readers are attached the writer can't protect, mute or block
+//! anyone, and once it has no reader attaches. It also keeps `expire_before`
+//! from releasing anything while readers are attached, since the writer can't
+//! see what they have pinned.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Deref;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// An anonymous in-memory file, inherited by children or reachable through
+/// `/proc/<pid>/fd/<fd>`
+pub fn memfd(name: &str) -> io::Result<File> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// The files behind a shared `Datastore`
+pub struct SharedFiles {
+    pub tweets: File,
+    pub edits: File,
+    /// Feed heads, then tweet counts
+    pub users: File,
+}
+
+impl SharedFiles {
+    const NAMES: [&'static str; 3] = ["tweets.pool", "edits.pool", "users.bin"];
+
+    pub fn memfd() -> io::Result<Self> {
+        let [tweets, edits, users] = Self::NAMES.map(memfd);
+        Ok(Self {
+            tweets: tweets?,
+            edits: edits?,
+            users: users?,
+        })
+    }
+
+    /// Creates or truncates the files in `dir`, for the writer
+    pub fn create_in(dir: &Path) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        Self::open_with(dir, &options)
+    }
+
+    /// Read-only, for readers
+    pub fn open_in(dir: &Path) -> io::Result<Self> {
+        Self::open_with(dir, OpenOptions::new().read(true))
+    }
+
+    fn open_with(dir: &Path, options: &OpenOptions) -> io::Result<Self> {
+        let [tweets, edits, users] = Self::NAMES.map(|name| options.open(dir.join(name)));
+        Ok(Self {
+            tweets: tweets?,
+            edits: edits?,
+            users: users?,
+        })
+    }
+}
+
+/// `len` items of `T` at a page-aligned `offset` in a shared file mapping
+pub struct SharedSlice<T> {
+    items: *const T,
+    len: usize,
+    map_len: usize,
+}
+
+unsafe impl<T: Sync> Sync for SharedSlice<T> {}
+unsafe impl<T: Send> Send for SharedSlice<T> {}
+
+impl<T> SharedSlice<T> {
+    /// # Safety
+    /// `T` must be plain data that's valid all zeroes, and any other process
+    /// mapping the file must agree on the layout.
+    pub unsafe fn map(file: &File, offset: usize, len: usize, writable: bool) -> io::Result<Self> {
+        let bytes = len * std::mem::size_of::<T>();
+        if (file.metadata()?.len() as usize) < offset + bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared file is too short",
+            ));
+        }
+        let map_len = bytes.max(1);
+        let prot = match writable {
+            true => libc::PROT_READ | libc::PROT_WRITE,
+            false => libc::PROT_READ,
+        };
+        let map = libc::mmap(
+            ptr::null_mut(),
+            map_len,
+            prot,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            offset as libc::off_t,
+        );
+        if map == libc::MAP_FAILED || map.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            items: map as *const T,
+            len,
+            map_len,
+        })
+    }
+}
+
+impl<T> Deref for SharedSlice<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.items, self.len) }
+    }
+}
+
+impl<T> Drop for SharedSlice<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.items as *mut libc::c_void, self.map_len) };
+    }
+}
+
+/// Readers of a shared datastore hold a shared `flock` on the users file while
+/// attached, the writer takes it exclusively to expire tweets or restrict what
+/// anyone sees. Locks go away with the process, so a crashed reader doesn't
+/// block the writer forever.
+pub struct ReaderGate {
+    /// Our own open file description, since `flock` locks belong to those and
+    /// a memfd can be shared between a writer and readers
+    lock: File,
+    /// Set for good once the writer restricted anyone, in the header page
+    restricted: SharedSlice<AtomicU32>,
+}
+
+impl ReaderGate {
+    /// Bytes at the start of the users file
+    pub const HEADER_SIZE: usize = 4096;
+
+    fn open(users: &File, writable: bool) -> io::Result<Self> {
+        let lock = File::open(format!("/proc/self/fd/{}", users.as_raw_fd()))?;
+        let restricted = unsafe { SharedSlice::map(users, 0, 1, writable)? };
+        Ok(Self { lock, restricted })
+    }
+
+    pub fn for_writer(users: &File) -> io::Result<Self> {
+        Self::open(users, true)
+    }
+
+    /// Fails if the writer already restricted anyone, readers couldn't hide
+    /// what they should
+    pub fn attach(users: &File) -> io::Result<Self> {
+        let gate = Self::open(users, false)?;
+        gate.flock(libc::LOCK_SH)?;
+        if gate.restricted[0].load(Ordering::SeqCst) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "shared datastore has protected, muted or blocked users",
+            ));
+        }
+        Ok(gate)
+    }
+
+    fn flock(&self, operation: libc::c_int) -> io::Result<()> {
+        match unsafe { libc::flock(self.lock.as_raw_fd(), operation) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Runs `f` with no reader attached, `None` if any is
+    pub fn exclusive<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        self.flock(libc::LOCK_EX | libc::LOCK_NB).ok()?;
+        let result = f();
+        let _ = self.flock(libc::LOCK_UN);
+        Some(result)
+    }
+
+    /// Marks the datastore restricted unless a reader is attached. Returns
+    /// false if one is, the restriction mustn't happen then.
+    pub fn restrict(&self) -> bool {
+        self.exclusive(|| self.restricted[0].store(1, Ordering::SeqCst))
+            .is_some()
+    }
+}
+
+/// Per-user arrays like `Datastore::feeds`, in the heap or in shared memory
+pub enum PerUser<T> {
+    Owned(Vec<T>),
+    Shared(SharedSlice<T>),
+}
+
+impl<T> Deref for PerUser<T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        match self {
+            PerUser::Owned(items) => items,
+            PerUser::Shared(items) => items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::*;
+    use crate::import::GraphBuilder;
+    use crate::timeline::TimelineFetcher;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn reader_follows_writer() {
+        let mut builder = GraphBuilder::new(4, true);
+        for (follower, followee) in [(0, 1), (0, 2), (3, 1)] {
+            builder.add_edge(follower, followee);
+        }
+        let baked = builder.finish();
+        let files = SharedFiles::memfd().unwrap();
+        let writer = Datastore::create_shared(baked.graph(), &files).unwrap();
+        // a separate mapping of the same files, as another process would have
+        let reader = Datastore::open_shared(baked.graph(), &files).unwrap();
+        assert!(reader
+            .tweets
+            .push(ChainedTweet::new(Tweet::dummy(START_TIME), None, 1))
+            .is_err());
+
+        let ts = |t| Timestamp::new(t).unwrap();
+        let n = 5_000;
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for t in 1..=n {
+                    writer.add_tweet(Tweet::dummy(ts(t)), 1 + t % 2).unwrap();
+                }
+                assert!(writer.edit_tweet(0, "edited", ts(n + 1)));
+                assert!(writer.delete_tweet(1));
+                done.store(true, Ordering::SeqCst);
+            });
+            let mut fetcher = TimelineFetcher::default();
+            loop {
+                let finished = done.load(Ordering::SeqCst);
+                let timeline = fetcher.for_user(&reader, 0, 64, START_TIME);
+                // every published head leads to complete, ordered tweets
+                assert!(timeline.tweets.windows(2).all(|w| w[0].ts > w[1].ts));
+                if finished {
+                    break;
+                }
+            }
+        });
+        assert_eq!(reader.tweets.len(), n as usize);
+        assert_eq!(reader.tweet_counts[1].load(Ordering::SeqCst), n / 2 - 1);
+        assert_eq!(reader.get_tweet(0).unwrap().text(), "edited");
+        assert!(reader.get_tweet(1).is_none());
+        // mutators fail instead of writing to the read-only mappings
+        assert!(reader.add_tweet(Tweet::dummy(ts(n + 2)), 1).is_err());
+        assert!(!reader.delete_tweet(2));
+        assert!(!reader.edit_tweet(2, "edited", ts(n + 2)));
+        assert!(!reader.apply_edit(2, 0));
+        assert!(reader.like_tweet(0, 2, ts(n + 2)).is_err());
+        assert_eq!(reader.expire_before(ts(n)), 0);
+        assert!(reader.get_tweet(2).is_some());
+        let mut fetcher = TimelineFetcher::default();
+        let newest = fetcher.for_user(&reader, 3, 1, START_TIME).tweets[0].ts;
+        assert_eq!(newest, ts(n));
+
+        // a graph of another size doesn't fit the files
+        let other = GraphBuilder::new(5, true).finish();
+        assert!(Datastore::open_shared(other.graph(), &files).is_err());
+
+        // the reader couldn't hide protected or blocked tweets, nor see expiry
+        assert!(!writer.set_protected(1, true));
+        assert!(!writer.relations.block(0, 1));
+        assert!(!writer.relations.mute(0, 1));
+        assert_eq!(writer.expire_before(ts(10)), 0);
+        let second = Datastore::open_shared(baked.graph(), &files).unwrap();
+        drop((reader, second));
+        assert_eq!(writer.expire_before(ts(10)), 9);
+        assert!(writer.set_protected(1, true));
+        let err = Datastore::open_shared(baked.graph(), &files).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        // the flag stays set after unprotecting
+        assert!(writer.set_protected(1, false));
+        assert!(Datastore::open_shared(baked.graph(), &files).is_err());
+    }
+}
@@ -3,13 +3,46 @@ use ringbuffer::ConstGenericRingBuffer;
 use ringbuffer::RingBufferWrite;
 use static_assertions::assert_eq_size;
 use std::collections::BinaryHeap;
+use std::sync::atomic::Ordering;
 
+use crate::compress::CompressedFollows;
 use crate::data::*;
+use crate::lists::ListIdx;
+use crate::trace;
 
 pub struct Timeline<'a> {
     pub tweets: &'a [Tweet],
+    /// Author of each of `tweets`, empty unless the fetcher was made with
+    /// `TimelineFetcher::with_authors`
+    pub authors: &'a [UserIdx],
+    /// Where each of `tweets` is in `Datastore::tweets`, empty unless the
+    /// fetcher was made with `TimelineFetcher::with_idxs`
+    pub idxs: &'a [TweetIdx],
 }
 
+/// Where a profile page starts walking the author's feed
+#[derive(Clone, Copy, Debug)]
+pub enum ProfileStart {
+    Newest,
+    /// Tweets strictly older than this
+    Before(Timestamp),
+    /// `ProfilePage::next` from the previous page
+    Cursor(NextLink),
+}
+
+pub struct ProfilePage<'a> {
+    pub tweets: &'a [Tweet],
+    /// Pass as `ProfileStart::Cursor` to get the next page, `None` if this was the last
+    pub next: FeedChain,
+    /// All the author's undeleted tweets, not just this page. 0 if the viewer
+    /// can't see the author.
+    pub tweet_count: u32,
+}
+
+/// Servers cap requested lengths at this, so one request can't make a
+/// fetcher allocate without limit
+pub const MAX_TIMELINE_LEN: usize = 1000;
+
 pub const CACHE_SIZE: usize = 123;
 
 #[derive(Clone)]
@@ -49,13 +82,45 @@ impl TimelineCache {
 #[derive(Default)]
 pub struct TimelineFetcher {
     tweets: Vec<Tweet>,
+    authors: Vec<UserIdx>,
+    keep_authors: bool,
+    idxs: Vec<TweetIdx>,
+    keep_idxs: bool,
     heap: BinaryHeap<NextLink>,
 }
 
 impl TimelineFetcher {
+    /// Also fills `Timeline::authors`, which plain timelines don't need
+    pub fn with_authors() -> Self {
+        Self {
+            keep_authors: true,
+            ..Default::default()
+        }
+    }
+
+    /// Also fills `Timeline::idxs`
+    pub fn with_idxs() -> Self {
+        Self {
+            keep_idxs: true,
+            ..Default::default()
+        }
+    }
+
+    /// What the last `for_user`, `for_users` or `for_list` returned
+    pub fn timeline(&self) -> Timeline<'_> {
+        Timeline {
+            tweets: &self.tweets[..],
+            authors: &self.authors[..],
+            idxs: &self.idxs[..],
+        }
+    }
+
+    /// Links to expired tweets end the chain once popped
     #[inline]
     fn push_after(&mut self, link: Option<NextLink>, after: Timestamp) {
-        link.filter(|l| l.ts >= after).map(|l| self.heap.push(l));
+        if let Some(l) = link.filter(|l| l.ts >= after) {
+            self.heap.push(l);
+        }
     }
 
     pub fn for_user<'a>(
@@ -64,30 +129,484 @@ impl TimelineFetcher {
         user_idx: UserIdx,
         max_len: usize,
         after: Timestamp,
+    ) -> Timeline<'a> {
+        let user = &data.graph.users[user_idx as usize];
+        let follows = data.graph.user_follows(user).iter().copied();
+        self.for_users(data, user_idx, follows, max_len, after)
+    }
+
+    /// Same merge as `for_user` but decoding the follow list as it seeds the heap
+    pub fn for_user_compressed<'a>(
+        &'a mut self,
+        data: &Datastore,
+        compressed: &CompressedFollows,
+        user_idx: UserIdx,
+        max_len: usize,
+        after: Timestamp,
+    ) -> Timeline<'a> {
+        let follows = compressed.user_follows(user_idx);
+        self.for_users(data, user_idx, follows, max_len, after)
+    }
+
+    /// Timeline of a list's members as seen by `viewer`, who needn't own the list.
+    /// Empty if there's no such list.
+    pub fn for_list<'a>(
+        &'a mut self,
+        data: &Datastore,
+        viewer: UserIdx,
+        list_idx: ListIdx,
+        max_len: usize,
+        after: Timestamp,
+    ) -> Timeline<'a> {
+        let lists = data.lists.read();
+        let members = lists.get(list_idx as usize).map_or(&[][..], |l| &l.members);
+        let members = members.iter().copied();
+        self.for_users(data, viewer, members, max_len, after)
+    }
+
+    /// One user's own tweets, newest first, walking their feed chain without a merge.
+    /// Empty if `viewer` can't see `author` because of a block or protection.
+    pub fn for_profile<'a>(
+        &'a mut self,
+        data: &Datastore,
+        viewer: UserIdx,
+        author: UserIdx,
+        max_len: usize,
+        after: Timestamp,
+        start: ProfileStart,
+    ) -> ProfilePage<'a> {
+        self.tweets.clear();
+        let pinned = data.pin();
+        let live = |link: FeedChain| link.filter(|l| pinned.follow(*l).is_some());
+
+        let blocked = data
+            .relations
+            .read()
+            .for_viewer(viewer)
+            .is_some_and(|r| r.blocks_either_way(author));
+        let hidden = blocked || !data.visibility.can_see(viewer, author);
+        let tweet_count = match hidden {
+            true => 0,
+            false => data.tweet_counts[author as usize].load(Ordering::SeqCst),
+        };
+        let mut link = match start {
+            _ if hidden => None,
+            ProfileStart::Newest => live(data.feeds[author as usize].fetch()),
+            ProfileStart::Before(before) => {
+                let mut link = live(data.feeds[author as usize].fetch());
+                while let Some(chain) = link
+                    .filter(|l| l.ts >= before)
+                    .and_then(|l| pinned.follow(l))
+                {
+                    link = live(chain.prev_tweet);
+                }
+                link
+            }
+            // a cursor into someone else's feed would leak their tweets
+            ProfileStart::Cursor(cursor) => pinned
+                .follow(cursor)
+                .filter(|chained| chained.author == author)
+                .and(Some(cursor)),
+        };
+
+        while let Some(l) = link.filter(|l| l.ts >= after) {
+            if self.tweets.len() >= max_len {
+                break;
+            }
+            // live, we checked before following the link here
+            let Some(chain) = pinned.follow(l) else {
+                break;
+            };
+            if let Some(tweet) = pinned.current_tweet(chain) {
+                self.tweets.push(tweet);
+            }
+            link = live(chain.prev_tweet);
+        }
+
+        ProfilePage {
+            tweets: &self.tweets[..],
+            next: link.filter(|l| l.ts >= after),
+            tweet_count,
+        }
+    }
+
+    /// Merges the feeds of an arbitrary set of users, as seen by `viewer`
+    pub fn for_users<'a>(
+        &'a mut self,
+        data: &Datastore,
+        viewer: UserIdx,
+        follows: impl IntoIterator<Item = UserIdx>,
+        max_len: usize,
+        after: Timestamp,
     ) -> Timeline<'a> {
         self.heap.clear();
         self.tweets.clear();
-        let user = &data.graph.users[user_idx as usize];
+        self.authors.clear();
+        self.idxs.clear();
+
+        // tweets we can reach stay put until we unpin
+        let pinned = data.pin();
 
-        // seed heap
-        for follow in data.graph.user_follows(user) {
-            self.push_after(data.feeds[*follow as usize].fetch(), after);
+        // seed heap, skipping whole feeds the viewer has muted, blocked or can't see
+        let seed_span = trace::span("seed_heap");
+        let mut relations = data.relations.read();
+        let hidden = relations.for_viewer(viewer);
+        let mut visibility = data.visibility.read();
+        for follow in follows {
+            if hidden.is_some_and(|h| h.hides(follow)) || !visibility.can_see(viewer, follow) {
+                continue;
+            }
+            self.push_after(data.feeds[follow as usize].fetch(), after);
         }
+        drop(seed_span);
 
         // compose timeline
-        while let Some(NextLink { ts: _, tweet_idx }) = self.heap.pop() {
-            let chain = &data.tweets[tweet_idx as usize];
-            // tweets.push(Tweet::dummy(NonZeroU64::new(1).unwrap()));
-            self.tweets.push(chain.tweet.clone());
-            if self.tweets.len() >= max_len {
-                break;
+        let _merge_span = trace::span("merge");
+        while let Some(link) = self.heap.pop() {
+            let Some(chain) = pinned.follow(link) else {
+                // expired, and so is the rest of the chain
+                continue;
+            };
+            // deleted tweets still link to older ones
+            if let Some(tweet) = pinned.current_tweet(chain) {
+                // tweets.push(Tweet::dummy(NonZeroU64::new(1).unwrap()));
+                self.tweets.push(tweet);
+                if self.keep_authors {
+                    self.authors.push(chain.author);
+                }
+                if self.keep_idxs {
+                    self.idxs.push(link.tweet_idx);
+                }
+                if self.tweets.len() >= max_len {
+                    break;
+                }
             }
 
             self.push_after(chain.prev_tweet, after);
         }
 
-        Timeline {
-            tweets: &self.tweets[..],
+        self.timeline()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::GraphBuilder;
+    use crate::pool::{PoolConfig, SharedPool};
+    use crate::visibility::VisibilityChange;
+
+    fn timestamps(timeline: Timeline) -> Vec<u32> {
+        timeline.tweets.iter().map(|t| t.ts.get()).collect()
+    }
+
+    #[test]
+    fn deleted_tweets_are_skipped() {
+        let mut builder = GraphBuilder::new(3, true);
+        builder.add_edge(0, 1);
+        builder.add_edge(0, 2);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let idxs: Vec<TweetIdx> = (1..=6)
+            .map(|ts| {
+                data.add_tweet(Tweet::dummy(Timestamp::new(ts).unwrap()), 1 + ts % 2)
+                    .unwrap()
+            })
+            .collect();
+
+        let mut fetcher = TimelineFetcher::default();
+        assert_eq!(
+            timestamps(fetcher.for_user(&data, 0, 3, START_TIME)),
+            [6, 5, 4]
+        );
+
+        // a feed head and a tweet in the middle of a chain
+        assert!(data.delete_tweet(idxs[5]));
+        assert!(data.delete_tweet(idxs[2]));
+        assert!(!data.delete_tweet(idxs[2]));
+        assert!(!data.delete_tweet(100));
+        assert!(data.get_tweet(idxs[2]).is_none());
+        assert_eq!(
+            timestamps(fetcher.for_user(&data, 0, 3, START_TIME)),
+            [5, 4, 2]
+        );
+        assert_eq!(
+            timestamps(fetcher.for_user(&data, 0, 10, START_TIME)),
+            [5, 4, 2, 1]
+        );
+    }
+
+    #[test]
+    fn edits_keep_their_position() {
+        let mut builder = GraphBuilder::new(3, true);
+        builder.add_edge(0, 1);
+        builder.add_edge(0, 2);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        let first = data.add_tweet(Tweet::new(ts(1), "first"), 1).unwrap();
+        data.add_tweet(Tweet::new(ts(2), "second"), 2).unwrap();
+
+        assert!(data.edit_tweet(first, "first, edited", ts(3)));
+        assert!(data.edit_tweet(first, "first, edited again", ts(4)));
+        let mut fetcher = TimelineFetcher::default();
+        let texts: Vec<String> = fetcher
+            .for_user(&data, 0, 10, START_TIME)
+            .tweets
+            .iter()
+            .map(|t| format!("{} {}", t.ts, t.text()))
+            .collect();
+        assert_eq!(texts, ["2 second", "1 first, edited again"]);
+
+        let history: Vec<String> = data
+            .tweet_versions(first)
+            .iter()
+            .map(|t| format!("{} {}", t.ts, t.text()))
+            .collect();
+        assert_eq!(
+            history,
+            ["4 first, edited again", "3 first, edited", "1 first"]
+        );
+
+        assert!(data.delete_tweet(first));
+        assert!(!data.edit_tweet(first, "too late", ts(5)));
+        assert!(data.tweet_versions(first).is_empty());
+    }
+
+    #[test]
+    fn edits_never_tear() {
+        let mut builder = GraphBuilder::new(2, true);
+        builder.add_edge(0, 1);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let versions: Vec<String> = (0..8u8)
+            .map(|i| ((b'a' + i) as char).to_string().repeat(TWEET_BYTES))
+            .collect();
+        let idx = data
+            .add_tweet(Tweet::new(START_TIME, &versions[0]), 1)
+            .unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..2000 {
+                    data.edit_tweet(idx, &versions[i % versions.len()], START_TIME);
+                }
+            });
+            let mut fetcher = TimelineFetcher::default();
+            for _ in 0..2000 {
+                let timeline = fetcher.for_user(&data, 0, 1, START_TIME);
+                let text = timeline.tweets[0].text();
+                assert!(versions.iter().any(|v| *v == text));
+            }
+        });
+    }
+
+    #[test]
+    fn profile_pages() {
+        let baked = GraphBuilder::new(3, true).finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        let idxs: Vec<TweetIdx> = (1..=10)
+            .map(|t| data.add_tweet(Tweet::dummy(ts(t)), 1).unwrap())
+            .collect();
+        let other = data.add_tweet(Tweet::dummy(ts(11)), 2).unwrap();
+        assert!(data.delete_tweet(idxs[4]));
+
+        let mut fetcher = TimelineFetcher::default();
+        let mut page = |viewer, max_len, after, start| {
+            let page = fetcher.for_profile(&data, viewer, 1, max_len, ts(after), start);
+            assert_eq!(page.tweet_count, 9);
+            let tss: Vec<u32> = page.tweets.iter().map(|t| t.ts.get()).collect();
+            (tss, page.next)
+        };
+        let (tss, next) = page(0, 3, 1, ProfileStart::Newest);
+        assert_eq!(tss, [10, 9, 8]);
+        let (tss, next) = page(0, 3, 1, ProfileStart::Cursor(next.unwrap()));
+        // deleted tweets don't count towards the page
+        assert_eq!(tss, [7, 6, 4]);
+        let (tss, next) = page(0, 3, 2, ProfileStart::Cursor(next.unwrap()));
+        assert_eq!((tss, next), (vec![3, 2], None));
+        assert_eq!(page(0, 10, 1, ProfileStart::Before(ts(4))).0, [3, 2, 1]);
+
+        let foreign = NextLink {
+            ts: ts(11),
+            tweet_idx: other,
+        };
+        assert!(page(0, 10, 1, ProfileStart::Cursor(foreign)).0.is_empty());
+        data.relations.block(1, 0);
+        assert_eq!(page(2, 10, 1, ProfileStart::Newest).0.len(), 9);
+        // a hidden profile doesn't give away how much they tweet either
+        let hidden = fetcher.for_profile(&data, 0, 1, 10, ts(1), ProfileStart::Newest);
+        assert_eq!((hidden.tweets.len(), hidden.tweet_count), (0, 0));
+    }
+
+    #[test]
+    fn expired_tweets_end_chains() {
+        let mut builder = GraphBuilder::new(3, true);
+        builder.add_edge(0, 1);
+        builder.add_edge(0, 2);
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        let idxs: Vec<TweetIdx> = (1..=20)
+            .map(|t| {
+                data.add_tweet(Tweet::new(ts(t), "word"), 1 + t % 2)
+                    .unwrap()
+            })
+            .collect();
+        // edits go in order, up to the first one of a live tweet
+        for i in [2, 15, 4] {
+            assert!(data.edit_tweet(idxs[i], "word, edited", ts(21)));
         }
+
+        assert_eq!(data.expire_before(ts(11)), 10);
+        assert_eq!(data.edits.start(), 1);
+        assert_eq!(data.expire_before(ts(11)), 0);
+        let mut fetcher = TimelineFetcher::default();
+        let expected: Vec<u32> = (11..=20).rev().collect();
+        assert_eq!(
+            timestamps(fetcher.for_user(&data, 0, 100, START_TIME)),
+            expected
+        );
+        assert!(data.get_tweet(idxs[9]).is_none());
+        assert!(!data.delete_tweet(idxs[9]));
+        assert!(data.get_tweet(idxs[10]).is_some());
+        assert_eq!(data.tweet_counts[1].load(Ordering::SeqCst), 5);
+        let page = fetcher.for_profile(&data, 0, 1, 100, START_TIME, ProfileStart::Newest);
+        assert_eq!((page.tweets.len(), page.next), (5, None));
+
+        let mut searcher = crate::search::Searcher::default();
+        let query = crate::search::Query::parse("word");
+        let found = searcher.search(&data, None, &query, false, 100, None);
+        assert_eq!(found.idxs.len(), 10);
+
+        assert_eq!(data.expire_before(ts(17)), 6);
+        assert_eq!(data.edits.start(), 3);
+    }
+
+    #[test]
+    fn edits_fit_after_expiry() {
+        let mut builder = GraphBuilder::new(3, true);
+        builder.add_edge(0, 1);
+        let baked = builder.finish();
+        let mut data = Datastore::new(baked.graph()).unwrap();
+        let config = |limit| PoolConfig {
+            segment_size: 4096,
+            limit,
+            ..Default::default()
+        };
+        data.edits = SharedPool::with_config(config(4 * size_of::<TweetEdit>())).unwrap();
+        data.version_log = SharedPool::with_config(config(4 * size_of::<VersionUpdate>())).unwrap();
+        data.visibility.log =
+            SharedPool::with_config(config(4 * size_of::<VisibilityChange>())).unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        let idxs: Vec<TweetIdx> = (1..=8)
+            .map(|t| data.add_tweet(Tweet::new(ts(t), "word"), 1).unwrap())
+            .collect();
+        for (i, idx) in idxs[..4].iter().enumerate() {
+            assert!(data.edit_tweet(*idx, "edited", ts(10 + i as u32)));
+            assert!(data.set_protected(2, i % 2 == 0));
+        }
+        assert!(!data.edit_tweet(idxs[4], "edited", ts(20)));
+        assert!(!data.set_protected(2, true));
+
+        // the first expiry marks where the visibility log stood
+        assert_eq!(data.expire_before(ts(5)), 4);
+        assert_eq!((data.edits.start(), data.version_log.start()), (4, 4));
+        assert_eq!(data.visibility.log.start(), 0);
+        assert_eq!(data.expire_before(ts(9)), 4);
+        assert_eq!(data.visibility.log.start(), 4);
+        let idx = data.add_tweet(Tweet::new(ts(21), "word"), 1).unwrap();
+        assert!(data.edit_tweet(idx, "edited again", ts(22)));
+        assert!(data.set_protected(2, true));
+        assert_eq!(data.get_tweet(idx).unwrap().text(), "edited again");
+    }
+
+    #[test]
+    fn expiry_races_readers() {
+        let mut builder = GraphBuilder::new(4, true);
+        for f in 1..4 {
+            builder.add_edge(0, f);
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let n_tweets = 20_000;
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let mut fetcher = TimelineFetcher::default();
+                    while !done.load(Ordering::SeqCst) {
+                        let timeline = fetcher.for_user(&data, 0, 50, START_TIME);
+                        for pair in timeline.tweets.windows(2) {
+                            assert!(pair[0].ts > pair[1].ts);
+                        }
+                        // released memory would read back as zeroes
+                        for tweet in timeline.tweets {
+                            assert_eq!(tweet.text(), format!("tweet {}", tweet.ts));
+                        }
+                    }
+                });
+            }
+            for t in 1..=n_tweets {
+                let tweet = Tweet::new(Timestamp::new(t).unwrap(), &format!("tweet {t}"));
+                data.add_tweet(tweet, 1 + t % 3).unwrap();
+                if t % 1000 == 0 {
+                    data.expire_before(
+                        Timestamp::new(t.saturating_sub(2000)).unwrap_or(START_TIME),
+                    );
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        assert_eq!(data.tweets.start(), n_tweets as usize - 2001);
+    }
+
+    #[test]
+    fn readers_reach_oldest_while_expiring() {
+        let mut builder = GraphBuilder::new(4, true);
+        for f in 1..4 {
+            builder.add_edge(0, f);
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let n_tweets = 20_000;
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            for author in 1..4 {
+                let (data, done) = (&data, &done);
+                s.spawn(move || {
+                    let mut fetcher = TimelineFetcher::default();
+                    let mut searcher = crate::search::Searcher::default();
+                    let query = crate::search::Query::parse("tweet");
+                    while !done.load(Ordering::SeqCst) {
+                        // every walk runs off the end of what's live
+                        let timeline = fetcher.for_user(data, 0, usize::MAX, START_TIME);
+                        assert!(timeline.tweets.len() <= 400);
+                        let page = fetcher.for_profile(
+                            data,
+                            0,
+                            author,
+                            usize::MAX,
+                            START_TIME,
+                            ProfileStart::Newest,
+                        );
+                        for tweet in page.tweets {
+                            assert_eq!(tweet.text(), format!("tweet {}", tweet.ts));
+                        }
+                        searcher.search(data, None, &query, false, usize::MAX, None);
+                    }
+                });
+            }
+            for t in 1..=n_tweets {
+                let tweet = Tweet::new(Timestamp::new(t).unwrap(), &format!("tweet {t}"));
+                data.add_tweet(tweet, 1 + t % 3).unwrap();
+                if t % 100 == 0 {
+                    data.expire_before(Timestamp::new(t.saturating_sub(200)).unwrap_or(START_TIME));
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        assert_eq!(data.tweets.start(), n_tweets as usize - 201);
     }
 }
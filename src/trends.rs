@@ -0,0 +1,434 @@
+//! Trending hashtags.
+//!
+//! Time is cut into fixed buckets. Each bucket counts hashtags in a Count-Min
+//! Sketch, which never undercounts and has fixed size however many distinct tags
+//! show up, plus a Space-Saving summary that remembers which tags are heavy
+//! hitters, since a sketch can't be enumerated.
+//!
+//! Trending means fast relative to a tag's own baseline, so a tag that's always
+//! popular doesn't trend just for being popular: the last `window` buckets are
+//! compared against the average of the `baseline` buckets before them.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Mutex;
+
+use crate::data::Timestamp;
+
+/// Tags without the `#`, which must be followed by letters, digits or `_`
+fn raw_hashtags(text: &str) -> impl Iterator<Item = &str> {
+    text.split('#').skip(1).filter_map(|rest| {
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        (end > 0).then(|| &rest[..end])
+    })
+}
+
+/// Lowercased tags without the `#`, which must be followed by letters, digits or `_`
+pub fn hashtags(text: &str) -> impl Iterator<Item = String> + '_ {
+    raw_hashtags(text).map(str::to_lowercase)
+}
+
+/// FNV-1a, then double hashing for the sketch rows
+fn hash(tag: &str) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in tag.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    counts: Vec<u32>,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> Self {
+        Self {
+            width,
+            depth,
+            counts: vec![0; width * depth],
+        }
+    }
+
+    /// One counter per row of a tag with hash `h`, doesn't borrow `self` so
+    /// `add` can write to them
+    #[inline]
+    fn cells(width: usize, depth: usize, h: u64) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (h & 0xffff_ffff, (h >> 32) | 1);
+        (0..depth).map(move |row| row * width + (h1 + row as u64 * h2) as usize % width)
+    }
+
+    pub fn add(&mut self, tag: &str, n: u32) {
+        self.add_hashed(hash(tag), n);
+    }
+
+    /// `add` with the tag's `hash` computed up front, outside any lock
+    fn add_hashed(&mut self, h: u64, n: u32) {
+        for cell in Self::cells(self.width, self.depth, h) {
+            self.counts[cell] = self.counts[cell].saturating_add(n);
+        }
+    }
+
+    /// At least the true count, more only if colliding tags were added
+    pub fn estimate(&self, tag: &str) -> u32 {
+        Self::cells(self.width, self.depth, hash(tag))
+            .map(|c| self.counts[c])
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.fill(0);
+    }
+}
+
+/// Tracks the `capacity` most frequent items. An item with true count above
+/// total / capacity is guaranteed to be kept.
+pub struct SpaceSaving {
+    capacity: usize,
+    /// tag -> (count, max overcount)
+    counts: HashMap<String, (u32, u32)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn add(&mut self, tag: &str) {
+        if let Some((count, _)) = self.counts.get_mut(tag) {
+            *count += 1;
+            return;
+        }
+        if self.counts.len() < self.capacity {
+            self.counts.insert(tag.to_string(), (1, 0));
+            return;
+        }
+        // evict the smallest, the newcomer inherits its count as possible error
+        let (min_tag, &(min, _)) = self
+            .counts
+            .iter()
+            .min_by_key(|(_, (count, _))| *count)
+            .unwrap();
+        let min_tag = min_tag.clone();
+        self.counts.remove(&min_tag);
+        self.counts.insert(tag.to_string(), (min + 1, min));
+    }
+
+    /// (tag, count, max overcount), highest count first
+    pub fn top(&self) -> Vec<(&str, u32, u32)> {
+        let mut top: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(tag, (count, err))| (&tag[..], *count, *err))
+            .collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        top
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+    }
+}
+
+/// Everything but `baseline` and `min_count` must be nonzero
+pub struct TrendsConfig {
+    pub bucket_secs: u32,
+    /// Recent buckets whose counts are ranked
+    pub window: u32,
+    /// Buckets before the window averaged for the baseline
+    pub baseline: u32,
+    pub sketch_width: usize,
+    pub sketch_depth: usize,
+    pub heavy_hitters: usize,
+    /// Tags with fewer uses in the window don't trend however fast they grew
+    pub min_count: u32,
+}
+
+impl Default for TrendsConfig {
+    fn default() -> Self {
+        Self {
+            bucket_secs: 60,
+            window: 5,
+            baseline: 60,
+            sketch_width: 2048,
+            sketch_depth: 4,
+            heavy_hitters: 256,
+            min_count: 10,
+        }
+    }
+}
+
+struct Bucket {
+    /// ts / bucket_secs
+    idx: u32,
+    sketch: CountMinSketch,
+    top: SpaceSaving,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trend {
+    pub tag: String,
+    /// Uses in the window
+    pub count: u32,
+    /// Uses the baseline predicts for a window
+    pub expected: f32,
+    pub velocity: f32,
+}
+
+pub struct Trends {
+    config: TrendsConfig,
+    /// Oldest first, buckets nothing was tagged in are missing
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl Trends {
+    pub fn new(config: TrendsConfig) -> io::Result<Self> {
+        let c = &config;
+        if [c.bucket_secs, c.window].contains(&0)
+            || [c.sketch_width, c.sketch_depth, c.heavy_hitters].contains(&0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "trends config has a zero bucket, window, sketch or heavy hitter size",
+            ));
+        }
+        Ok(Self {
+            config,
+            buckets: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Counts the hashtags in a new tweet, each once however often it's
+    /// repeated. Late tweets count towards their own bucket, unless it's
+    /// older than the newest one's history and was dropped.
+    pub fn record(&self, ts: Timestamp, text: &str) {
+        if !text.contains('#') {
+            return;
+        }
+        // lowercased into one buffer and hashed before taking the lock
+        let mut lower = String::new();
+        let mut ends = vec![];
+        for tag in raw_hashtags(text) {
+            lower.extend(tag.chars().flat_map(char::to_lowercase));
+            ends.push(lower.len());
+        }
+        let mut tags: Vec<(u64, &str)> = ends
+            .iter()
+            .scan(0, |start, &end| {
+                let tag = &lower[*start..end];
+                *start = end;
+                Some((hash(tag), tag))
+            })
+            .collect();
+        if tags.is_empty() {
+            return;
+        }
+        tags.sort_unstable();
+        tags.dedup();
+
+        let idx = ts.get() / self.config.bucket_secs;
+        let history = self.config.window + self.config.baseline;
+        let mut buckets = self.buckets.lock().unwrap();
+        let newest = buckets.back().map_or(idx, |b| b.idx.max(idx));
+        if idx + history <= newest {
+            return;
+        }
+        let mut pos = buckets.partition_point(|b| b.idx < idx);
+        if buckets.get(pos).is_none_or(|b| b.idx != idx) {
+            // recycle the oldest bucket's allocations if it's expired
+            let bucket = match buckets.front() {
+                Some(front) if front.idx + history <= newest => {
+                    // older than `idx`, which isn't expired
+                    pos -= 1;
+                    let mut bucket = buckets.pop_front().unwrap();
+                    bucket.sketch.clear();
+                    bucket.top.clear();
+                    bucket.idx = idx;
+                    bucket
+                }
+                _ => Bucket {
+                    idx,
+                    sketch: CountMinSketch::new(self.config.sketch_width, self.config.sketch_depth),
+                    top: SpaceSaving::new(self.config.heavy_hitters),
+                },
+            };
+            buckets.insert(pos, bucket);
+            while buckets.front().is_some_and(|b| b.idx + history <= newest) {
+                buckets.pop_front();
+                pos -= 1;
+            }
+        }
+        let bucket = &mut buckets[pos];
+        for (h, tag) in tags {
+            bucket.sketch.add_hashed(h, 1);
+            bucket.top.add(tag);
+        }
+    }
+
+    /// Up to `k` tags growing fastest in the window ending at `now`
+    pub fn trending(&self, now: Timestamp, k: usize) -> Vec<Trend> {
+        let c = &self.config;
+        let current = now.get() / c.bucket_secs;
+        let window_start = current.saturating_sub(c.window - 1);
+        let baseline_start = window_start.saturating_sub(c.baseline);
+        let buckets = self.buckets.lock().unwrap();
+        let in_range =
+            |lo: u32, hi: u32| buckets.iter().filter(move |b| b.idx >= lo && b.idx <= hi);
+
+        let mut candidates: Vec<&str> = in_range(window_start, current)
+            .flat_map(|b| b.top.counts.keys().map(|t| &t[..]))
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let baseline_len = (window_start - baseline_start).max(1) as f32;
+        let mut trends: Vec<Trend> = candidates
+            .into_iter()
+            .filter_map(|tag| {
+                let count: u32 = in_range(window_start, current)
+                    .map(|b| b.sketch.estimate(tag))
+                    .sum();
+                if count < c.min_count {
+                    return None;
+                }
+                let before: u32 = match window_start {
+                    0 => 0,
+                    _ => in_range(baseline_start, window_start - 1)
+                        .map(|b| b.sketch.estimate(tag))
+                        .sum(),
+                };
+                let expected = before as f32 / baseline_len * c.window as f32;
+                Some(Trend {
+                    tag: tag.to_string(),
+                    count,
+                    expected,
+                    velocity: count as f32 / (expected + 1.),
+                })
+            })
+            .collect();
+        trends.sort_by(|a, b| {
+            b.velocity
+                .total_cmp(&a.velocity)
+                .then(b.count.cmp(&a.count))
+                .then(a.tag.cmp(&b.tag))
+        });
+        trends.truncate(k);
+        trends
+    }
+}
+
+impl Default for Trends {
+    fn default() -> Self {
+        Self::new(TrendsConfig::default()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extraction() {
+        let tags: Vec<String> = hashtags("Loving #Rust and #rust_lang! #42 # #über.").collect();
+        assert_eq!(tags, ["rust", "rust_lang", "42", "über"]);
+    }
+
+    #[test]
+    fn heavy_hitters() {
+        let mut sketch = CountMinSketch::new(64, 3);
+        let mut top = SpaceSaving::new(8);
+        for i in 0..300 {
+            let noise = format!("noise{i}");
+            sketch.add(&noise, 1);
+            top.add(&noise);
+            if i % 3 == 0 {
+                sketch.add("heavy", 1);
+                top.add("heavy");
+            }
+        }
+        assert!(sketch.estimate("heavy") >= 100);
+        assert!(sketch.estimate("noise7") >= 1);
+        assert_eq!(top.top()[0].0, "heavy");
+    }
+
+    #[test]
+    fn velocity() {
+        let trends = Trends::new(TrendsConfig {
+            bucket_secs: 10,
+            window: 2,
+            baseline: 4,
+            min_count: 3,
+            ..Default::default()
+        })
+        .unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        for bucket in 1..=6 {
+            for i in 0..5 {
+                trends.record(ts(bucket * 10 + i), "same old #steady");
+            }
+            if bucket == 5 {
+                for i in 0..8 {
+                    // counted once per tweet
+                    trends.record(ts(50 + i), "#spike #Spike #spike");
+                }
+            }
+        }
+        trends.record(ts(60), "#rare");
+
+        let top = trends.trending(ts(65), 10);
+        let tags: Vec<&str> = top.iter().map(|t| &t.tag[..]).collect();
+        assert_eq!(tags, ["spike", "steady"]);
+        assert_eq!((top[0].count, top[0].expected), (8, 0.));
+        assert_eq!((top[1].count, top[1].expected), (10, 10.));
+        // once the spike leaves the window it stops trending
+        for i in 0..5 {
+            trends.record(ts(80 + i), "#steady");
+        }
+        let top = trends.trending(ts(85), 10);
+        assert_eq!(top.len(), 1);
+        assert_eq!((top[0].count, top[0].expected), (5, 10.));
+    }
+
+    #[test]
+    fn late_tweets() {
+        let config = || TrendsConfig {
+            bucket_secs: 10,
+            window: 3,
+            baseline: 0,
+            min_count: 1,
+            ..Default::default()
+        };
+        assert!(Trends::new(TrendsConfig {
+            window: 0,
+            ..config()
+        })
+        .is_err());
+        assert!(Trends::new(TrendsConfig {
+            sketch_width: 0,
+            ..config()
+        })
+        .is_err());
+
+        let trends = Trends::new(config()).unwrap();
+        let ts = |t| Timestamp::new(t).unwrap();
+        trends.record(ts(10), "#late");
+        trends.record(ts(30), "#other");
+        // a retained bucket, then one nothing was tagged in yet
+        trends.record(ts(15), "#late");
+        trends.record(ts(25), "#late");
+        // the newest bucket's history starts at 10
+        trends.record(ts(5), "#late");
+        let top = trends.trending(ts(30), 10);
+        assert_eq!((&top[0].tag[..], top[0].count), ("late", 3));
+        assert_eq!(trends.buckets.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,346 @@
+//! Protected accounts, whose tweets only their approved followers can see.
+//!
+//! `User` is baked into `users.bin`, so the protected flag lives here as one
+//! bit per user instead. Like mutes and blocks it's checked once per feed
+//! while seeding the timeline merge, and only a protected author takes the lock.
+//!
+//! The follow graph itself is immutable, so approval is tracked separately:
+//! a viewer sees a protected author if they're the author or approved. Anyone
+//! following the author in the graph is approved unless the author removed
+//! them, which is checked against the viewer's follow list when it comes up
+//! rather than by finding every follower up front. Anyone else has to request
+//! to follow and wait for the author to accept.
+//!
+//! Changes that affect what viewers see are appended to `Visibility::log` so
+//! replicas can apply them in the same order.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use crate::data::{Graph, UserIdx};
+use crate::pool::SharedPool;
+use crate::shm::ReaderGate;
+
+#[derive(Default)]
+struct Followers {
+    /// Approved on request, sorted
+    approved: Vec<UserIdx>,
+    /// Graph followers whose approval was taken back, sorted
+    removed: Vec<UserIdx>,
+    /// sorted
+    pending: Vec<UserIdx>,
+}
+
+impl Followers {
+    fn approves(&self, graph: &Graph, viewer: UserIdx, author: UserIdx) -> bool {
+        if self.approved.binary_search(&viewer).is_ok() {
+            return true;
+        }
+        let follows = graph.user_follows(&graph.users[viewer as usize]);
+        follows.binary_search(&author).is_ok() && self.removed.binary_search(&viewer).is_err()
+    }
+}
+
+fn insert_sorted(v: &mut Vec<UserIdx>, x: UserIdx) {
+    if let Err(i) = v.binary_search(&x) {
+        v.insert(i, x);
+    }
+}
+
+fn remove_sorted(v: &mut Vec<UserIdx>, x: UserIdx) {
+    if let Ok(i) = v.binary_search(&x) {
+        v.remove(i);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VisibilityChange {
+    Protect(UserIdx),
+    Unprotect(UserIdx),
+    Approve { author: UserIdx, follower: UserIdx },
+    Remove { author: UserIdx, follower: UserIdx },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FollowStatus {
+    Approved,
+    Pending,
+}
+
+pub struct Visibility<'g> {
+    graph: Graph<'g>,
+    protected: Vec<AtomicU64>,
+    followers: RwLock<HashMap<UserIdx, Followers>>,
+    /// Appended under the `followers` lock, so in the order changes applied.
+    /// `Datastore::expire_before` truncates it.
+    pub log: SharedPool<VisibilityChange>,
+    /// Protecting fails while shared readers are attached, see `crate::shm`
+    gate: Option<Arc<ReaderGate>>,
+}
+
+/// Read access for one fetch, see `Visibility::read`
+pub struct VisibilityView<'a, 'g> {
+    visibility: &'a Visibility<'g>,
+    followers: Option<RwLockReadGuard<'a, HashMap<UserIdx, Followers>>>,
+}
+
+impl VisibilityView<'_, '_> {
+    #[inline]
+    pub fn can_see(&mut self, viewer: UserIdx, author: UserIdx) -> bool {
+        if viewer == author || !self.visibility.is_protected(author) {
+            return true;
+        }
+        let visibility = self.visibility;
+        self.followers
+            .get_or_insert_with(|| visibility.followers.read().unwrap())
+            .get(&author)
+            .is_some_and(|f| f.approves(&visibility.graph, viewer, author))
+    }
+}
+
+impl<'g> Visibility<'g> {
+    pub fn new(graph: Graph<'g>) -> std::io::Result<Self> {
+        Ok(Self {
+            graph,
+            protected: (0..graph.users.len().div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            followers: RwLock::new(HashMap::new()),
+            log: SharedPool::new()?,
+            gate: None,
+        })
+    }
+
+    pub fn gated(self, gate: Arc<ReaderGate>) -> Self {
+        Self {
+            gate: Some(gate),
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn is_protected(&self, user: UserIdx) -> bool {
+        let word = self.protected[user as usize / 64].load(Ordering::SeqCst);
+        word & (1 << (user % 64)) != 0
+    }
+
+    /// Only takes the lock if an author passed to `can_see` is protected
+    pub fn read(&self) -> VisibilityView<'_, 'g> {
+        VisibilityView {
+            visibility: self,
+            followers: None,
+        }
+    }
+
+    pub fn can_see(&self, viewer: UserIdx, author: UserIdx) -> bool {
+        self.read().can_see(viewer, author)
+    }
+
+    /// Everyone following `author` in the graph stays approved. Returns false
+    /// if `author` was already protected, which keeps their approvals, the
+    /// change log is full or it's gated and readers are attached.
+    pub fn protect(&self, author: UserIdx) -> bool {
+        self.apply(VisibilityChange::Protect(author))
+    }
+
+    /// Makes `author` public again, dropping approvals and pending requests.
+    /// Returns false if they weren't protected or the change log is full.
+    pub fn unprotect(&self, author: UserIdx) -> bool {
+        self.apply(VisibilityChange::Unprotect(author))
+    }
+
+    /// Applies and logs a change, the way a replica replays the leader's log.
+    /// Returns false if it changed nothing or the log is full.
+    pub fn apply(&self, change: VisibilityChange) -> bool {
+        let mut all = self.followers.write().unwrap();
+        let author = match change {
+            VisibilityChange::Protect(author) | VisibilityChange::Unprotect(author) => author,
+            VisibilityChange::Approve { author, .. } | VisibilityChange::Remove { author, .. } => {
+                author
+            }
+        };
+        let followers = all.get(&author);
+        let changes = match change {
+            VisibilityChange::Protect(_) => followers.is_none(),
+            VisibilityChange::Unprotect(_) => followers.is_some(),
+            VisibilityChange::Approve { follower, .. } => {
+                followers.is_some_and(|f| !f.approves(&self.graph, follower, author))
+            }
+            VisibilityChange::Remove { follower, .. } => {
+                followers.is_some_and(|f| f.approves(&self.graph, follower, author))
+            }
+        };
+        if !changes {
+            return false;
+        }
+        let restricts = matches!(change, VisibilityChange::Protect(_));
+        if restricts && self.gate.as_ref().is_some_and(|g| !g.restrict()) {
+            return false;
+        }
+        if self.log.push(change).is_err() {
+            return false;
+        }
+        let bit = 1 << (author % 64);
+        let word = &self.protected[author as usize / 64];
+        match change {
+            VisibilityChange::Protect(_) => {
+                all.insert(author, Followers::default());
+                word.fetch_or(bit, Ordering::SeqCst);
+            }
+            VisibilityChange::Unprotect(_) => {
+                word.fetch_and(!bit, Ordering::SeqCst);
+                all.remove(&author);
+            }
+            VisibilityChange::Approve { follower, .. } => {
+                let followers = all.get_mut(&author).unwrap();
+                insert_sorted(&mut followers.approved, follower);
+                remove_sorted(&mut followers.removed, follower);
+                remove_sorted(&mut followers.pending, follower);
+            }
+            VisibilityChange::Remove { follower, .. } => {
+                let followers = all.get_mut(&author).unwrap();
+                remove_sorted(&mut followers.approved, follower);
+                insert_sorted(&mut followers.removed, follower);
+            }
+        }
+        true
+    }
+
+    /// Following a public account needs no approval
+    pub fn request_follow(&self, requester: UserIdx, author: UserIdx) -> FollowStatus {
+        if !self.is_protected(author) || requester == author {
+            return FollowStatus::Approved;
+        }
+        let mut all = self.followers.write().unwrap();
+        let Some(followers) = all.get_mut(&author) else {
+            // unprotected while we waited for the lock
+            return FollowStatus::Approved;
+        };
+        if followers.approves(&self.graph, requester, author) {
+            return FollowStatus::Approved;
+        }
+        insert_sorted(&mut followers.pending, requester);
+        FollowStatus::Pending
+    }
+
+    /// Requests waiting for `author` to answer, sorted by user
+    pub fn pending_requests(&self, author: UserIdx) -> Vec<UserIdx> {
+        let all = self.followers.read().unwrap();
+        all.get(&author)
+            .map_or_else(Vec::new, |f| f.pending.clone())
+    }
+
+    /// Answers a pending request. Returns false if there wasn't one, or the
+    /// change log is full.
+    pub fn answer_request(&self, author: UserIdx, requester: UserIdx, approve: bool) -> bool {
+        if approve {
+            let pending = self
+                .pending_requests(author)
+                .binary_search(&requester)
+                .is_ok();
+            return pending
+                && self.apply(VisibilityChange::Approve {
+                    author,
+                    follower: requester,
+                });
+        }
+        let mut all = self.followers.write().unwrap();
+        let Some(followers) = all.get_mut(&author) else {
+            return false;
+        };
+        let Ok(i) = followers.pending.binary_search(&requester) else {
+            return false;
+        };
+        followers.pending.remove(i);
+        true
+    }
+
+    /// Takes back an approval, the follower has to request again. Returns
+    /// false if they weren't approved.
+    pub fn remove_follower(&self, author: UserIdx, follower: UserIdx) -> bool {
+        self.apply(VisibilityChange::Remove { author, follower })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::*;
+    use crate::import::GraphBuilder;
+    use crate::timeline::TimelineFetcher;
+
+    #[test]
+    fn protected_accounts() {
+        // 0 and 3 follow 1 and 2
+        let mut builder = GraphBuilder::new(4, true);
+        for (follower, followee) in [(0, 1), (0, 2), (3, 1), (3, 2)] {
+            builder.add_edge(follower, followee);
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        let mut idxs = vec![];
+        for ts in 1..=6 {
+            idxs.push(
+                data.add_tweet(Tweet::dummy(Timestamp::new(ts).unwrap()), 1 + ts % 2)
+                    .unwrap(),
+            );
+        }
+        let authors = |fetcher: &mut TimelineFetcher, viewer| -> Vec<u32> {
+            let timeline = fetcher.for_user(&data, viewer, 3, START_TIME);
+            timeline.tweets.iter().map(|t| 1 + t.ts.get() % 2).collect()
+        };
+        let mut fetcher = TimelineFetcher::default();
+
+        assert!(data.set_protected(1, true));
+        assert!(data.visibility.can_see(0, 1));
+        assert!(data.visibility.can_see(1, 1));
+        assert!(!data.visibility.can_see(2, 1));
+        assert!(data.visibility.remove_follower(1, 3));
+        assert!(!data.visibility.remove_follower(1, 3));
+        // protecting again keeps 3 out
+        assert!(!data.set_protected(1, true));
+        assert!(!data.visibility.can_see(3, 1));
+        assert_eq!(authors(&mut fetcher, 0), [1, 2, 1]);
+        // still fills max_len from the public feed
+        assert_eq!(authors(&mut fetcher, 3), [2, 2, 2]);
+        assert!(data.get_tweet_as(Some(3), idxs[1]).is_none());
+        assert!(data.get_tweet_as(None, idxs[1]).is_none());
+        assert!(data.get_tweet_as(None, idxs[0]).is_some());
+
+        assert_eq!(data.visibility.request_follow(3, 1), FollowStatus::Pending);
+        assert_eq!(data.visibility.request_follow(3, 2), FollowStatus::Approved);
+        assert_eq!(data.visibility.pending_requests(1), [3]);
+        assert_eq!(authors(&mut fetcher, 3), [2, 2, 2]);
+        assert!(data.visibility.answer_request(1, 3, true));
+        assert!(!data.visibility.answer_request(1, 3, true));
+        assert!(data.visibility.pending_requests(1).is_empty());
+        assert_eq!(authors(&mut fetcher, 3), [1, 2, 1]);
+        assert!(data.get_tweet_as(Some(3), idxs[1]).is_some());
+
+        assert!(data.set_protected(1, false));
+        assert!(data.visibility.can_see(2, 1));
+        assert_eq!(data.visibility.log.len(), 4);
+    }
+
+    #[test]
+    fn viewer_following_many() {
+        // 0 follows everyone, added out of order
+        let n = 1000;
+        let mut builder = GraphBuilder::new(n, true);
+        for i in 0..n as UserIdx - 1 {
+            builder.add_edge(0, 1 + (i * 379) % (n as UserIdx - 1));
+        }
+        let baked = builder.finish();
+        let data = Datastore::new(baked.graph()).unwrap();
+        for author in [1, 378, 500, 999] {
+            assert!(data.set_protected(author, true));
+            assert!(data.visibility.can_see(0, author));
+            assert!(!data.visibility.can_see(author % 7 + 2, author));
+        }
+        assert!(data.visibility.remove_follower(500, 0));
+        assert!(!data.visibility.can_see(0, 500));
+        assert!(data.visibility.can_see(0, 999));
+        assert!(!data.visibility.remove_follower(999, 2));
+    }
+}